@@ -1,63 +1,22 @@
 use std::env;
 use std::fs::File;
-use std::collections::BTreeMap;
-use std::io::{BufReader, BufRead, Write};
-use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::Write;
+use gsp_parse::*;
 
 const SPECIAL_TYPES:  [&str;8] = ["NvU32", "NvU64", "NvU16", "NvU8", "NvBool", "char", "NvHandle", "int"];
 
-#[derive(Serialize, Deserialize, Clone)]
-enum FieldType {
-    Member,
-    UnionStart,
-    UnionEnd,
-    StructStart,
-    StructEnd,
-}
-
-#[derive(Serialize, Deserialize, Clone)]
-struct CStructField {
-    fldtype: FieldType,
-    ftype: String,
-    name: String,
-    is_array: bool,
-    size: u32,
-    is_aligned: bool,
-    alignment: u32,
-}
-
-#[derive(Serialize, Deserialize, Default, Clone)]
-enum CType {
-    #[default]
-    Unknown,
-    Value,
-    Struct,
-    Typedef,
-}
-
-#[derive(Serialize, Deserialize, Default, Clone)]
-struct CTypes {
-    ctype: CType,
-    vals: Vec<String>,
-    is_anon_struct: bool,
-    // for structs
-    fields: Vec<CStructField>,
-}
-
-#[derive(Serialize, Deserialize, Default)]
-struct CJson {
-    version: String,
-    types: BTreeMap<String, CTypes>,
-}
-
-#[derive(Serialize, Deserialize, Default)]
-struct WantedJson {
-    structs: Vec<String>,
-    cmds: BTreeMap<String, Vec<String>>,
-    defines: Vec<String>,
-}
-
 fn generate_define(out_writer: &mut File, verstr: &str, defname: &String, define: &CTypes) -> std::io::Result<()> {
+    // macros that aren't simple values or constant expressions
+    if define.vals.len() == 0 {
+	return Ok(());
+    }
+    // enum constants are defined by their enum, which the closure
+    // emits along with them
+    if define.enum_name != "" {
+	return Ok(());
+    }
+    write_source_comment(out_writer, &define.source)?;
     if define.vals.len() == 2 {
 	writeln!(out_writer, "#define {} {}:{}", defname, define.vals[0], define.vals[1])?;
     } else {
@@ -66,9 +25,57 @@ fn generate_define(out_writer: &mut File, verstr: &str, defname: &String, define
     Ok(())
 }
 
+// members of anonymous structs and unions share the namespace of the
+// record they are in, so they can clash with members of the parent.
+fn report_name_collisions(strname: &String, fields: &Vec<CStructField>) {
+    let mut scopes: Vec<HashSet<&str>> = vec![Default::default()];
+    // whether each open record has a namespace of its own
+    let mut named: Vec<bool> = Default::default();
+    for field in fields {
+	let is_named = field.name != "" && field.anon_name == "";
+	match field.fldtype {
+	    FieldType::StructStart | FieldType::UnionStart => {
+		if is_named && !scopes.last_mut().unwrap().insert(&field.name) {
+		    eprintln!("{}: member {} is declared more than once", strname, field.name);
+		}
+		if is_named {
+		    scopes.push(Default::default());
+		}
+		named.push(is_named);
+	    }
+	    FieldType::StructEnd | FieldType::UnionEnd => {
+		if named.pop() == Some(true) {
+		    scopes.pop();
+		}
+	    }
+	    FieldType::Member => {
+		if field.name != "" && !scopes.last_mut().unwrap().insert(&field.name) {
+		    eprintln!("{}: member {} is declared more than once", strname, field.name);
+		}
+	    }
+	}
+    }
+}
+
 fn generate_struct(out_writer: &mut File, verstr: &str, strname: &String, cstruct: &CTypes) -> std::io::Result<()> {
+    report_name_collisions(strname, &cstruct.fields);
+    let keyword = if cstruct.is_union { "union" } else { "struct" };
+    if cstruct.opaque {
+	write_source_comment(out_writer, &cstruct.source)?;
+	writeln!(out_writer, "typedef {} {} {};", keyword, strname, strname)?;
+	writeln!(out_writer, "")?;
+	return Ok(());
+    }
+    // pack(alignment) gives the same layout for both packed attributes
+    // and the original #pragma pack regions
+    if cstruct.fields.len() > 0 {
+	write_source_comment(out_writer, &cstruct.source)?;
+    }
+    if cstruct.fields.len() > 0 && cstruct.packed {
+	writeln!(out_writer, "#pragma pack(push, {})", cstruct.alignment)?;
+    }
     if cstruct.fields.len() > 0 {
-	writeln!(out_writer, "typedef struct {} {{", strname)?;
+	writeln!(out_writer, "typedef {} {} {{", keyword, strname)?;
     }
     let mut cur_level = 1;
     for field in &cstruct.fields {
@@ -89,6 +96,8 @@ fn generate_struct(out_writer: &mut File, verstr: &str, strname: &String, cstruc
 			fld_size_str = format!("{}", field.size);
 		    }
 		    writeln!(out_writer, "{}{} {}[{}]{};", indent, fname, field.name, fld_size_str, alignedstr)?;
+		} else if field.bit_width > 0 {
+		    writeln!(out_writer, "{}{} {} : {}{};", indent, field.ftype, field.name, field.bit_width, alignedstr)?;
 		} else {
 		    writeln!(out_writer, "{}{} {}{};", indent, field.ftype, field.name, alignedstr)?;
 		}
@@ -142,25 +151,70 @@ fn generate_struct(out_writer: &mut File, verstr: &str, strname: &String, cstruc
     }
     if cstruct.fields.len() > 0 {
 	writeln!(out_writer, "}} {};", strname)?;
+	if cstruct.packed {
+	    writeln!(out_writer, "#pragma pack(pop)")?;
+	}
 	writeln!(out_writer, "");
     }
     Ok(())
 }
 
+fn generate_enum(out_writer: &mut File, _verstr: &str, enumname: &String, cenum: &CTypes) -> std::io::Result<()> {
+    write_source_comment(out_writer, &cenum.source)?;
+    if cenum.alias_of != "" {
+	writeln!(out_writer, "typedef enum {} {};", cenum.alias_of, enumname)?;
+	writeln!(out_writer)?;
+	return Ok(());
+    }
+    writeln!(out_writer, "typedef enum {} {{", enumname)?;
+    for member in &cenum.members {
+	writeln!(out_writer, "    {} = {},", member.name, member.value)?;
+    }
+    if cenum.packed {
+	writeln!(out_writer, "}} __attribute__ ((packed)) {};", enumname)?;
+    } else {
+	writeln!(out_writer, "}} {};", enumname)?;
+    }
+    writeln!(out_writer)?;
+    Ok(())
+}
+
 fn generate_typedef(out_writer: &mut File, verstr: &str, tdname: &String, ctypedef: &CTypes) -> std::io::Result<()> {
-    writeln!(out_writer, "typedef struct {}_{} {}", tdname, verstr, ctypedef.vals[0])?;
+    write_source_comment(out_writer, &ctypedef.source)?;
+    writeln!(out_writer, "typedef {} {};", ctypedef.vals[0], tdname)?;
+    writeln!(out_writer)?;
+    Ok(())
+}
+
+// a wanted symbol and whatever it needs that isn't in the output yet
+fn generate_with_deps(out_writer: &mut File, verstr: &str, json_input: &CJson, closure: &mut Closure, symbol: Symbol) -> std::io::Result<()> {
+    for symbol in closure.add(json_input, symbol) {
+	let ctype = match json_input.get_type(&symbol.name) {
+	    Some(x) => { x }
+	    None => { continue }
+	};
+	match symbol.kind {
+	    SymbolKind::Define => { generate_define(out_writer, verstr, &symbol.name, ctype)?; }
+	    SymbolKind::Enum => { generate_enum(out_writer, verstr, &symbol.name, ctype)?; }
+	    SymbolKind::Struct => { generate_struct(out_writer, verstr, &symbol.name, ctype)?; }
+	    SymbolKind::Typedef => { generate_typedef(out_writer, verstr, &symbol.name, ctype)?; }
+	}
+    }
     Ok(())
 }
 
 fn main() -> std::io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let file = File::open(args[1].clone())?;
-    let reader = BufReader::new(file);
-    let json_input: CJson = serde_json::from_reader(reader)?;
+    // --sources adds a comment with the defining header to each symbol
+    let emit_sources = env::args().any(|a| a == "--sources");
+    let args: Vec<String> = env::args().filter(|a| a != "--sources").collect();
+    let mut json_input = CJson::load(&args[1])?;
+    let sym_json = WantedJson::load(&args[2])?;
 
-    let sym_list = File::open(args[2].clone())?;
-    let sym_reader = BufReader::new(sym_list);
-    let sym_json: WantedJson = serde_json::from_reader(sym_reader)?;    
+    // header: selectors need the sources, so select before stripping them
+    let selection = json_input.select(&sym_json.selectors()?);
+    if !emit_sources {
+	json_input.strip_sources();
+    }
 
     let mut out_file = File::create(args[3].clone())?;
 
@@ -180,76 +234,83 @@ fn main() -> std::io::Result<()> {
 
     writeln!(out_file)?;
 
-    for sym_define in sym_json.defines {
-	for (defname, define) in &json_input.types {
-	    if sym_define.chars().last().unwrap() == '*' {
-		if defname.starts_with(&sym_define.strip_suffix("*").unwrap()) {
-		    generate_define(&mut out_file, &ver_str.as_str(), &defname, &define);
-		}
-	    } else if *defname == sym_define {
-		generate_define(&mut out_file, &ver_str.as_str(), &defname, &define);
-	    }
+    // everything emitted goes through the closure, so the types wanted
+    // symbols use are defined before them and nothing is emitted twice
+    let mut closure = Closure::new();
+    for defname in &selection.defines {
+	if json_input.get_type(defname).is_some() {
+	    generate_with_deps(&mut out_file, &ver_str, &json_input, &mut closure, Symbol::new(SymbolKind::Define, defname))?;
 	}
     }
 
     writeln!(out_file)?;
-    for sym_struct in sym_json.structs {
-	for (name, ctype) in &json_input.types {
-	    if *name == sym_struct {
-		generate_struct(&mut out_file,
-				&ver_str.as_str(),
-				&name,
-				&ctype)?;
-	    }
+    for sym_enum in &selection.enums {
+	if json_input.get_type(sym_enum).is_some() {
+	    generate_with_deps(&mut out_file, &ver_str, &json_input, &mut closure, Symbol::new(SymbolKind::Enum, sym_enum))?;
 	}
     }
 
     writeln!(out_file)?;
-    for cmdgroup in sym_json.cmds {
-	let basename : String = "NV".to_owned() + &cmdgroup.0;
-	for cmd in cmdgroup.1 {
-	    let cmdname = basename.clone() + "_CTRL_CMD_" + &cmd;
-	    let ctrlname = basename.clone() + "_CTRL_" + &cmd;
-
-	    for (defname, define) in &json_input.types {
-		if defname.starts_with(&cmdname) || defname.starts_with(&ctrlname) {
-		    match define.ctype {
-			CType::Value => { generate_define(&mut out_file,
-							  &ver_str.as_str(),
-							  &defname,
-							  &define)?;
-			}
-			_ => {}
-		    }
-		}
-	    }
-	    let stname = ctrlname.clone() + "_PARAMS";
-	    for (name, ctype) in &json_input.types {
-		if name == &stname {
-		    writeln!(out_file)?;
-		    generate_struct(&mut out_file,
-				    &ver_str.as_str(),
-				    &name,
-				    &ctype);
+    for sym_struct in &selection.structs {
+	if json_input.get_type(sym_struct).is_some() {
+	    generate_with_deps(&mut out_file, &ver_str, &json_input, &mut closure, Symbol::new(SymbolKind::Struct, sym_struct))?;
+	}
+    }
 
-		}
+    writeln!(out_file)?;
+    for (cmdname, ctrlname) in selection.cmd_prefixes() {
+	for (defname, define) in &json_input.types {
+	    if (defname.starts_with(&cmdname) || defname.starts_with(&ctrlname)) && define.ctype == CType::Value {
+		generate_with_deps(&mut out_file, &ver_str, &json_input, &mut closure, Symbol::new(SymbolKind::Define, defname))?;
+	    }
+	}
+	for stname in [ctrlname.clone() + "_PARAMS", cmdname.clone() + "_PARAMS"] {
+	    if json_input.get_type(&stname).is_some() {
+		writeln!(out_file)?;
+		generate_with_deps(&mut out_file, &ver_str, &json_input, &mut closure, Symbol::new(SymbolKind::Struct, &stname))?;
 	    }
-	    let stname = cmdname.clone() + "_PARAMS";
-	    for (name, ctype) in &json_input.types {
-		if name == &stname {
-		    writeln!(out_file)?;
-		    generate_struct(&mut out_file,
-				    &ver_str.as_str(),
-				    &name,
-				    &ctype);
-
-		}
-	    }	    
 	}
     }
 
-    
-
     writeln!(out_file, "#endif")?;
+    closure.report(&mut std::io::stderr())?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // typedef enum tag { A = 1 } NAME; with A and NAME wanted
+    #[test]
+    fn enum_constants_are_not_defined_twice() {
+	let mut json_input: CJson = Default::default();
+	json_input.types.insert("tag".to_string(), CTypes {
+	    ctype: CType::Enum,
+	    members: vec![CEnumMember { name: "A".to_string(), value: 1 }],
+	    ..Default::default()
+	});
+	json_input.types.insert("NAME".to_string(), CTypes { ctype: CType::Enum, alias_of: "tag".to_string(), ..Default::default() });
+	json_input.types.insert("A".to_string(), CTypes {
+	    ctype: CType::Value,
+	    vals: vec!["1".to_string()],
+	    value: Some(1),
+	    enum_name: "tag".to_string(),
+	    ..Default::default()
+	});
+
+	let path = std::env::temp_dir().join(format!("generate-test-{}.h", std::process::id()));
+	let mut out_file = File::create(&path).unwrap();
+	let mut closure = Closure::new();
+	generate_with_deps(&mut out_file, "", &json_input, &mut closure, Symbol::new(SymbolKind::Define, "A")).unwrap();
+	generate_with_deps(&mut out_file, "", &json_input, &mut closure, Symbol::new(SymbolKind::Enum, "NAME")).unwrap();
+	drop(out_file);
+	let output = std::fs::read_to_string(&path).unwrap();
+	std::fs::remove_file(&path).unwrap();
+
+	assert!(!output.contains("#define A"));
+	assert_eq!(output.matches("A = 1,").count(), 1);
+	assert!(output.contains("typedef enum tag {"));
+	assert!(output.contains("typedef enum tag NAME;"));
+    }
+}
@@ -2,57 +2,17 @@ use std::env;
 use std::fs::File;
 use std::collections::BTreeMap;
 use std::io::{BufReader, BufRead, Write};
-use serde::{Deserialize, Serialize};
+use gsp_parse::*;
 
 const SPECIAL_TYPES:  [&str;8] = ["NvU32", "NvU64", "NvU16", "NvU8", "NvBool", "char", "NvHandle", "int"];
 
 
-// start/end are in bits
-#[derive(Serialize, Deserialize)]
-struct HWStructField {
-    name: String,
-    start: u32,
-    size: u32,
-    group_len: u32,
-    isint: u32,    
-    val_type: String,
-}
-
-#[derive(Serialize, Deserialize)]
-struct HWStruct {
-    total_size: u32,    
-    fields: Vec<HWStructField>,
-}
-
-#[derive(Serialize, Deserialize, Default)]
-enum HWDefineType {
-    #[default]
-    Unknown,
-    Value,
-}
-
-#[derive(Serialize, Deserialize, Default)]
-struct HWDefine {
-    hwtype: HWDefineType,
-    vals: Vec<String>,
-}
-
-#[derive(Serialize, Deserialize, Default)]
-struct HWJson {
-    version: String,
-    defines: BTreeMap<String, HWDefine>,
-    structs: BTreeMap<String, HWStruct>,
-}
-
-
-#[derive(Serialize, Deserialize, Default)]
-struct WantedJson {
-    structs: Vec<String>,
-    cmds: BTreeMap<String, Vec<String>>,
-    defines: Vec<String>,
-}
-
 fn generate_define(out_writer: &mut File, verstr: &str, defname: &String, define: &HWDefine) -> std::io::Result<()> {
+    // enum constants are defined by their enum
+    if define.enum_name != "" {
+	return Ok(());
+    }
+    write_source_comment(out_writer, &define.source)?;
     if define.vals.len() == 2 {
 	writeln!(out_writer, "#define {} {}:{}", defname, define.vals[0], define.vals[1])?;
     } else {
@@ -61,19 +21,48 @@ fn generate_define(out_writer: &mut File, verstr: &str, defname: &String, define
     Ok(())
 }
 
-fn generate_hw_struct(out_writer: &mut File, verstr: &str, strname: &String, hwstruct: &HWStruct) -> std::io::Result<()> {
-    writeln!(out_writer, "struct {} {{", strname)?;
+// signed and bool fields keep their kind, bitfields and anything not
+// in the scalar registry are unsigned. There are no float types in the
+// kernel so floats are kept as unsigned storage of the same size.
+fn field_type(scalars: &BTreeMap<String, HWScalar>, field: &HWStructField) -> String {
+    let utype = match field.size {
+	64 => "u64",
+	32 => "u32",
+	16 => "u16",
+	8 => "u8",
+	_ => "u32",
+    };
+    let scalar = match scalars.get(&field.scalar) {
+	Some(x) if field.bit_width == 0 => { x }
+	_ => { return utype.to_string() }
+    };
+    if scalar.boolean {
+	"bool".to_string()
+    } else if scalar.signed && !scalar.float {
+	utype.replace('u', "s")
+    } else {
+	utype.to_string()
+    }
+}
+
+fn generate_hw_struct(out_writer: &mut File, verstr: &str, strname: &String, hwstruct: &HWStruct,
+		      scalars: &BTreeMap<String, HWScalar>) -> std::io::Result<()> {
+    for name in hwstruct.name_collisions() {
+	eprintln!("{}: field {} appears more than once after flattening", strname, name);
+    }
+    write_source_comment(out_writer, &hwstruct.source)?;
+    let keyword = if hwstruct.is_union { "union" } else { "struct" };
+    if hwstruct.opaque {
+	return writeln!(out_writer, "{} {};", keyword, strname);
+    }
+    writeln!(out_writer, "{} {} {{", keyword, strname)?;
 
     for field in &hwstruct.fields {
-	let typestr = match field.size {
-	    64 => "u64",
-	    32 => "u32",
-	    16 => "u16",
-	    8 => "u8",
-	    _ => "u32",
-	};
+	let typestr = field_type(scalars, field);
 	if field.group_len != 0xffffffff && field.group_len > 0 {
 	    writeln!(out_writer, "    {} {}[{}];", typestr, field.name, field.group_len)?;
+	} else if field.bit_width > 0 {
+	    writeln!(out_writer, "    {} {} : {};", typestr, field.name, field.bit_width)?;
 	} else {
 	    writeln!(out_writer, "    {} {};", typestr, field.name)?;
 	}
@@ -81,14 +70,80 @@ fn generate_hw_struct(out_writer: &mut File, verstr: &str, strname: &String, hws
     writeln!(out_writer, "}};")
 }
 
+// gnu11 has no fixed underlying enum type, the fields using the enum
+// are emitted with the sized integer type instead.
+fn generate_enum(out_writer: &mut File, enumname: &String, hwenum: &HWEnum) -> std::io::Result<()> {
+    write_source_comment(out_writer, &hwenum.source)?;
+    if hwenum.alias_of != "" {
+	return writeln!(out_writer, "typedef enum {} {};", hwenum.alias_of, enumname);
+    }
+    writeln!(out_writer, "enum {} {{", enumname)?;
+    for member in &hwenum.members {
+	writeln!(out_writer, "    {} = {},", member.name, member.value)?;
+    }
+    writeln!(out_writer, "}};")
+}
+
+// a wanted symbol and whatever it needs that isn't in the output yet
+fn generate_with_deps(out_writer: &mut File, verstr: &str, json_input: &HWJson, closure: &mut Closure, symbol: Symbol) -> std::io::Result<()> {
+    for symbol in closure.add(json_input, symbol) {
+	match symbol.kind {
+	    SymbolKind::Define => {
+		match json_input.get_define(&symbol.name) {
+		    Some(x) if x.hwtype == HWDefineType::Value => { generate_define(out_writer, verstr, &symbol.name, x)?; }
+		    Some(_) => { eprintln!("{} has no value", symbol.name); }
+		    None => {}
+		}
+	    }
+	    SymbolKind::Enum => {
+		if let Some(x) = json_input.get_enum(&symbol.name) {
+		    generate_enum(out_writer, &symbol.name, x)?;
+		}
+	    }
+	    SymbolKind::Struct => {
+		if let Some(x) = json_input.get_struct(&symbol.name) {
+		    generate_hw_struct(out_writer, verstr, &symbol.name, x, &json_input.scalars)?;
+		}
+	    }
+	    SymbolKind::Typedef => {}
+	}
+	writeln!(out_writer)?;
+    }
+    Ok(())
+}
+
 fn main() -> std::io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let file = File::open(args[1].clone())?;
-    let reader = BufReader::new(file);
-    let json_input: HWJson = serde_json::from_reader(reader)?;
+    // --sources adds a comment with the defining header to each symbol
+    let emit_sources = env::args().any(|a| a == "--sources");
+    let args: Vec<String> = env::args().filter(|a| a != "--sources").collect();
+    let mut json_input = HWJson::load(&args[1])?;
 
     let sym_list = File::open(args[2].clone())?;
     let sym_reader = BufReader::new(sym_list);
+    let lines: Vec<String> = sym_reader.lines().collect::<std::io::Result<_>>()?;
+
+    // each line is a selector, the symbols it picks are emitted in the
+    // order of the lines, defines before structs. The !exclusions apply
+    // to every line.
+    let excludes: Vec<String> = lines.iter().filter(|x| x.starts_with('!')).cloned().collect();
+    let mut wanted: Vec<Symbol> = Default::default();
+    for line in lines.iter().filter(|x| !x.starts_with('!')) {
+	let selector = Selector::parse(&[&[line.clone()], &excludes[..]].concat())?;
+	for name in selector.select_from(&json_input.defines) {
+	    if json_input.get_define(&name).is_some() {
+		wanted.push(Symbol::new(SymbolKind::Define, &name));
+	    }
+	}
+	for name in selector.select_from(&json_input.structs) {
+	    if json_input.get_struct(&name).is_some() {
+		wanted.push(Symbol::new(SymbolKind::Struct, &name));
+	    }
+	}
+    }
+
+    if !emit_sources {
+	json_input.strip_sources();
+    }
 
     let mut out_file = File::create(args[3].clone())?;
 
@@ -104,26 +159,49 @@ fn main() -> std::io::Result<()> {
 //	writeln!(out_file, "#define {}_{} {}", base_type, ver_str, base_type)?;
     }
     writeln!(out_file)?;
-    for sym_name in sym_reader.lines() {
-	let name = sym_name.unwrap();
-
-	for (defname, define) in &json_input.defines {
-	    if *defname == name {
-		match define.hwtype {
-		    HWDefineType::Value => generate_define(&mut out_file, &ver_str.as_str(), &defname, define),
-		    HWDefineType::Unknown => todo!(),
-		}?;
-		writeln!(&out_file).unwrap();
-		break;
-	    }
-	}
-
-	for (strname, structinfo) in &json_input.structs {
-	    if *strname == name {
-		generate_hw_struct(&mut out_file, &ver_str.as_str(), &strname, structinfo)?;
-	    }
-	}
+    let mut closure = Closure::new();
+    for symbol in wanted {
+	generate_with_deps(&mut out_file, &ver_str, &json_input, &mut closure, symbol)?;
     }
     writeln!(out_file, "#endif")?;
+    closure.report(&mut std::io::stderr())?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // typedef enum tag { A = 1 } NAME; with A and NAME wanted
+    #[test]
+    fn enum_constants_are_not_defined_twice() {
+	let mut json_input: HWJson = Default::default();
+	json_input.enums.insert("tag".to_string(), HWEnum {
+	    size: 32,
+	    members: vec![HWEnumMember { name: "A".to_string(), value: 1 }],
+	    ..Default::default()
+	});
+	json_input.enums.insert("NAME".to_string(), HWEnum { size: 32, alias_of: "tag".to_string(), ..Default::default() });
+	json_input.defines.insert("A".to_string(), HWDefine {
+	    hwtype: HWDefineType::Value,
+	    vals: vec!["1".to_string()],
+	    value: Some(1),
+	    enum_name: "tag".to_string(),
+	    ..Default::default()
+	});
+
+	let path = std::env::temp_dir().join(format!("generate_hw-test-{}.h", std::process::id()));
+	let mut out_file = File::create(&path).unwrap();
+	let mut closure = Closure::new();
+	generate_with_deps(&mut out_file, "", &json_input, &mut closure, Symbol::new(SymbolKind::Define, "A")).unwrap();
+	generate_with_deps(&mut out_file, "", &json_input, &mut closure, Symbol::new(SymbolKind::Enum, "NAME")).unwrap();
+	drop(out_file);
+	let output = std::fs::read_to_string(&path).unwrap();
+	std::fs::remove_file(&path).unwrap();
+
+	assert!(!output.contains("#define A"));
+	assert_eq!(output.matches("A = 1,").count(), 1);
+	assert!(output.contains("enum tag {"));
+	assert!(output.contains("typedef enum tag NAME;"));
+    }
+}
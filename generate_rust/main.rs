@@ -1,56 +1,9 @@
 
 use std::env;
 use std::fs::File;
-use std::collections::BTreeMap;
-use std::io::{BufReader, Write};
-use serde::{Deserialize, Serialize};
-
-const SPECIAL_TYPES:  [&str;8] = ["NvU32", "NvU64", "NvU16", "NvU8", "NvBool", "char", "NvHandle", "int"];
-
-
-// start/end are in bits
-#[derive(Serialize, Deserialize)]
-struct HWStructField {
-    name: String,
-    start: u32,
-    size: u32,
-    group_len: u32,
-    isint: u32,
-    val_type: String,
-}
-
-#[derive(Serialize, Deserialize)]
-struct HWStruct {
-    total_size: u32,
-    fields: Vec<HWStructField>,
-}
-
-#[derive(Serialize, Deserialize, Default)]
-enum HWDefineType {
-    #[default]
-    Unknown,
-    Value,
-}
-
-#[derive(Serialize, Deserialize, Default)]
-struct HWDefine {
-    hwtype: HWDefineType,
-    vals: Vec<String>,
-}
-
-#[derive(Serialize, Deserialize, Default)]
-struct HWJson {
-    version: String,
-    defines: BTreeMap<String, HWDefine>,
-    structs: BTreeMap<String, HWStruct>,
-}
-
-#[derive(Serialize, Deserialize, Default)]
-struct WantedJson {
-    structs: Vec<String>,
-    cmds: BTreeMap<String, Vec<String>>,
-    defines: Vec<String>,
-}
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use gsp_parse::*;
 
 fn get_val_info(val: String) -> (u32, String) {
     if val.ends_with("ULL") {
@@ -70,6 +23,20 @@ fn get_val_info(val: String) -> (u32, String) {
 }
 
 fn generate_define(out_writer: &mut File, defname: &String, define: &HWDefine) -> std::io::Result<()> {
+    if define.vals.len() == 0 {
+	return Ok(());
+    }
+    write_source_comment(out_writer, &define.source)?;
+    // evaluated defines know their width and signedness, the literal
+    // as written may be octal or carry C suffixes so use the value.
+    if let (1, Some(value)) = (define.vals.len(), define.value) {
+	if define.signed || value < 0 {
+	    writeln!(out_writer, "pub(crate) const {}: i{} = {};", defname, define.width, value)?;
+	} else {
+	    writeln!(out_writer, "pub(crate) const {}: u{} = {:#x};", defname, define.width, value)?;
+	}
+	return Ok(());
+    }
     let (valsize, valstr) = get_val_info(define.vals[0].clone());
     if define.vals.len() == 2 {
 	if define.vals[0] == define.vals[1] {
@@ -85,29 +52,308 @@ fn generate_define(out_writer: &mut File, defname: &String, define: &HWDefine) -
     Ok(())
 }
 
-fn generate_hw_struct(out_writer: &mut File, strname: &String, hwstruct: &HWStruct) -> std::io::Result<()> {
-    writeln!(out_writer, "struct {} {{", strname)?;
-
-    for field in &hwstruct.fields {
-	let typestr = match field.size {
-	    64 => "u64",
-	    32 => "u32",
-	    16 => "u16",
-	    8 => "u8",
-	    _ => "u32",
-	};
-	if field.group_len > 0 {
-	    writeln!(out_writer, "    {} {}[{}];", typestr, field.name, field.group_len)?;
+// bitfields share their storage unit with other fields so
+// the setters have to read-modify-write the whole unit.
+fn emit_bitfield_accessors(out_file: &mut File, fld_name: &String, fld: &HWStructField) -> std::io::Result<()> {
+    let start = fld.start / 8;
+    let end = (fld.start + fld.size) / 8;
+    let mask: u64 = if fld.bit_width >= 64 { u64::MAX } else { (1u64 << fld.bit_width) - 1 };
+
+    writeln!(out_file, "    pub(crate) fn {}(self, fld: u{}) -> Self {{", fld_name, fld.size)?;
+    writeln!(out_file, "        let val = u{}::from_le_bytes(self.store[{}..{}].try_into().unwrap());", fld.size, start, end)?;
+    writeln!(out_file, "        let val = (val & !({:#x} << {})) | ((fld & {:#x}) << {});", mask, fld.bit_offset, mask, fld.bit_offset)?;
+    writeln!(out_file, "        self.store[{}..{}].copy_from_slice(&u{}::to_le_bytes(val));", start, end, fld.size)?;
+    writeln!(out_file, "    self }}")?;
+
+    writeln!(out_file, "")?;
+    writeln!(out_file, "    pub(crate) fn get_{}(&self) -> u{} {{", fld_name, fld.size)?;
+    writeln!(out_file, "        (u{}::from_le_bytes(self.store[{}..{}].try_into().unwrap()) >> {}) & {:#x}", fld.size, start, end, fld.bit_offset, mask)?;
+    writeln!(out_file, "    }}")?;
+
+    writeln!(out_file, "    pub(crate) fn set_{}(&mut self, fld: u{}) {{", fld_name, fld.size)?;
+    writeln!(out_file, "        let val = u{}::from_le_bytes(self.store[{}..{}].try_into().unwrap());", fld.size, start, end)?;
+    writeln!(out_file, "        let val = (val & !({:#x} << {})) | ((fld & {:#x}) << {});", mask, fld.bit_offset, mask, fld.bit_offset)?;
+    writeln!(out_file, "        self.store[{}..{}].copy_from_slice(&u{}::to_le_bytes(val));", start, end, fld.size)?;
+    writeln!(out_file, "    }}")?;
+    Ok(())
+}
+
+// C enums can give several names the same value, rust enums can't,
+// so later duplicates become associated consts of the first variant.
+fn emit_hw_enum(json_input: &HWJson, out_file: &mut File, sym_enum: String) -> std::io::Result<()> {
+    let hwenum = match json_input.enums.get(&sym_enum) {
+	Some(x) => { x }
+	None => { return Ok(()); }
+    };
+    if hwenum.alias_of != "" {
+	write_source_comment(out_file, &hwenum.source)?;
+	writeln!(out_file, "pub(crate) type {} = {};", sym_enum, hwenum.alias_of)?;
+	return writeln!(out_file, "");
+    }
+    let repr = format!("{}{}", if hwenum.signed { "i" } else { "u" }, hwenum.size);
+    let mut variants: Vec<&HWEnumMember> = Default::default();
+    let mut aliases: Vec<(&HWEnumMember, &HWEnumMember)> = Default::default();
+    for member in &hwenum.members {
+	match variants.iter().find(|v| v.value == member.value) {
+	    Some(orig) => aliases.push((member, orig)),
+	    None => variants.push(member),
+	}
+    }
+
+    write_source_comment(out_file, &hwenum.source)?;
+    writeln!(out_file, "#[repr({})]", repr)?;
+    writeln!(out_file, "#[derive(Clone, Copy, Debug, PartialEq, Eq)]")?;
+    writeln!(out_file, "pub(crate) enum {} {{", sym_enum)?;
+    for member in &variants {
+	writeln!(out_file, "    {} = {},", member.name, member.value)?;
+    }
+    writeln!(out_file, "}}")?;
+    writeln!(out_file, "")?;
+
+    if aliases.len() > 0 {
+	writeln!(out_file, "impl {} {{", sym_enum)?;
+	for (member, orig) in &aliases {
+	    writeln!(out_file, "    pub(crate) const {}: {} = {}::{};", member.name, sym_enum, sym_enum, orig.name)?;
+	}
+	writeln!(out_file, "}}")?;
+	writeln!(out_file, "")?;
+    }
+
+    writeln!(out_file, "impl TryFrom<{}> for {} {{", repr, sym_enum)?;
+    writeln!(out_file, "    type Error = {};", repr)?;
+    writeln!(out_file, "")?;
+    writeln!(out_file, "    fn try_from(val: {}) -> Result<Self, Self::Error> {{", repr)?;
+    writeln!(out_file, "        match val {{")?;
+    for member in &variants {
+	writeln!(out_file, "            {} => Ok({}::{}),", member.value, sym_enum, member.name)?;
+    }
+    writeln!(out_file, "            _ => Err(val),")?;
+    writeln!(out_file, "        }}")?;
+    writeln!(out_file, "    }}")?;
+    writeln!(out_file, "}}")?;
+    writeln!(out_file, "")?;
+    Ok(())
+}
+
+// the accessors for one field, offsets are from the start of the
+// outer struct so they work in union views too.
+// the rust type for a scalar field, unknown types stay unsigned.
+// bools are stored as an unsigned integer and converted on access.
+fn scalar_type_name(scalars: &BTreeMap<String, HWScalar>, fld: &HWStructField) -> (String, bool) {
+    let scalar = match scalars.get(&fld.scalar) {
+	Some(x) => { x }
+	None => { return (format!("u{}", fld.size), false) }
+    };
+    if scalar.boolean {
+	("bool".to_string(), true)
+    } else if scalar.float && (fld.size == 32 || fld.size == 64) {
+	(format!("f{}", fld.size), false)
+    } else if scalar.signed && !scalar.float {
+	(format!("i{}", fld.size), false)
+    } else {
+	(format!("u{}", fld.size), false)
+    }
+}
+
+fn emit_field_accessors(out_file: &mut File, scalars: &BTreeMap<String, HWScalar>, fld: &HWStructField) -> std::io::Result<()> {
+    let (mut fld_type_name, mut is_bool) = scalar_type_name(scalars, fld);
+    let mut fld_is_struct: bool = false;
+
+    if fld.size == 0 {
+	return Ok(());
+    }
+
+    if fld.isint == 0 {
+	fld_type_name = "s_".to_owned() + &fld.val_type.clone();
+	fld_is_struct = true;
+    }
+
+    if fld_is_struct {
+	writeln!(out_file, "")?;
+
+	if fld.group_len != 0xffffffff {
+	    writeln!(out_file, "    pub(crate) fn new_S_{}(&mut self, idx: isize) -> s_{}<'s> {{", fld.name, fld.val_type)?;
+	    writeln!(out_file, "        s_{}::new(unsafe {{ self.ptr.byte_offset(idx * {} + {}) }})", fld.val_type, fld.size / 8, fld.start / 8)?;
 	} else {
-	    writeln!(out_writer, "    {} {};", typestr, field.name)?;
+	    writeln!(out_file, "    pub(crate) fn new_S_{}(&mut self) -> s_{}<'s> {{", fld.name, fld.val_type)?;
+	    writeln!(out_file, "        s_{}::new(unsafe {{ self.ptr.byte_offset({}) }})", fld.val_type, fld.start / 8)?;
+	}
+	writeln!(out_file, "    }}")?;
+	writeln!(out_file, "")?;
+	return Ok(());
+    }
+
+    let mut fld_name = fld.name.clone();
+    if fld.name == "type" {
+	fld_name = "r".to_string() + &fld.name;
+    }
+    if fld.bit_width > 0 {
+	emit_bitfield_accessors(out_file, &fld_name, fld)?;
+	return Ok(());
+    }
+    // arrays of bools are left as the integers they are stored as
+    if fld.group_len != 0xffffffff && is_bool {
+	fld_type_name = format!("u{}", fld.size);
+	is_bool = false;
+    }
+    let to_store = if is_bool { format!("u{}::to_le_bytes(fld as u{})", fld.size, fld.size) } else { format!("{}::to_le_bytes(fld)", fld_type_name) };
+    let from_store = if is_bool { format!("u{}::from_le_bytes", fld.size) } else { format!("{}::from_le_bytes", fld_type_name) };
+    let from_suffix = if is_bool { " != 0" } else { "" };
+    if fld.group_len != 0xffffffff {
+	writeln!(out_file, "    pub(crate) fn {}(self, fld: [{}; {}]) -> Self {{", fld_name, fld_type_name, fld.group_len)?;
+
+	writeln!(out_file, "        let mut byte_data = [0u8; {}];", fld.group_len * (fld.size / 8))?;
+	writeln!(out_file, "        for i in 0..{} {{", fld.group_len)?;
+	writeln!(out_file, "            let bytes = fld[i].to_le_bytes();")?;
+	writeln!(out_file, "            byte_data[(i * {})..((i + 1) * {})].copy_from_slice(&bytes);", fld.size / 8, fld.size / 8)?;
+	writeln!(out_file, "        }}")?;
+	writeln!(out_file, "        self.store[{}..{}].copy_from_slice(&byte_data);", fld.start / 8, (fld.start + (fld.size * fld.group_len)) / 8)?;
+	writeln!(out_file, "    self }}")?;
+
+	writeln!(out_file, "    pub(crate) fn set_{}(&mut self, fld: [{}; {}]) {{", fld_name, fld_type_name, fld.group_len)?;
+
+	writeln!(out_file, "        let mut byte_data = [0u8; {}];", fld.group_len * (fld.size / 8))?;
+	writeln!(out_file, "        for i in 0..{} {{", fld.group_len)?;
+	writeln!(out_file, "            let bytes = fld[i].to_le_bytes();")?;
+	writeln!(out_file, "            byte_data[(i * {})..((i + 1) * {})].copy_from_slice(&bytes);", fld.size / 8, fld.size / 8)?;
+	writeln!(out_file, "        }}")?;
+	writeln!(out_file, "        self.store[{}..{}].copy_from_slice(&byte_data);", fld.start / 8, (fld.start + (fld.size * fld.group_len)) / 8)?;
+	writeln!(out_file, "    }}")?;
+
+	writeln!(out_file, "    pub(crate) fn get_{}(&mut self) -> [{}; {}] {{", fld_name, fld_type_name, fld.group_len)?;
+	writeln!(out_file, "        let mut array = [0{}; {}];", fld_type_name, fld.group_len)?;
+	writeln!(out_file, "        for (i, chunk) in self.store[{}..{}].chunks_exact({}).enumerate() {{", fld.start / 8, (fld.start + (fld.size * fld.group_len)) / 8, fld.size / 8)?;
+	writeln!(out_file, "            array[i] = {}::from_le_bytes(chunk.try_into().unwrap());", fld_type_name)?;
+	writeln!(out_file, "        }}")?;
+	writeln!(out_file, "        array")?;
+	writeln!(out_file, "    }}")?;
+    } else {
+	writeln!(out_file, "    pub(crate) fn {}(self, fld: {}) -> Self {{", fld_name, fld_type_name)?;
+	writeln!(out_file, "        self.store[{}..{}].copy_from_slice(&{});", fld.start / 8, (fld.start + fld.size) / 8, to_store)?;
+	writeln!(out_file, "    self }}")?;
+
+	writeln!(out_file, "")?;
+	writeln!(out_file, "    pub(crate) fn get_{}(&self) -> {} {{", fld_name, fld_type_name)?;
+	writeln!(out_file, "        {}(self.store[{}..{}].try_into().unwrap()){}", from_store, fld.start / 8, (fld.start + fld.size) / 8, from_suffix)?;
+	writeln!(out_file, "    }}")?;
+
+	writeln!(out_file, "    pub(crate) fn set_{}(&mut self, fld: {}) {{", fld_name, fld_type_name)?;
+	writeln!(out_file, "        self.store[{}..{}].copy_from_slice(&{});", fld.start / 8, (fld.start + fld.size) / 8, to_store)?;
+	writeln!(out_file, "    }}")?;
+    }
+    Ok(())
+}
+
+fn union_ident(union_idx: usize, hwunion: &HWUnion) -> String {
+    if hwunion.name == "" {
+	format!("union{}", union_idx)
+    } else {
+	hwunion.name.clone()
+    }
+}
+
+fn alternative_ident(alt_idx: usize, alt: &HWUnionAlternative) -> String {
+    if alt.name == "" {
+	format!("alt{}", alt_idx)
+    } else {
+	alt.name.clone()
+    }
+}
+
+// the innermost union and alternative each union field belongs to,
+// nested unions come after their parent so they overwrite it.
+fn union_fields(hwstruct: &HWStruct) -> HashMap<&str, (usize, usize)> {
+    let mut field_union: HashMap<&str, (usize, usize)> = Default::default();
+    for (union_idx, hwunion) in hwstruct.unions.iter().enumerate() {
+	for (alt_idx, alt) in hwunion.alternatives.iter().enumerate() {
+	    for fld in &alt.fields {
+		field_union.insert(fld.as_str(), (union_idx, alt_idx));
+	    }
 	}
     }
-    writeln!(out_writer, "}};")
+    field_union
+}
+
+// union and alternative views share the storage of the outer struct
+// and borrow it mutably, so only one alternative is usable at a time.
+fn emit_view_type(out_file: &mut File, view_name: &String) -> std::io::Result<()> {
+    writeln!(out_file, "pub(crate) struct {}<'s> {{", view_name)?;
+    writeln!(out_file, "    ptr: *mut u8,")?;
+    writeln!(out_file, "    store: &'s mut[u8],")?;
+    writeln!(out_file, "}}")?;
+    writeln!(out_file, "")?;
+    Ok(())
+}
+
+fn emit_view_getter(out_file: &mut File, getter: &String, view_name: &String) -> std::io::Result<()> {
+    writeln!(out_file, "    pub(crate) fn {}(&mut self) -> {}<'_> {{", getter, view_name)?;
+    writeln!(out_file, "        {} {{ ptr: self.ptr, store: &mut self.store[..] }}", view_name)?;
+    writeln!(out_file, "    }}")?;
+    Ok(())
+}
+
+fn emit_union_getter(out_file: &mut File, sym_struct: &String, union_idx: usize, hwunion: &HWUnion) -> std::io::Result<()> {
+    let ident = union_ident(union_idx, hwunion);
+    emit_view_getter(out_file, &format!("u_{}", ident), &format!("u_{}_{}", sym_struct, ident))
+}
+
+fn emit_alternative_getters(out_file: &mut File, sym_struct: &String, union_idx: usize, hwunion: &HWUnion) -> std::io::Result<()> {
+    let ident = union_ident(union_idx, hwunion);
+    for (alt_idx, alt) in hwunion.alternatives.iter().enumerate() {
+	let alt_ident = alternative_ident(alt_idx, alt);
+	emit_view_getter(out_file, &format!("a_{}", alt_ident), &format!("a_{}_{}_{}", sym_struct, ident, alt_ident))?;
+    }
+    Ok(())
+}
+
+fn emit_union_views(out_file: &mut File, scalars: &BTreeMap<String, HWScalar>,
+		    sym_struct: &String, hwstruct: &HWStruct,
+		    field_union: &HashMap<&str, (usize, usize)>) -> std::io::Result<()> {
+    for (union_idx, hwunion) in hwstruct.unions.iter().enumerate() {
+	let ident = union_ident(union_idx, hwunion);
+	let view_name = format!("u_{}_{}", sym_struct, ident);
+	emit_view_type(out_file, &view_name)?;
+	writeln!(out_file, "impl<'s> {}<'s> {{", view_name)?;
+	writeln!(out_file, "    pub(crate) const fn union_size() -> usize {{")?;
+	writeln!(out_file, "        {}", hwunion.size / 8)?;
+	writeln!(out_file, "    }}")?;
+	emit_alternative_getters(out_file, sym_struct, union_idx, hwunion)?;
+	writeln!(out_file, "}}")?;
+	writeln!(out_file, "")?;
+
+	for (alt_idx, alt) in hwunion.alternatives.iter().enumerate() {
+	    let alt_name = format!("{}_{}", ident, alternative_ident(alt_idx, alt));
+	    let view_name = format!("a_{}_{}", sym_struct, alt_name);
+	    emit_view_type(out_file, &view_name)?;
+	    writeln!(out_file, "impl<'s> {}<'s> {{", view_name)?;
+	    for fld in &hwstruct.fields {
+		if field_union.get(fld.name.as_str()) == Some(&(union_idx, alt_idx)) {
+		    emit_field_accessors(out_file, scalars, fld)?;
+		}
+	    }
+	    for (inner_idx, inner) in hwstruct.unions.iter().enumerate() {
+		if inner.parent_union == Some((union_idx as u32, alt_idx as u32)) {
+		    emit_union_getter(out_file, sym_struct, inner_idx, inner)?;
+		}
+	    }
+	    writeln!(out_file, "}}")?;
+	    writeln!(out_file, "")?;
+	}
+    }
+    Ok(())
 }
 
 fn emit_hw_struct(json_input: &HWJson, out_file: &mut File, sym_struct: String) -> std::io::Result<()> {
     for (strname, structinfo) in &json_input.structs {
 	if *strname == sym_struct {
+	    if structinfo.opaque {
+		eprintln!("{} is an opaque type, no accessors generated", strname);
+		continue;
+	    }
+	    for name in structinfo.name_collisions() {
+		eprintln!("{}: field {} appears more than once after flattening", strname, name);
+	    }
+	    write_source_comment(out_file, &structinfo.source)?;
 	    writeln!(out_file, "pub(crate) struct s_{}<'s> {{", sym_struct)?;
 	    writeln!(out_file, "    ptr: *mut u8,")?;
 	    writeln!(out_file, "    store: &'s mut[u8],")?;
@@ -117,102 +363,72 @@ fn emit_hw_struct(json_input: &HWJson, out_file: &mut File, sym_struct: String)
 	    writeln!(out_file, "    pub(crate) const fn str_size() -> usize {{")?;
 	    writeln!(out_file, "        {}", structinfo.total_size / 8)?;
 	    writeln!(out_file, "    }}")?;
+	    if structinfo.alignment > 0 {
+		writeln!(out_file, "    pub(crate) const fn str_align() -> usize {{")?;
+		writeln!(out_file, "        {}", structinfo.alignment)?;
+		writeln!(out_file, "    }}")?;
+	    }
 	    writeln!(out_file, "    pub(crate) fn new(ptr: *mut u8) -> Self {{ Self {{")?;
 	    writeln!(out_file, "        ptr,")?;
 	    writeln!(out_file, "        store: unsafe {{ core::slice::from_raw_parts_mut(ptr, {}) }},", structinfo.total_size / 8)?;
 	    writeln!(out_file, "    }} }}")?;
 	    writeln!(out_file, "")?;
+	    let field_union = union_fields(structinfo);
 	    for fld in &structinfo.fields {
-		let mut fld_type_name = format!("u{}", fld.size);
-		let mut fld_is_struct: bool = false;
-
-		if fld.size == 0 {
+		if field_union.contains_key(fld.name.as_str()) {
 		    continue;
 		}
-
-		if fld.isint == 0 {
-		    fld_type_name = "s_".to_owned() + &fld.val_type.clone();
-		    fld_is_struct = true;
-		}
-
-		if fld_is_struct {
-		    writeln!(out_file, "")?;
-
-		    if fld.group_len != 0xffffffff {
-			writeln!(out_file, "    pub(crate) fn new_S_{}(&mut self, idx: isize) -> s_{}<'s> {{", fld.name, fld.val_type)?;
-			writeln!(out_file, "        s_{}::new(unsafe {{ self.ptr.byte_offset(idx * {} + {}) }})", fld.val_type, fld.size / 8, fld.start / 8)?;
-		    } else {
-			writeln!(out_file, "    pub(crate) fn new_S_{}(&mut self) -> s_{}<'s> {{", fld.name, fld.val_type)?;
-			writeln!(out_file, "        s_{}::new(unsafe {{ self.ptr.byte_offset({}) }})", fld.val_type, fld.start / 8)?;
-		    }
-		    writeln!(out_file, "    }}")?;
-		    writeln!(out_file, "")?;
+		emit_field_accessors(out_file, &json_input.scalars, fld)?;
+	    }
+	    for (union_idx, hwunion) in structinfo.unions.iter().enumerate() {
+		if hwunion.parent_union.is_some() {
 		    continue;
 		}
-
-		let mut fld_name = fld.name.clone();
-		if fld.name == "type" {
-		    fld_name = "r".to_string() + &fld.name;
-		}
-		if fld.group_len != 0xffffffff {
-		    writeln!(out_file, "    pub(crate) fn {}(self, fld: [{}; {}]) -> Self {{", fld_name, fld_type_name, fld.group_len)?;
-
-		    writeln!(out_file, "        let mut byte_data = [0u8; {}];", fld.group_len * (fld.size / 8))?;
-		    writeln!(out_file, "        for i in 0..{} {{", fld.group_len)?;
-		    writeln!(out_file, "            let bytes = fld[i].to_le_bytes();")?;
-		    writeln!(out_file, "            byte_data[(i * {})..((i + 1) * {})].copy_from_slice(&bytes);", fld.size / 8, fld.size / 8)?;
-		    writeln!(out_file, "        }}")?;
-		    writeln!(out_file, "        self.store[{}..{}].copy_from_slice(&byte_data);", fld.start / 8, (fld.start + (fld.size * fld.group_len)) / 8)?;
-		    writeln!(out_file, "    self }}")?;
-
-		    writeln!(out_file, "    pub(crate) fn set_{}(&mut self, fld: [{}; {}]) {{", fld_name, fld_type_name, fld.group_len)?;
-
-		    writeln!(out_file, "        let mut byte_data = [0u8; {}];", fld.group_len * (fld.size / 8))?;
-		    writeln!(out_file, "        for i in 0..{} {{", fld.group_len)?;
-		    writeln!(out_file, "            let bytes = fld[i].to_le_bytes();")?;
-		    writeln!(out_file, "            byte_data[(i * {})..((i + 1) * {})].copy_from_slice(&bytes);", fld.size / 8, fld.size / 8)?;
-		    writeln!(out_file, "        }}")?;
-		    writeln!(out_file, "        self.store[{}..{}].copy_from_slice(&byte_data);", fld.start / 8, (fld.start + (fld.size * fld.group_len)) / 8)?;
-		    writeln!(out_file, "    }}")?;
-
-		    writeln!(out_file, "    pub(crate) fn get_{}(&mut self) -> [{}; {}] {{", fld_name, fld_type_name, fld.group_len)?;
-		    writeln!(out_file, "        let mut array = [0{}; {}];", fld_type_name, fld.group_len)?;
-		    writeln!(out_file, "        for (i, chunk) in self.store[{}..{}].chunks_exact({}).enumerate() {{", fld.start / 8, (fld.start + (fld.size * fld.group_len)) / 8, fld.size / 8)?;
-		    writeln!(out_file, "            array[i] = {}::from_le_bytes(chunk.try_into().unwrap());", fld_type_name)?;
-		    writeln!(out_file, "        }}")?;
-		    writeln!(out_file, "        array")?;
-		    writeln!(out_file, "    }}")?;
+		// a typedef union has its alternatives directly on the type
+		if structinfo.is_union && union_idx == 0 {
+		    emit_alternative_getters(out_file, &sym_struct, union_idx, hwunion)?;
 		} else {
-		    writeln!(out_file, "    pub(crate) fn {}(self, fld: {}) -> Self {{", fld_name, fld_type_name)?;
-		    writeln!(out_file, "        self.store[{}..{}].copy_from_slice(&u{}::to_le_bytes(fld));", fld.start / 8, (fld.start + fld.size) / 8, fld.size)?;
-		    writeln!(out_file, "    self }}")?;
-
-		    writeln!(out_file, "")?;
-		    writeln!(out_file, "    pub(crate) fn get_{}(&self) -> {} {{", fld_name, fld_type_name)?;
-		    writeln!(out_file, "        u{}::from_le_bytes(self.store[{}..{}].try_into().unwrap())", fld.size, fld.start / 8, (fld.start + fld.size) / 8)?;
-		    writeln!(out_file, "    }}")?;
-
-		    writeln!(out_file, "    pub(crate) fn set_{}(&mut self, fld: {}) {{", fld_name, fld_type_name)?;
-		    writeln!(out_file, "        self.store[{}..{}].copy_from_slice(&u{}::to_le_bytes(fld));", fld.start / 8, (fld.start + fld.size) / 8, fld.size)?;
-		    writeln!(out_file, "    }}")?;
+		    emit_union_getter(out_file, &sym_struct, union_idx, hwunion)?;
 		}
 	    }
 	    writeln!(out_file, "}}")?;
 	    writeln!(out_file, "")?;
+	    emit_union_views(out_file, &json_input.scalars, &sym_struct, structinfo, &field_union)?;
 	}
     }
     Ok(())
 }
 
-fn main() -> std::io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let file = File::open(args[1].clone())?;
-    let reader = BufReader::new(file);
-    let json_input: HWJson = serde_json::from_reader(reader)?;
+// a wanted symbol and whatever it needs that isn't in the output yet
+fn emit_with_deps(json_input: &HWJson, out_file: &mut File, closure: &mut Closure, symbol: Symbol) -> std::io::Result<()> {
+    for symbol in closure.add(json_input, symbol) {
+	match symbol.kind {
+	    SymbolKind::Define => {
+		match json_input.get_define(&symbol.name) {
+		    Some(x) if x.hwtype == HWDefineType::Value => { generate_define(out_file, &symbol.name, x)?; }
+		    _ => {}
+		}
+	    }
+	    SymbolKind::Enum => { emit_hw_enum(json_input, out_file, symbol.name)?; }
+	    SymbolKind::Struct => { emit_hw_struct(json_input, out_file, symbol.name)?; }
+	    SymbolKind::Typedef => {}
+	}
+    }
+    Ok(())
+}
 
-    let sym_list = File::open(args[2].clone())?;
-    let sym_reader = BufReader::new(sym_list);
-    let sym_json: WantedJson = serde_json::from_reader(sym_reader)?;
+fn main() -> std::io::Result<()> {
+    // --sources adds a comment with the defining header to each symbol
+    let emit_sources = env::args().any(|a| a == "--sources");
+    let args: Vec<String> = env::args().filter(|a| a != "--sources").collect();
+    let mut json_input = HWJson::load(&args[1])?;
+    let sym_json = WantedJson::load(&args[2])?;
+
+    // header: selectors need the sources, so select before stripping them
+    let selection = json_input.select(&sym_json.selectors()?);
+    if !emit_sources {
+	json_input.strip_sources();
+    }
 
     let mut out_file = File::create(args[3].clone())?;
 
@@ -224,61 +440,43 @@ fn main() -> std::io::Result<()> {
     writeln!(out_file, "#![allow(non_upper_case_globals)]")?;
     writeln!(out_file)?;
 
-    for sym_define in sym_json.defines {
-	for (defname, define) in &json_input.defines {
-	    if sym_define.chars().last().unwrap() == '*' {
-		if defname.starts_with(&sym_define.strip_suffix("*").unwrap()) {
-		    generate_define(&mut out_file, &defname, &define);
-		}
-	    } else if *defname == sym_define {
-		match define.hwtype {
-		    HWDefineType::Value => generate_define(&mut out_file, &defname, define),
-		    HWDefineType::Unknown => todo!(),
-		}?;
-		break;
+    // everything emitted goes through the closure, so the types wanted
+    // symbols use are emitted before them and nothing is emitted twice
+    let mut closure = Closure::new();
+    for defname in &selection.defines {
+	if let Some(define) = json_input.get_define(defname) {
+	    if define.hwtype == HWDefineType::Unknown && sym_json.defines.contains(defname) {
+		eprintln!("{} has no value", defname);
 	    }
+	    emit_with_deps(&json_input, &mut out_file, &mut closure, Symbol::new(SymbolKind::Define, defname))?;
 	}
     }
 
     writeln!(&mut out_file, "")?;
-    for sym_struct in sym_json.structs {
+    for sym_enum in &selection.enums {
+	emit_with_deps(&json_input, &mut out_file, &mut closure, Symbol::new(SymbolKind::Enum, sym_enum))?;
+    }
+
+    for sym_struct in &selection.structs {
 	println!("{}", sym_struct);
-	emit_hw_struct(&json_input, &mut out_file, sym_struct)?;
+	emit_with_deps(&json_input, &mut out_file, &mut closure, Symbol::new(SymbolKind::Struct, sym_struct))?;
     }
 
-    for cmdgroup in sym_json.cmds {
-	// cmd have a general structure
-	let basename : String = "NV".to_owned() + &cmdgroup.0;
-	for cmd in cmdgroup.1 {
-	    let cmdname = basename.clone() + "_CTRL_CMD_" + &cmd;
-	    let ctrlname = basename.clone() + "_CTRL_" + &cmd;
-
-	    for (defname, define) in &json_input.defines {
-		if defname.starts_with(&cmdname) || defname.starts_with(&ctrlname) {
-		    match define.hwtype {
-			HWDefineType::Value => generate_define(&mut out_file, &defname, define),
-			HWDefineType::Unknown => todo!(),
-		    }?;
-		}
+    // cmd have a general structure
+    for (cmdname, ctrlname) in selection.cmd_prefixes() {
+	for defname in json_input.defines.keys() {
+	    if defname.starts_with(&cmdname) || defname.starts_with(&ctrlname) {
+		emit_with_deps(&json_input, &mut out_file, &mut closure, Symbol::new(SymbolKind::Define, defname))?;
 	    }
+	}
 
-	    /* find the params for this command */
-	    emit_hw_struct(&json_input, &mut out_file, ctrlname.clone() + "_PARAMS");
-	    for (strname, hwstruct) in &json_input.structs {
-		if *strname == ctrlname.clone() + "_PARAMS" {
-		    println!("{:?} {:?}", strname, hwstruct.total_size);
-		    for fld in &hwstruct.fields {
-			if fld.val_type != "" {
-			    for (fldname, fldhwstruct) in &json_input.structs {
-				if *fldname == fld.val_type {
-				    emit_hw_struct(&json_input, &mut out_file, fldname.to_string());
-				}
-			    }
-			}
-		    }
-		}
-	    }
+	/* find the params for this command */
+	let params = ctrlname.clone() + "_PARAMS";
+	if let Some(hwstruct) = json_input.get_struct(&params) {
+	    println!("{:?} {:?}", params, hwstruct.total_size);
 	}
+	emit_with_deps(&json_input, &mut out_file, &mut closure, Symbol::new(SymbolKind::Struct, &params))?;
     }
+    closure.report(&mut std::io::stderr())?;
     Ok(())
 }
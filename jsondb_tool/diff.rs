@@ -0,0 +1,349 @@
+// jsondb diff, what changed in the ABI between two HW databases
+//
+// Defines, structs and enums are matched by name. Struct fields are
+// matched by name too, a field removed and another added at the same
+// offset with the same size is reported as a rename. With a want list
+// only the wanted symbols are compared, along with the structs and
+// enums their fields use, as a change to those changes them too.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use serde::Serialize;
+use gsp_parse::*;
+
+use crate::layout::size_str;
+use crate::query::{field_type, hex_value};
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Change {
+    // define, struct or enum
+    pub symbol: &'static str,
+    pub name: String,
+    pub change: ChangeKind,
+    // for changed symbols, what changed
+    pub details: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DiffReport {
+    pub old_version: String,
+    pub new_version: String,
+    pub changes: Vec<Change>,
+}
+
+// the wanted symbols of either database, the structs and enums closed
+// over the types of their fields
+struct Wanted {
+    defines: BTreeSet<String>,
+    structs: BTreeSet<String>,
+    enums: BTreeSet<String>,
+}
+
+impl Wanted {
+    fn new(want: &WantSelectors, dbs: [&HWJson; 2]) -> Wanted {
+	let mut wanted = Wanted { defines: Default::default(), structs: Default::default(), enums: Default::default() };
+	let mut todo: Vec<String> = Default::default();
+	for db in dbs {
+	    let selection = db.select(want);
+	    // cmds expand as the generators do, NV<group>_CTRL_CMD_<cmd>*
+	    // and NV<group>_CTRL_<cmd>* defines and the
+	    // NV<group>_CTRL_<cmd>_PARAMS struct
+	    for (cmd, ctrl) in selection.cmd_prefixes() {
+		wanted.defines.extend(db.defines.keys().filter(|x| x.starts_with(&cmd) || x.starts_with(&ctrl)).cloned());
+		todo.push(ctrl + "_PARAMS");
+	    }
+	    wanted.defines.extend(selection.defines);
+	    wanted.enums.extend(selection.enums);
+	    todo.extend(selection.structs);
+	}
+	while let Some(name) = todo.pop() {
+	    if !wanted.structs.insert(name.clone()) {
+		continue;
+	    }
+	    for db in dbs {
+		for fld in db.get_struct(&name).map(|x| &x.fields[..]).unwrap_or_default() {
+		    if fld.isint == 0 {
+			todo.push(fld.val_type.clone());
+		    }
+		    if let Some(x) = db.get_enum(&fld.enum_type) {
+			wanted.enums.insert(fld.enum_type.clone());
+			// and the enum a typedef names
+			if x.alias_of != "" {
+			    wanted.enums.insert(x.alias_of.clone());
+			}
+		    }
+		}
+	    }
+	}
+	wanted
+    }
+}
+
+fn define_str(define: &HWDefine) -> String {
+    match (define.value, define.expr.as_str()) {
+	(Some(x), _) => { hex_value(x, define.width).unwrap_or_else(|| x.to_string()) }
+	(None, "") => { define.vals.join(" ") }
+	(None, x) => { x.to_string() }
+    }
+}
+
+fn diff_define(old: &HWDefine, new: &HWDefine) -> Vec<String> {
+    let (old_str, new_str) = (define_str(old), define_str(new));
+    if old_str != new_str {
+	vec![format!("value {} -> {}", old_str, new_str)]
+    } else {
+	vec![]
+    }
+}
+
+fn field_desc(fld: &HWStructField) -> String {
+    let mut desc = format!("{} {}", field_type(fld), fld.name);
+    if fld.is_array() {
+	desc += &format!("[{}]", fld.group_len);
+    }
+    if fld.bit_width > 0 {
+	desc += &format!(":{}", fld.bit_width);
+    }
+    desc + &format!(" at {}", offset_str(fld))
+}
+
+// in bytes, with the bit inside the storage unit for bitfields as in
+// jsondb layout
+fn offset_str(fld: &HWStructField) -> String {
+    if fld.bit_width > 0 {
+	format!("{}:{}", fld.start / 8, fld.bit_offset)
+    } else {
+	format!("{}", fld.start / 8)
+    }
+}
+
+fn same_place(a: &HWStructField, b: &HWStructField) -> bool {
+    a.start == b.start && a.total_size() == b.total_size() && a.bit_offset == b.bit_offset && a.bit_width == b.bit_width
+}
+
+fn diff_field(old: &HWStructField, new: &HWStructField, details: &mut Vec<String>) {
+    let name = &new.name;
+    if old.start != new.start || old.bit_offset != new.bit_offset {
+	details.push(format!("field {} offset {} -> {}", name, offset_str(old), offset_str(new)));
+    }
+    if old.size != new.size {
+	details.push(format!("field {} size {} -> {}", name, size_str(old.size), size_str(new.size)));
+    }
+    if old.group_len != new.group_len {
+	let len = |f: &HWStructField| if f.is_array() { f.group_len.to_string() } else { "not an array".to_string() };
+	details.push(format!("field {} array length {} -> {}", name, len(old), len(new)));
+    }
+    if old.bit_width != new.bit_width {
+	details.push(format!("field {} bit width {} -> {}", name, old.bit_width, new.bit_width));
+    }
+    if field_type(old) != field_type(new) {
+	details.push(format!("field {} type {} -> {}", name, field_type(old), field_type(new)));
+    }
+}
+
+fn diff_struct(old: &HWStruct, new: &HWStruct) -> Vec<String> {
+    let mut details: Vec<String> = Default::default();
+    if old.opaque != new.opaque {
+	details.push(format!("{} opaque", if new.opaque { "now" } else { "no longer" }));
+	return details;
+    }
+    if old.total_size != new.total_size {
+	details.push(format!("size {} -> {}", old.total_size / 8, new.total_size / 8));
+    }
+    if old.alignment != new.alignment {
+	details.push(format!("alignment {} -> {}", old.alignment, new.alignment));
+    }
+    if old.packed != new.packed {
+	details.push(format!("{} packed", if new.packed { "now" } else { "no longer" }));
+    }
+    if old.is_union != new.is_union {
+	details.push(format!("{} a union", if new.is_union { "now" } else { "no longer" }));
+    }
+
+    let mut added: Vec<&HWStructField> = new.fields.iter().filter(|f| old.field(&f.name).is_none()).collect();
+    for fld in &old.fields {
+	match new.field(&fld.name) {
+	    Some(x) => { diff_field(fld, x, &mut details); }
+	    None => {
+		match added.iter().position(|x| same_place(fld, x)) {
+		    Some(idx) => {
+			let renamed = added.remove(idx);
+			details.push(format!("field {} renamed to {}", fld.name, renamed.name));
+			if field_type(fld) != field_type(renamed) {
+			    details.push(format!("field {} type {} -> {}", renamed.name, field_type(fld), field_type(renamed)));
+			}
+		    }
+		    None => { details.push(format!("field {} removed", field_desc(fld))); }
+		}
+	    }
+	}
+    }
+    for fld in added {
+	details.push(format!("field {} added", field_desc(fld)));
+    }
+    details
+}
+
+fn diff_enum(old: &HWEnum, new: &HWEnum) -> Vec<String> {
+    let mut details: Vec<String> = Default::default();
+    if old.alias_of != new.alias_of {
+	details.push(format!("typedef of {} -> {}", old.alias_of, new.alias_of));
+    }
+    if old.size != new.size || old.signed != new.signed {
+	details.push(format!("type {}{} -> {}{}", if old.signed { "s" } else { "u" }, old.size,
+			     if new.signed { "s" } else { "u" }, new.size));
+    }
+    for member in &old.members {
+	match new.members.iter().find(|m| m.name == member.name) {
+	    Some(x) if x.value != member.value => { details.push(format!("{} {} -> {}", member.name, member.value, x.value)); }
+	    Some(_) => {}
+	    None => { details.push(format!("{} = {} removed", member.name, member.value)); }
+	}
+    }
+    for member in &new.members {
+	if !old.members.iter().any(|m| m.name == member.name) {
+	    details.push(format!("{} = {} added", member.name, member.value));
+	}
+    }
+    details
+}
+
+fn diff_maps<T>(symbol: &'static str, old: &BTreeMap<String, T>, new: &BTreeMap<String, T>,
+		wanted: impl Fn(&str) -> bool, diff: impl Fn(&T, &T) -> Vec<String>,
+		changes: &mut Vec<Change>) {
+    let names: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    for name in names {
+	if !wanted(name) {
+	    continue;
+	}
+	let (change, details) = match (old.get(name), new.get(name)) {
+	    (Some(_), None) => { (ChangeKind::Removed, vec![]) }
+	    (None, Some(_)) => { (ChangeKind::Added, vec![]) }
+	    (Some(x), Some(y)) => {
+		let details = diff(x, y);
+		if details.is_empty() {
+		    continue;
+		}
+		(ChangeKind::Changed, details)
+	    }
+	    (None, None) => { continue }
+	};
+	changes.push(Change { symbol, name: name.clone(), change, details });
+    }
+}
+
+pub fn diff(old: &HWJson, new: &HWJson, want: Option<&WantSelectors>) -> DiffReport {
+    let wanted = want.map(|x| Wanted::new(x, [old, new]));
+    let mut changes: Vec<Change> = Default::default();
+    diff_maps("define", &old.defines, &new.defines,
+	      |x| wanted.as_ref().map_or(true, |w| w.defines.contains(x)), diff_define, &mut changes);
+    diff_maps("struct", &old.structs, &new.structs,
+	      |x| wanted.as_ref().map_or(true, |w| w.structs.contains(x)), diff_struct, &mut changes);
+    diff_maps("enum", &old.enums, &new.enums,
+	      |x| wanted.as_ref().map_or(true, |w| w.enums.contains(x)), diff_enum, &mut changes);
+    DiffReport { old_version: old.version.clone(), new_version: new.version.clone(), changes }
+}
+
+pub fn print(out: &mut impl Write, report: &DiffReport) -> std::io::Result<()> {
+    writeln!(out, "{} -> {}", report.old_version, report.new_version)?;
+    for change in &report.changes {
+	let mark = match change.change {
+	    ChangeKind::Added => { "+" }
+	    ChangeKind::Removed => { "-" }
+	    ChangeKind::Changed => { "~" }
+	};
+	writeln!(out, "{} {} {}", mark, change.symbol, change.name)?;
+	for detail in &change.details {
+	    writeln!(out, "    {}", detail)?;
+	}
+    }
+    Ok(())
+}
+
+// returns whether anything changed
+pub fn cmd_diff(old_path: &str, new_path: &str, want_path: Option<&String>, json: bool) -> std::io::Result<bool> {
+    let old = HWJson::load(old_path)?;
+    let new = HWJson::load(new_path)?;
+    let want = match want_path {
+	Some(x) => { Some(WantedJson::load(x)?.selectors()?) }
+	None => { None }
+    };
+    let report = diff(&old, &new, want.as_ref());
+    let mut stdout = std::io::stdout().lock();
+    if json {
+	serde_json::to_writer_pretty(&mut stdout, &report)?;
+	writeln!(stdout)?;
+    } else {
+	print(&mut stdout, &report)?;
+    }
+    stdout.flush()?;
+    Ok(!report.changes.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(name: &str, start: u32, size: u32, val_type: &str) -> HWStructField {
+	HWStructField { name: name.to_string(), start, size, group_len: NOT_ARRAY, isint: 1, val_type: val_type.to_string(), ..Default::default() }
+    }
+
+    fn bitfield(name: &str, start: u32, bit_offset: u32, bit_width: u32) -> HWStructField {
+	HWStructField { bit_offset, bit_width, ..field(name, start, 32, "NvU32") }
+    }
+
+    fn hw_struct(fields: Vec<HWStructField>) -> HWStruct {
+	HWStruct { total_size: 128, fields, ..Default::default() }
+    }
+
+    #[test]
+    fn fields_at_the_same_place_are_renames() {
+	let old = hw_struct(vec![field("a", 0, 32, "NvU32"), field("b", 32, 32, "NvU32"), field("c", 64, 64, "NvU64")]);
+	let new = hw_struct(vec![field("a", 0, 32, "NvU32"), field("flags", 32, 32, "NvS32"), field("d", 64, 32, "NvU32")]);
+	assert_eq!(diff_struct(&old, &new), [
+	    "field b renamed to flags",
+	    "field flags type NvU32 -> NvS32",
+	    "field NvU64 c at 8 removed",
+	    "field NvU32 d at 8 added",
+	]);
+    }
+
+    #[test]
+    fn bitfield_renames_need_the_same_bits() {
+	let old = hw_struct(vec![bitfield("lo", 0, 0, 4), bitfield("hi", 0, 4, 4)]);
+	let new = hw_struct(vec![bitfield("low", 0, 0, 4), bitfield("high", 0, 4, 8)]);
+	assert_eq!(diff_struct(&old, &new), [
+	    "field lo renamed to low",
+	    "field NvU32 hi:4 at 0:4 removed",
+	    "field NvU32 high:8 at 0:4 added",
+	]);
+    }
+
+    #[test]
+    fn offsets_and_sizes_in_bytes() {
+	let old = hw_struct(vec![field("a", 32, 16, "NvU16"), bitfield("b", 64, 1, 2)]);
+	let new = hw_struct(vec![field("a", 64, 32, "NvU32"), bitfield("b", 96, 3, 2)]);
+	assert_eq!(diff_struct(&old, &new), [
+	    "field a offset 4 -> 8",
+	    "field a size 2 -> 4",
+	    "field a type NvU16 -> NvU32",
+	    "field b offset 8:1 -> 12:3",
+	]);
+    }
+
+    #[test]
+    fn negative_define_values() {
+	let old = HWDefine { value: Some(-1), width: 32, signed: true, ..Default::default() };
+	let new = HWDefine { value: Some(-2), width: 32, signed: true, ..Default::default() };
+	assert_eq!(diff_define(&old, &new), ["value 0xffffffff -> 0xfffffffe"]);
+	assert_eq!(diff_define(&HWDefine { width: 0, ..old }, &HWDefine { value: Some(1), ..new }), ["value -1 -> 0x1"]);
+    }
+}
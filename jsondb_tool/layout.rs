@@ -0,0 +1,314 @@
+// jsondb layout, pahole style view of struct layouts
+//
+// Fields are listed with their offset and size in bytes, union members
+// are regrouped from the flattened fields using the unions recorded by
+// the extractor, and the holes between fields, tail padding and the
+// boundaries of cachelines (or any other block size) are marked.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Write};
+use gsp_parse::*;
+
+use crate::query::field_type;
+
+pub const CACHELINE: u32 = 64;
+
+// what to print around a field, opening and closing unions and the
+// anonymous structs used as union members
+enum Mark {
+    Union(usize),
+    Struct,
+    End(String),
+}
+
+struct Marks {
+    before: HashMap<String, Vec<Mark>>,
+    after: HashMap<String, Vec<Mark>>,
+}
+
+// a union member that is itself a union doesn't need a struct around it
+fn is_nested_union(hwstruct: &HWStruct, idx: usize, alt_idx: usize) -> bool {
+    let alt = &hwstruct.unions[idx].alternatives[alt_idx];
+    hwstruct.unions.iter().any(|u| {
+	u.parent_union == Some((idx as u32, alt_idx as u32)) &&
+	    u.alternatives.iter().map(|a| a.fields.len()).sum::<usize>() == alt.fields.len()
+    })
+}
+
+fn union_marks(hwstruct: &HWStruct) -> Marks {
+    let mut marks = Marks { before: HashMap::new(), after: HashMap::new() };
+    // outer unions come first, so opens are appended and the closes of
+    // each union are put in front of the ones of its outer unions, for
+    // a union type unions[0] is the type itself
+    for (idx, hwunion) in hwstruct.unions.iter().enumerate() {
+	let is_self = hwstruct.is_union && idx == 0;
+	let first = match hwunion.alternatives.iter().find_map(|a| a.fields.first()) {
+	    Some(x) => { x.clone() }
+	    None => { continue }
+	};
+	let last = hwunion.alternatives.iter().rev().find_map(|a| a.fields.last()).unwrap().clone();
+	if !is_self {
+	    marks.before.entry(first).or_default().push(Mark::Union(idx));
+	}
+	let mut closes: Vec<(String, Mark)> = Default::default();
+	for (alt_idx, alt) in hwunion.alternatives.iter().enumerate() {
+	    if alt.fields.len() < 2 || is_nested_union(hwstruct, idx, alt_idx) {
+		continue;
+	    }
+	    marks.before.entry(alt.fields[0].clone()).or_default().push(Mark::Struct);
+	    closes.push((alt.fields.last().unwrap().clone(), Mark::End(alt.name.clone())));
+	}
+	let name = hwunion.name.strip_prefix(&hwunion.parent).unwrap_or(&hwunion.name).to_string();
+	if !is_self {
+	    closes.push((last, Mark::End(name)));
+	}
+	let mut grouped: HashMap<String, Vec<Mark>> = HashMap::new();
+	for (fld, mark) in closes {
+	    grouped.entry(fld).or_default().push(mark);
+	}
+	for (fld, mut group) in grouped {
+	    let after = marks.after.entry(fld).or_default();
+	    group.append(after);
+	    *after = group;
+	}
+    }
+    marks
+}
+
+pub fn size_str(bits: u32) -> String {
+    if bits % 8 == 0 {
+	format!("{}", bits / 8)
+    } else {
+	format!("{} bits", bits)
+    }
+}
+
+fn bytes_str(bits: u32) -> String {
+    if bits % 8 == 0 {
+	format!("{} bytes", bits / 8)
+    } else {
+	format!("{} bits", bits)
+    }
+}
+
+fn block_name(boundary: u32) -> String {
+    if boundary == CACHELINE { "cacheline".to_string() } else { format!("{} byte block", boundary) }
+}
+
+// the declaration indented and padded so the comments line up with
+// tabs 8 wide
+fn decl_str(depth: usize, decl: &str) -> String {
+    format!("{}{:<width$}", "\t".repeat(depth), decl, width = 48usize.saturating_sub(8 * depth))
+}
+
+fn indent(depth: usize) -> String {
+    "\t".repeat(depth)
+}
+
+// prints the layout and returns the wasted bits, holes and tail padding
+fn print_layout(out: &mut impl Write, name: &str, hwstruct: &HWStruct, boundary: u32) -> std::io::Result<u32> {
+    let kind = if hwstruct.is_union { "union" } else { "struct" };
+    if hwstruct.opaque {
+	writeln!(out, "{} {};\t/* opaque, no layout */", kind, name)?;
+	return Ok(0);
+    }
+    let marks = union_marks(hwstruct);
+    let holes = match hwstruct.holes.is_empty() {
+	true => { find_holes(&hwstruct.fields, hwstruct.total_size) }
+	false => { hwstruct.holes.clone() }
+    };
+    // the hole running to the end of the struct is tail padding
+    let padding = holes.last().filter(|h| h.start + h.size == hwstruct.total_size).map(|h| h.size).unwrap_or(0);
+    let inner_holes = &holes[..holes.len() - if padding > 0 { 1 } else { 0 }];
+    let mut next_hole = 0;
+    let mut block = 0;
+    let mut depth = 1;
+
+    writeln!(out, "{} {} {{", kind, name)?;
+    for fld in &hwstruct.fields {
+	for mark in marks.before.get(&fld.name).into_iter().flatten() {
+	    match mark {
+		Mark::Union(idx) => {
+		    let hwunion = &hwstruct.unions[*idx];
+		    writeln!(out, "{}/* {:>5} {:>5} */", decl_str(depth, "union {"), hwunion.start / 8, size_str(hwunion.size))?;
+		}
+		_ => { writeln!(out, "{}struct {{", indent(depth))?; }
+	    }
+	    depth += 1;
+	}
+	while next_hole < inner_holes.len() && inner_holes[next_hole].start + inner_holes[next_hole].size <= fld.start {
+	    writeln!(out, "{}/* XXX {} hole, try to pack */", indent(depth), bytes_str(inner_holes[next_hole].size))?;
+	    next_hole += 1;
+	}
+
+	let start = fld.start / 8;
+	let end = (fld.start + fld.total_size() + 7) / 8;
+	if start / boundary > block {
+	    block = start / boundary;
+	    writeln!(out, "{}/* --- {} {} boundary ({} bytes) --- */", indent(depth), block_name(boundary), block, block * boundary)?;
+	}
+	let mut decl = format!("{} {}", field_type(fld), fld.name);
+	if fld.is_array() {
+	    decl += &format!("[{}]", fld.group_len);
+	}
+	let mut note = "";
+	if end > start && start / boundary != (end - 1) / boundary {
+	    note = " /* crosses a boundary */";
+	}
+	if fld.bit_width > 0 {
+	    decl += &format!(":{}", fld.bit_width);
+	    writeln!(out, "{}/* {:>5}:{:>2} {:>5} */{}", decl_str(depth, &(decl + ";")), start, fld.bit_offset, size_str(fld.size), note)?;
+	} else {
+	    writeln!(out, "{}/* {:>5} {:>5} */{}", decl_str(depth, &(decl + ";")), start, size_str(fld.total_size()), note)?;
+	}
+
+	for mark in marks.after.get(&fld.name).into_iter().flatten() {
+	    if let Mark::End(x) = mark {
+		depth -= 1;
+		match x.as_str() {
+		    "" => { writeln!(out, "{}}};", indent(depth))?; }
+		    x => { writeln!(out, "{}}} {};", indent(depth), x)?; }
+		}
+	    }
+	}
+    }
+    for hole in &inner_holes[next_hole..] {
+	writeln!(out, "\t/* XXX {} hole, try to pack */", bytes_str(hole.size))?;
+    }
+
+    let hole_bits: u32 = inner_holes.iter().map(|h| h.size).sum();
+    let size = hwstruct.total_size / 8;
+    writeln!(out)?;
+    writeln!(out, "\t/* size: {}, {}s: {}, members: {} */", size, block_name(boundary), (size + boundary - 1) / boundary, hwstruct.fields.len())?;
+    writeln!(out, "\t/* used: {}, holes: {}, sum holes: {} */", size_str(hwstruct.total_size - hole_bits - padding), inner_holes.len(), size_str(hole_bits))?;
+    if padding > 0 {
+	writeln!(out, "\t/* padding: {} */", size_str(padding))?;
+    }
+    if hwstruct.alignment != 0 || hwstruct.packed {
+	writeln!(out, "\t/* alignment: {}{} */", hwstruct.alignment, if hwstruct.packed { ", packed" } else { "" })?;
+    }
+    writeln!(out, "}};")?;
+    Ok(hole_bits + padding)
+}
+
+pub fn cmd_layout(path: &str, pattern: &str, boundary: u32) -> std::io::Result<()> {
+    let hwjson = HWJson::load(path)?;
+    let selector = Selector::parse(&[pattern.to_string()])?;
+    let mut stdout = std::io::stdout().lock();
+    let mut count = 0;
+    let mut wasted = 0;
+    for (name, hwstruct) in &hwjson.structs {
+	if !selector.matches(name, hwstruct.source()) {
+	    continue;
+	}
+	if count > 0 {
+	    writeln!(stdout)?;
+	}
+	wasted += print_layout(&mut stdout, name, hwstruct, boundary)?;
+	count += 1;
+    }
+    match count {
+	0 => { Err(Error::new(ErrorKind::NotFound, format!("{}: no struct matches {}", path, pattern))) }
+	1 => { Ok(()) }
+	_ => { writeln!(stdout, "\n/* structs: {}, wasted: {} */", count, bytes_str(wasted)) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(name: &str, start: u32, size: u32, val_type: &str) -> HWStructField {
+	HWStructField { name: name.to_string(), start: start * 8, size: size * 8, group_len: NOT_ARRAY, isint: 1, val_type: val_type.to_string(), ..Default::default() }
+    }
+
+    fn layout(name: &str, hwstruct: &HWStruct, boundary: u32) -> (String, u32) {
+	let mut out: Vec<u8> = Default::default();
+	let wasted = print_layout(&mut out, name, hwstruct, boundary).unwrap();
+	(String::from_utf8(out).unwrap(), wasted)
+    }
+
+    // struct { NvU8 a; NvU32 b; union { NvU32 x; struct { NvU16 lo; NvU16 hi; }; } u; NvU8 c; }
+    fn with_union() -> HWStruct {
+	HWStruct {
+	    total_size: 16 * 8,
+	    fields: vec![field("a", 0, 1, "NvU8"), field("b", 4, 4, "NvU32"), field("u_x", 8, 4, "NvU32"),
+			 field("u_lo", 8, 2, "NvU16"), field("u_hi", 10, 2, "NvU16"), field("c", 12, 1, "NvU8")],
+	    unions: vec![HWUnion {
+		name: "u".to_string(),
+		start: 8 * 8,
+		size: 4 * 8,
+		alternatives: vec![HWUnionAlternative { name: "x".to_string(), fields: vec!["u_x".to_string()] },
+				   HWUnionAlternative { name: "".to_string(), fields: vec!["u_lo".to_string(), "u_hi".to_string()] }],
+		..Default::default()
+	    }],
+	    alignment: 4,
+	    ..Default::default()
+	}
+    }
+
+    #[test]
+    fn holes_unions_and_padding() {
+	let (out, wasted) = layout("S", &with_union(), CACHELINE);
+	assert_eq!(out, "struct S {
+	NvU8 a;                                 /*     0     1 */
+	/* XXX 3 bytes hole, try to pack */
+	NvU32 b;                                /*     4     4 */
+	union {                                 /*     8     4 */
+		NvU32 u_x;                      /*     8     4 */
+		struct {
+			NvU16 u_lo;             /*     8     2 */
+			NvU16 u_hi;             /*    10     2 */
+		};
+	} u;
+	NvU8 c;                                 /*    12     1 */
+
+	/* size: 16, cachelines: 1, members: 6 */
+	/* used: 10, holes: 1, sum holes: 3 */
+	/* padding: 3 */
+	/* alignment: 4 */
+};
+");
+	assert_eq!(wasted, 6 * 8);
+    }
+
+    #[test]
+    fn boundaries() {
+	let array = |name: &str, start: u32, len: u32| HWStructField { group_len: len, ..field(name, start, 1, "NvU8") };
+	let hwstruct = HWStruct { total_size: 16 * 8, fields: vec![array("a", 0, 6), field("b", 6, 4, "NvU32"), array("c", 10, 6)], ..Default::default() };
+	let (out, wasted) = layout("S", &hwstruct, 8);
+	assert_eq!(out, "struct S {
+	NvU8 a[6];                              /*     0     6 */
+	NvU32 b;                                /*     6     4 */ /* crosses a boundary */
+	/* --- 8 byte block 1 boundary (8 bytes) --- */
+	NvU8 c[6];                              /*    10     6 */
+
+	/* size: 16, 8 byte blocks: 2, members: 3 */
+	/* used: 16, holes: 0, sum holes: 0 */
+};
+");
+	assert_eq!(wasted, 0);
+    }
+
+    #[test]
+    fn bitfields_and_opaque_records() {
+	let mut lo = field("lo", 0, 4, "NvU32");
+	lo.bit_width = 3;
+	let hi = HWStructField { name: "hi".to_string(), bit_offset: 3, bit_width: 5, ..lo.clone() };
+	let hwstruct = HWStruct { total_size: 4 * 8, fields: vec![lo, hi], ..Default::default() };
+	let (out, wasted) = layout("BITS", &hwstruct, CACHELINE);
+	assert_eq!(out, "struct BITS {
+	NvU32 lo:3;                             /*     0: 0     4 */
+	NvU32 hi:5;                             /*     0: 3     4 */
+
+	/* size: 4, cachelines: 1, members: 2 */
+	/* used: 1, holes: 0, sum holes: 0 */
+	/* padding: 3 */
+};
+");
+	assert_eq!(wasted, 24);
+
+	let opaque = HWStruct { opaque: true, is_union: true, ..Default::default() };
+	assert_eq!(layout("U", &opaque, CACHELINE), ("union U;\t/* opaque, no layout */\n".to_string(), 0));
+    }
+}
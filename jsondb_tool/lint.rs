@@ -0,0 +1,284 @@
+// jsondb lint, check a want list against the databases of every
+// version in fw_list
+//
+// The generators emit nothing for names that don't exist, so typos,
+// stray punctuation and doubled prefixes in a want list go unnoticed.
+// Names missing from every database get the closest existing names as
+// suggestions, names missing from only some versions are listed with
+// those versions. Globs, regexes and header: selectors that match
+// nothing are reported the same way.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{Error, ErrorKind, Write};
+use std::path::Path;
+use gsp_parse::*;
+use gsp_parse::selector::Pattern;
+
+// how many suggestions to give for an unmatched name
+const MAX_SUGGESTIONS: usize = 3;
+
+// the HW database of a version in whichever format it was written in
+fn load_database(dir: &str, version: &str) -> std::io::Result<HWJson> {
+    for format in [Format::Json, Format::Binary, Format::Zstd] {
+	let path = format!("{}/{}.hw.{}", dir, version, format.extension());
+	if Path::new(&path).exists() {
+	    return HWJson::load(&path);
+	}
+    }
+    Err(Error::new(ErrorKind::NotFound, format!("{}: no database for {}, run recreate_hw_json.sh", dir, version)))
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') &&
+	name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// the closest names by edit distance, close enough to be a typo or a
+// doubled or missing prefix
+fn suggest(name: &str, pool: &BTreeSet<&str>) -> Vec<String> {
+    let limit = std::cmp::max(2, name.len() / 4);
+    let mut close: Vec<(usize, &str)> = pool.iter()
+	.filter(|x| x.len().abs_diff(name.len()) <= limit)
+	.map(|x| (strsim::levenshtein(name, x), *x))
+	.filter(|(dist, _)| *dist <= limit)
+	.collect();
+    close.sort();
+    close.iter().take(MAX_SUGGESTIONS).map(|(_, x)| x.to_string()).collect()
+}
+
+fn duplicates<'a>(names: impl Iterator<Item = &'a String>) -> Vec<&'a String> {
+    let mut count: BTreeMap<&String, usize> = Default::default();
+    for name in names {
+	*count.entry(name).or_default() += 1;
+    }
+    count.into_iter().filter(|(_, x)| *x > 1).map(|(x, _)| x).collect()
+}
+
+// whether a selector matches any symbol of a kind
+fn matches_any<T: HasSource>(pattern: &Pattern, map: &BTreeMap<String, T>) -> bool {
+    map.iter().any(|(name, x)| pattern.matches(name, x.source()))
+}
+
+// the part of a selector suggestions and the identifier check apply
+// to, the name or a glob that only has a trailing *
+fn stem(pattern: &Pattern) -> Option<&str> {
+    match pattern {
+	Pattern::Name(x) => { Some(x) }
+	Pattern::Glob(x) => { Some(x.trim_end_matches('*')).filter(|x| !x.contains(|c| c == '*' || c == '?')) }
+	Pattern::Regex(_) | Pattern::Header(_) => { None }
+    }
+}
+
+struct Linter<'a> {
+    versions: &'a [(String, HWJson)],
+    problems: Vec<String>,
+}
+
+impl<'a> Linter<'a> {
+    // report a selector some databases have nothing for, found tells
+    // whether a database has it
+    fn check(&mut self, what: &str, entry: &str, pattern: &Pattern, found: impl Fn(&HWJson) -> bool, pool: &BTreeSet<&str>) {
+	let missing: Vec<&str> = self.versions.iter().filter(|(_, db)| !found(db)).map(|(x, _)| x.as_str()).collect();
+	if missing.is_empty() {
+	    return;
+	}
+	let verb = if let Pattern::Name(_) = pattern { "not found" } else { "matches nothing" };
+	let mut problem = if missing.len() == self.versions.len() {
+	    format!("{} {:?} {} in any database", what, entry, verb)
+	} else {
+	    format!("{} {:?} {} in {}", what, entry, verb, missing.join(", "))
+	};
+	let stem = stem(pattern);
+	if stem.is_some_and(|x| !is_identifier(x)) {
+	    problem += ", not a C identifier";
+	}
+	if let Some(stem) = stem.filter(|_| missing.len() == self.versions.len()) {
+	    let suggestions = suggest(stem, pool);
+	    if !suggestions.is_empty() {
+		problem += &format!(", did you mean {}", suggestions.join(" or "));
+	    }
+	}
+	self.problems.push(problem);
+    }
+
+    // the selector of a want list entry, exclusions are checked like
+    // the rest as one that matches nothing is as likely a typo
+    fn parse(&mut self, what: &str, entry: &str) -> Option<Pattern> {
+	match Pattern::parse(entry.strip_prefix('!').unwrap_or(entry)) {
+	    Ok(x) => { Some(x) }
+	    Err(e) => {
+		self.problems.push(format!("{} {:?} is not a valid selector: {}", what, entry, e));
+		None
+	    }
+	}
+    }
+
+    fn pool(&self, names: impl Fn(&'a HWJson) -> Vec<&'a String>) -> BTreeSet<&'a str> {
+	self.versions.iter().flat_map(|(_, db)| names(db)).map(|x| x.as_str()).collect()
+    }
+
+    fn lint(&mut self, want: &WantedJson) {
+	for (what, list) in [("define", &want.defines), ("struct", &want.structs), ("enum", &want.enums)] {
+	    for name in duplicates(list.iter()) {
+		self.problems.push(format!("{} {:?} is listed more than once", what, name));
+	    }
+	}
+
+	let defines = self.pool(|db| db.defines.keys().collect());
+	for entry in &want.defines {
+	    if let Some(pattern) = self.parse("define", entry) {
+		self.check("define", entry, &pattern, |db| matches_any(&pattern, &db.defines), &defines);
+	    }
+	}
+	let structs = self.pool(|db| db.structs.keys().collect());
+	for entry in &want.structs {
+	    if let Some(pattern) = self.parse("struct", entry) {
+		self.check("struct", entry, &pattern, |db| matches_any(&pattern, &db.structs), &structs);
+	    }
+	}
+	let enums = self.pool(|db| db.enums.keys().collect());
+	for entry in &want.enums {
+	    if let Some(pattern) = self.parse("enum", entry) {
+		self.check("enum", entry, &pattern, |db| matches_any(&pattern, &db.enums), &enums);
+	    }
+	}
+
+	for (group, cmds) in &want.cmds {
+	    let what = format!("cmd {}", group);
+	    for cmd in duplicates(cmds.iter()) {
+		self.problems.push(format!("{} {:?} is listed more than once", what, cmd));
+	    }
+	    // the commands of the group, NV<group>_CTRL_CMD_<cmd>
+	    let prefix = format!("NV{}_CTRL_CMD_", group);
+	    let group_cmds: BTreeSet<&str> = defines.iter().filter_map(|x| x.strip_prefix(&prefix)).collect();
+	    let has_params = |db: &HWJson, cmd: &str| {
+		db.get_struct(&format!("NV{}_CTRL_{}_PARAMS", group, cmd)).is_some() ||
+		    db.get_struct(&format!("{}{}_PARAMS", prefix, cmd)).is_some()
+	    };
+	    for entry in cmds {
+		let pattern = match self.parse(&what, entry) {
+		    Some(x) => { x }
+		    None => { continue; }
+		};
+		// a cmd without a define is still usable through its params
+		let found = |db: &HWJson| match &pattern {
+		    Pattern::Name(cmd) => { db.get_define(&(prefix.clone() + cmd)).is_some() || has_params(db, cmd) }
+		    _ => { db.defines.iter().any(|(name, x)| name.strip_prefix(&prefix).is_some_and(|cmd| pattern.matches(cmd, x.source()))) }
+		};
+		self.check(&what, entry, &pattern, found, &group_cmds);
+		if entry.starts_with('!') {
+		    continue;
+		}
+		// the params struct of every command the entry selects
+		let selected: Vec<String> = match &pattern {
+		    Pattern::Name(cmd) if self.versions.iter().any(|(_, db)| found(db)) => { vec![cmd.clone()] }
+		    Pattern::Name(_) => { vec![] }
+		    _ => {
+			self.versions.iter()
+			    .flat_map(|(_, db)| db.defines.iter().filter_map(|(name, x)| name.strip_prefix(&prefix).filter(|cmd| pattern.matches(cmd, x.source()))))
+			    .collect::<BTreeSet<&str>>().into_iter().map(|x| x.to_string()).collect()
+		    }
+		};
+		for cmd in selected {
+		    let params = format!("NV{}_CTRL_{}_PARAMS", group, cmd);
+		    self.check(&format!("{} params struct", what), &params, &Pattern::Name(params.clone()), |db| has_params(db, &cmd), &structs);
+		}
+	    }
+	}
+    }
+}
+
+// returns whether there were any problems
+pub fn cmd_lint(want_path: &str, fw_list: &str, dir: &str) -> std::io::Result<bool> {
+    let want = WantedJson::load(want_path)?;
+    let mut versions: Vec<(String, HWJson)> = Default::default();
+    for version in fs::read_to_string(fw_list)?.split_whitespace() {
+	versions.push((version.to_string(), load_database(dir, version)?));
+    }
+    if versions.is_empty() {
+	return Err(Error::new(ErrorKind::InvalidInput, format!("{}: no versions listed", fw_list)));
+    }
+
+    let mut linter = Linter { versions: &versions, problems: Default::default() };
+    linter.lint(&want);
+    let mut stdout = std::io::stdout().lock();
+    for problem in &linter.problems {
+	writeln!(stdout, "{}: {}", want_path, problem)?;
+    }
+    stdout.flush()?;
+    Ok(!linter.problems.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database(defines: &[&str], structs: &[&str]) -> HWJson {
+	let mut hwjson: HWJson = Default::default();
+	for name in defines {
+	    hwjson.defines.insert(name.to_string(), HWDefine { value: Some(0), ..Default::default() });
+	}
+	for name in structs {
+	    hwjson.structs.insert(name.to_string(), Default::default());
+	}
+	hwjson
+    }
+
+    fn versions() -> Vec<(String, HWJson)> {
+	vec![
+	    ("535.113.01".to_string(), database(&["NV2080_CTRL_CMD_GPU_GET_INFO", "NV_PFB_SIZE"],
+						 &["NV2080_CTRL_GPU_GET_INFO_PARAMS", "NV2080_CTRL_GPU_INFO"])),
+	    ("570.86.16".to_string(), database(&["NV2080_CTRL_CMD_GPU_GET_INFO", "NV2080_CTRL_CMD_DP_SET_MANUAL_DISPLAYPORT", "NV_PFB_SIZE"],
+						&["NV2080_CTRL_GPU_GET_INFO_PARAMS", "NV2080_CTRL_GPU_INFO", "NV2080_CTRL_DP_SET_MANUAL_DISPLAYPORT_PARAMS"])),
+	]
+    }
+
+    fn lint(want: WantedJson) -> Vec<String> {
+	let versions = versions();
+	let mut linter = Linter { versions: &versions, problems: Default::default() };
+	linter.lint(&want);
+	linter.problems
+    }
+
+    fn list(names: &[&str]) -> Vec<String> {
+	names.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn suggestions() {
+	let pool: BTreeSet<&str> = ["NV2080_CTRL_GPU_INFO", "NV2080_CTRL_GPU_INFO_V2", "NV2080_CTRL_GR_INFO", "NV_PFB_SIZE"].into_iter().collect();
+	assert_eq!(suggest("NV2080_CTRL_GPU_INF0", &pool), ["NV2080_CTRL_GPU_INFO", "NV2080_CTRL_GR_INFO", "NV2080_CTRL_GPU_INFO_V2"]);
+	assert_eq!(suggest("NV2080_CTRL_GPU_INFO_V3", &pool), ["NV2080_CTRL_GPU_INFO_V2", "NV2080_CTRL_GPU_INFO", "NV2080_CTRL_GR_INFO"]);
+	assert_eq!(suggest("NV_PFB", &pool), Vec::<String>::new());
+	assert_eq!(suggest("SOMETHING_ELSE", &pool), Vec::<String>::new());
+    }
+
+    #[test]
+    fn missing_names() {
+	let want = WantedJson {
+	    structs: list(&["NV2080_CTRL_GPU_INF", "NV2080_CTRL_GPU_INFO", "NV2080_CTRL_GPU_INFO", "NV2080_CTRL_DP_SET_MANUAL_DISPLAYPORT_PARAMS"]),
+	    defines: list(&["NV_PFB_SIZE ", "NV_PFB_*", "NV_PMC_*", "/NV_PFB_[/"]),
+	    ..Default::default()
+	};
+	let invalid = format!("define \"/NV_PFB_[/\" is not a valid selector: {}", Pattern::parse("/NV_PFB_[/").err().unwrap());
+	assert_eq!(lint(want), [
+	    "struct \"NV2080_CTRL_GPU_INFO\" is listed more than once",
+	    "define \"NV_PFB_SIZE \" not found in any database, not a C identifier, did you mean NV_PFB_SIZE",
+	    "define \"NV_PMC_*\" matches nothing in any database",
+	    invalid.as_str(),
+	    "struct \"NV2080_CTRL_GPU_INF\" not found in any database, did you mean NV2080_CTRL_GPU_INFO",
+	    "struct \"NV2080_CTRL_DP_SET_MANUAL_DISPLAYPORT_PARAMS\" not found in 535.113.01",
+	]);
+    }
+
+    #[test]
+    fn cmds() {
+	let mut want: WantedJson = Default::default();
+	want.cmds.insert("2080".to_string(), list(&["GPU_GET_INFO", "CMD_DP_SET_MANUAL_DISPLAYPORT", "GPU_*", "!GPU_LEGACY_*"]));
+	assert_eq!(lint(want), [
+	    "cmd 2080 \"CMD_DP_SET_MANUAL_DISPLAYPORT\" not found in any database, did you mean DP_SET_MANUAL_DISPLAYPORT",
+	    "cmd 2080 \"!GPU_LEGACY_*\" matches nothing in any database",
+	]);
+    }
+}
@@ -0,0 +1,149 @@
+// Tools for working with the jsondb databases
+//
+// jsondb schema [hw|c|want]   print the JSON Schema of a database or want list
+// jsondb migrate <in> <out>   rewrite a database in the current schema and the format
+//                             the output name asks for, .bin is binary, .zst compressed
+// jsondb convert <in> <out>   the same as migrate
+// jsondb query <db> <selector> [--json]
+//                             print matching defines, structs, enums and scalars
+// jsondb layout <db> <selector> [--boundary bytes]
+//                             pahole style struct layout with holes and padding
+// jsondb diff <old> <new> [--want list] [--json]
+//                             ABI changes between two versions, exits 1 if anything
+//                             (wanted) changed
+// jsondb lint <want> [--fw-list file] [--dir dir]
+//                             check a want list against the database of every
+//                             version in fw_list, exits 1 on problems
+
+use std::env;
+use std::io::{Error, ErrorKind, Write};
+use gsp_parse::*;
+
+mod diff;
+mod layout;
+mod lint;
+mod query;
+
+fn usage(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput,
+	       format!("{}\nusage: jsondb schema [hw|c|want]\n       jsondb migrate <in> <out>\n       jsondb convert <in> <out>\n       jsondb query <db> <selector> [--json]\n       jsondb layout <db> <selector> [--boundary bytes]\n       jsondb diff <old> <new> [--want list] [--json]\n       jsondb lint <want> [--fw-list file] [--dir dir]", msg))
+}
+
+fn cmd_schema(args: &[String]) -> std::io::Result<()> {
+    let schema = match args.get(0).map(|x| x.as_str()) {
+	Some("hw") | None => { schemars::schema_for!(HWJson) }
+	Some("c") => { schemars::schema_for!(CJson) }
+	Some("want") => { schemars::schema_for!(WantedJson) }
+	Some(x) => { return Err(usage(&format!("unknown schema {}", x))); }
+    };
+    let mut stdout = std::io::stdout().lock();
+    serde_json::to_writer_pretty(&mut stdout, &schema)?;
+    writeln!(stdout)
+}
+
+// loading migrates and the output format comes from the file name,
+// so this both migrates and converts
+fn cmd_migrate(args: &[String]) -> std::io::Result<()> {
+    if args.len() != 2 {
+	return Err(usage("migrate needs an input and an output database"));
+    }
+    let db = Database::load(&args[0])?;
+    db.save(&args[1])
+}
+
+fn cmd_query(args: &[String]) -> std::io::Result<()> {
+    let json = args.iter().any(|a| a == "--json");
+    let args: Vec<&String> = args.iter().filter(|a| *a != "--json").collect();
+    if args.len() != 2 {
+	return Err(usage("query needs a database and a symbol name or selector"));
+    }
+    query::cmd_query(args[0], args[1], json)
+}
+
+// --boundary marks blocks other than cachelines, 8 for the 8 byte
+// alignment of 64 bit fields
+fn cmd_layout(args: &[String]) -> std::io::Result<()> {
+    let mut boundary = layout::CACHELINE;
+    let mut positional: Vec<&String> = Default::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+	if arg == "--boundary" {
+	    boundary = match iter.next().and_then(|x| x.parse::<u32>().ok()) {
+		Some(x) if x > 0 => { x }
+		_ => { return Err(usage("--boundary needs a size in bytes")); }
+	    };
+	} else {
+	    positional.push(arg);
+	}
+    }
+    if positional.len() != 2 {
+	return Err(usage("layout needs a database and a struct name or selector"));
+    }
+    layout::cmd_layout(positional[0], positional[1], boundary)
+}
+
+fn cmd_diff(args: &[String]) -> std::io::Result<()> {
+    let mut json = false;
+    let mut want: Option<String> = None;
+    let mut positional: Vec<&String> = Default::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+	if arg == "--json" {
+	    json = true;
+	} else if arg == "--want" {
+	    want = match iter.next() {
+		Some(x) => { Some(x.clone()) }
+		None => { return Err(usage("--want needs a want list")); }
+	    };
+	} else {
+	    positional.push(arg);
+	}
+    }
+    if positional.len() != 2 {
+	return Err(usage("diff needs an old and a new database"));
+    }
+    if diff::cmd_diff(positional[0], positional[1], want.as_ref(), json)? {
+	std::process::exit(1);
+    }
+    Ok(())
+}
+
+// by default the fw_list and jsondb/ recreate_hw_json.sh uses
+fn cmd_lint(args: &[String]) -> std::io::Result<()> {
+    let mut fw_list = "fw_list".to_string();
+    let mut dir = "jsondb".to_string();
+    let mut positional: Vec<&String> = Default::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+	if arg == "--fw-list" || arg == "--dir" {
+	    let value = match iter.next() {
+		Some(x) => { x.clone() }
+		None => { return Err(usage(&format!("{} needs a value", arg))); }
+	    };
+	    if arg == "--dir" { dir = value; } else { fw_list = value; }
+	} else {
+	    positional.push(arg);
+	}
+    }
+    if positional.len() != 1 {
+	return Err(usage("lint needs a want list"));
+    }
+    if lint::cmd_lint(positional[0], &fw_list, &dir)? {
+	std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn main() -> std::io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(|x| x.as_str()) {
+	Some("schema") => { cmd_schema(&args[2..]) }
+	Some("migrate") | Some("convert") => { cmd_migrate(&args[2..]) }
+	Some("query") => { cmd_query(&args[2..]) }
+	Some("layout") => { cmd_layout(&args[2..]) }
+	Some("diff") => { cmd_diff(&args[2..]) }
+	Some("lint") => { cmd_lint(&args[2..]) }
+	Some(x) => { Err(usage(&format!("unknown command {}", x))) }
+	None => { Err(usage("no command given")) }
+    }
+}
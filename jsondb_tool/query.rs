@@ -0,0 +1,223 @@
+// jsondb query, look symbols up in a HW database
+//
+// Matching defines, structs, enums and scalars are collected into a
+// smaller HWJson along with the struct types their fields use, which
+// is printed as C-like definitions or as json for scripts.
+
+use std::io::{Error, ErrorKind, Write};
+use gsp_parse::*;
+
+// add a struct and, recursively, the structs its fields are typed by
+fn add_struct(hwjson: &HWJson, name: &str, result: &mut HWJson) {
+    let hwstruct = match hwjson.get_struct(name) {
+	Some(x) => { x }
+	None => { return; }
+    };
+    if result.structs.insert(name.to_string(), hwstruct.clone()).is_some() {
+	return;
+    }
+    for fld in &hwstruct.fields {
+	if fld.isint == 0 {
+	    add_struct(hwjson, &fld.val_type, result);
+	}
+	add_enum(hwjson, &fld.enum_type, result);
+    }
+}
+
+// add an enum and the enum it is a typedef of
+fn add_enum(hwjson: &HWJson, name: &str, result: &mut HWJson) {
+    if let Some(x) = hwjson.get_enum(name) {
+	result.enums.insert(name.to_string(), x.clone());
+	add_enum(hwjson, &x.alias_of, result);
+    }
+}
+
+pub fn query(hwjson: &HWJson, selector: &Selector) -> HWJson {
+    let mut result = HWJson {
+	schema_version: hwjson.schema_version,
+	version: hwjson.version.clone(),
+	..Default::default()
+    };
+    for (name, define) in &hwjson.defines {
+	if selector.matches(name, define.source()) {
+	    result.defines.insert(name.clone(), define.clone());
+	}
+    }
+    for (name, hwstruct) in &hwjson.structs {
+	if selector.matches(name, hwstruct.source()) {
+	    add_struct(hwjson, name, &mut result);
+	}
+    }
+    for (name, hwenum) in &hwjson.enums {
+	if selector.matches(name, hwenum.source()) {
+	    add_enum(hwjson, name, &mut result);
+	}
+    }
+    for (name, scalar) in &hwjson.scalars {
+	if selector.matches(name, scalar.source()) {
+	    result.scalars.insert(name.clone(), scalar.clone());
+	}
+    }
+    result
+}
+
+fn source_str(source: &Option<SourceInfo>) -> String {
+    match source {
+	Some(x) => { format!(", {}", x) }
+	None => { "".to_string() }
+    }
+}
+
+// negative values are shown as the bits of their width, there's no
+// hex form of a negative value of unknown width
+pub fn hex_value(value: i128, width: u32) -> Option<String> {
+    if value >= 0 {
+	Some(format!("{:#x}", value))
+    } else if width > 0 {
+	let mask = if width >= 128 { u128::MAX } else { (1u128 << width) - 1 };
+	Some(format!("{:#x}", value as u128 & mask))
+    } else {
+	None
+    }
+}
+
+fn print_define(out: &mut impl Write, name: &str, define: &HWDefine) -> std::io::Result<()> {
+    let body = match define.expr.as_str() {
+	"" => { define.vals.join(" ") }
+	x => { x.to_string() }
+    };
+    match define.value {
+	Some(x) => {
+	    let value = match hex_value(x, define.width) {
+		Some(hex) => { format!("{} {}", x, hex) }
+		None => { x.to_string() }
+	    };
+	    writeln!(out, "#define {} {}\t/* {}{} */", name, body, value, source_str(&define.source))
+	}
+	None => { writeln!(out, "#define {} {}\t/* not a constant{} */", name, body, source_str(&define.source)) }
+    }
+}
+
+pub fn field_type(fld: &HWStructField) -> &str {
+    if fld.enum_type != "" {
+	&fld.enum_type
+    } else {
+	&fld.val_type
+    }
+}
+
+// offsets and sizes in bytes, bitfields add the bit offset inside
+// their storage unit
+fn print_struct(out: &mut impl Write, name: &str, hwstruct: &HWStruct) -> std::io::Result<()> {
+    let kind = if hwstruct.is_union { "union" } else { "struct" };
+    if hwstruct.opaque {
+	return writeln!(out, "{} {};\t/* opaque{} */", kind, name, source_str(&hwstruct.source));
+    }
+    if hwstruct.alias_of != "" {
+	writeln!(out, "/* typedef of {} */", hwstruct.alias_of)?;
+    }
+    let mut attrs = format!("size {}", hwstruct.total_size / 8);
+    if hwstruct.alignment != 0 {
+	attrs += &format!(", align {}", hwstruct.alignment);
+    }
+    if hwstruct.packed {
+	attrs += ", packed";
+    }
+    writeln!(out, "{} {} {{\t/* {}{} */", kind, name, attrs, source_str(&hwstruct.source))?;
+    for fld in &hwstruct.fields {
+	let mut decl = format!("{} {}", field_type(fld), fld.name);
+	if fld.is_array() {
+	    decl += &format!("[{}]", fld.group_len);
+	}
+	if fld.bit_width > 0 {
+	    decl += &format!(":{}", fld.bit_width);
+	    writeln!(out, "    {};\t/* offset {} bit {}, size {} */", decl, fld.start / 8, fld.bit_offset, fld.size / 8)?;
+	} else {
+	    writeln!(out, "    {};\t/* offset {}, size {} */", decl, fld.start / 8, fld.total_size() / 8)?;
+	}
+    }
+    writeln!(out, "}};")
+}
+
+fn print_enum(out: &mut impl Write, name: &str, hwenum: &HWEnum) -> std::io::Result<()> {
+    if hwenum.alias_of != "" {
+	return match &hwenum.source {
+	    Some(x) => { writeln!(out, "typedef enum {} {};\t/* {} */", hwenum.alias_of, name, x) }
+	    None => { writeln!(out, "typedef enum {} {};", hwenum.alias_of, name) }
+	};
+    }
+    writeln!(out, "enum {} {{\t/* size {}{} */", name, hwenum.size / 8, source_str(&hwenum.source))?;
+    for member in &hwenum.members {
+	writeln!(out, "    {} = {},", member.name, member.value)?;
+    }
+    writeln!(out, "}};")
+}
+
+fn print_scalar(out: &mut impl Write, name: &str, scalar: &HWScalar) -> std::io::Result<()> {
+    if name == scalar.canonical {
+	writeln!(out, "/* builtin {}, size {} */", name, scalar.size / 8)
+    } else {
+	writeln!(out, "typedef {} {};\t/* size {}{} */", scalar.canonical, name, scalar.size / 8, source_str(&scalar.source))
+    }
+}
+
+pub fn print(out: &mut impl Write, result: &HWJson) -> std::io::Result<()> {
+    for (name, define) in &result.defines {
+	print_define(out, name, define)?;
+    }
+    for (name, scalar) in &result.scalars {
+	print_scalar(out, name, scalar)?;
+    }
+    for (name, hwenum) in &result.enums {
+	writeln!(out)?;
+	print_enum(out, name, hwenum)?;
+    }
+    for (name, hwstruct) in &result.structs {
+	writeln!(out)?;
+	print_struct(out, name, hwstruct)?;
+    }
+    Ok(())
+}
+
+pub fn cmd_query(path: &str, pattern: &str, json: bool) -> std::io::Result<()> {
+    let hwjson = HWJson::load(path)?;
+    let result = query(&hwjson, &Selector::parse(&[pattern.to_string()])?);
+    if result.defines.is_empty() && result.structs.is_empty() && result.enums.is_empty() && result.scalars.is_empty() {
+	return Err(Error::new(ErrorKind::NotFound, format!("{}: no symbol matches {}", path, pattern)));
+    }
+    let mut stdout = std::io::stdout().lock();
+    if json {
+	serde_json::to_writer_pretty(&mut stdout, &result)?;
+	writeln!(stdout)
+    } else {
+	print(&mut stdout, &result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_values() {
+	assert_eq!(hex_value(0x20, 32).as_deref(), Some("0x20"));
+	assert_eq!(hex_value(0x20, 0).as_deref(), Some("0x20"));
+	assert_eq!(hex_value(-1, 32).as_deref(), Some("0xffffffff"));
+	assert_eq!(hex_value(-2, 8).as_deref(), Some("0xfe"));
+	assert_eq!(hex_value(i64::MIN as i128, 64).as_deref(), Some("0x8000000000000000"));
+	assert_eq!(hex_value(-1, 128).as_deref(), Some(format!("{:#x}", u128::MAX).as_str()));
+	assert_eq!(hex_value(-1, 0), None);
+    }
+
+    #[test]
+    fn negative_defines() {
+	let define = HWDefine { vals: vec!["(-1)".to_string()], value: Some(-1), width: 32, signed: true, ..Default::default() };
+	let mut out: Vec<u8> = Default::default();
+	print_define(&mut out, "NEG", &define).unwrap();
+	assert_eq!(String::from_utf8(out).unwrap(), "#define NEG (-1)\t/* -1 0xffffffff */\n");
+
+	let mut out: Vec<u8> = Default::default();
+	print_define(&mut out, "NEG", &HWDefine { width: 0, ..define }).unwrap();
+	assert_eq!(String::from_utf8(out).unwrap(), "#define NEG (-1)\t/* -1 */\n");
+    }
+}
@@ -11,10 +11,9 @@ git remote update
 cd ..
 cd ..
 
+# headers are read from each tag in the object database, the clone's
+# working tree is left alone
 for i in `cat fw_list`
 do
-	cd gitrepo/open-gpu-kernel-modules
-	git checkout -f $i
-	cd ../..
-	cargo run --bin json -- $i $PWD/gitrepo/open-gpu-kernel-modules/ jsondb
+	cargo run --bin json -- --cache $PWD/.extract_cache --git-rev $i $i $PWD/gitrepo/open-gpu-kernel-modules/ jsondb
 done
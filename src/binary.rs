@@ -0,0 +1,186 @@
+// Compact binary encoding of the databases, bincode with an optional
+// zstd layer, much smaller and faster to load than the pretty printed
+// json. Files start with a small uncompressed header so any tool can
+// tell the formats apart and check the schema version:
+//
+//   "GSPDB\0", schema version (u32 le), kind ('C' or 'H'),
+//   compression (0 none, 1 zstd), then the bincode encoded database.
+//
+// bincode has no field names, so unlike json a binary file can only
+// be read by a build with the same schema version.
+
+use std::io::{Error, ErrorKind};
+
+use crate::schema::{CJson, Database, HWJson, SCHEMA_VERSION};
+
+const MAGIC: &[u8; 6] = b"GSPDB\0";
+const HEADER_LEN: usize = 12;
+
+// databases are written once and read many times
+const ZSTD_LEVEL: i32 = 19;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    Json,
+    Binary,
+    Zstd,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+	match name {
+	    "json" => { Some(Format::Json) }
+	    "bin" => { Some(Format::Binary) }
+	    "zst" => { Some(Format::Zstd) }
+	    _ => { None }
+	}
+    }
+
+    // .bin is binary, .zst compressed binary, anything else json
+    pub fn from_path(path: &str) -> Format {
+	if path.ends_with(".zst") {
+	    Format::Zstd
+	} else if path.ends_with(".bin") {
+	    Format::Binary
+	} else {
+	    Format::Json
+	}
+    }
+
+    pub fn extension(&self) -> &'static str {
+	match self {
+	    Format::Json => { "json" }
+	    Format::Binary => { "bin" }
+	    Format::Zstd => { "bin.zst" }
+	}
+    }
+}
+
+fn bincode_error(err: bincode::Error) -> Error {
+    Error::new(ErrorKind::InvalidData, err.to_string())
+}
+
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn encode(db: &Database, compress: bool) -> std::io::Result<Vec<u8>> {
+    let (kind, payload) = match db {
+	Database::C(x) => { (b'C', bincode::serialize(x).map_err(bincode_error)?) }
+	Database::HW(x) => { (b'H', bincode::serialize(x).map_err(bincode_error)?) }
+    };
+    let mut bytes: Vec<u8> = MAGIC.to_vec();
+    bytes.extend(SCHEMA_VERSION.to_le_bytes());
+    bytes.push(kind);
+    if compress {
+	bytes.push(1);
+	bytes.extend(zstd::encode_all(&payload[..], ZSTD_LEVEL)?);
+    } else {
+	bytes.push(0);
+	bytes.extend(payload);
+    }
+    Ok(bytes)
+}
+
+pub fn decode(path: &str, bytes: &[u8]) -> std::io::Result<Database> {
+    let invalid = |msg: String| Error::new(ErrorKind::InvalidData, format!("{}: {}", path, msg));
+    if bytes.len() < HEADER_LEN || !is_binary(bytes) {
+	return Err(invalid("not a binary database".to_string()));
+    }
+    let version = u32::from_le_bytes(bytes[6..10].try_into().unwrap());
+    if version != SCHEMA_VERSION {
+	return Err(invalid(format!("binary database schema version {} doesn't match the supported version {}, convert it with a matching jsondb or extract it again",
+				   version, SCHEMA_VERSION)));
+    }
+    let decompressed;
+    let payload = match bytes[11] {
+	0 => { &bytes[HEADER_LEN..] }
+	1 => {
+	    decompressed = zstd::decode_all(&bytes[HEADER_LEN..]).map_err(|e| invalid(e.to_string()))?;
+	    &decompressed[..]
+	}
+	x => { return Err(invalid(format!("unknown compression {}", x))); }
+    };
+    match bytes[10] {
+	b'C' => { Ok(Database::C(bincode::deserialize::<CJson>(payload).map_err(|e| invalid(e.to_string()))?)) }
+	b'H' => { Ok(Database::HW(bincode::deserialize::<HWJson>(payload).map_err(|e| invalid(e.to_string()))?)) }
+	x => { Err(invalid(format!("unknown database kind {}", x))) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::*;
+
+    fn hw_database() -> HWJson {
+	let mut hwjson = HWJson { version: "570.86.16".to_string(), ..Default::default() };
+	hwjson.defines.insert("NEG".to_string(), HWDefine { vals: vec!["(-1)".to_string()], value: Some(-1), width: 32, signed: true, ..Default::default() });
+	hwjson.structs.insert("S".to_string(), HWStruct {
+	    total_size: 64,
+	    fields: vec![HWStructField { name: "a".to_string(), start: 0, size: 32, group_len: NOT_ARRAY, isint: 1, val_type: "NvU32".to_string(), ..Default::default() }],
+	    ..Default::default()
+	});
+	hwjson
+    }
+
+    #[test]
+    fn round_trip() {
+	let hwjson = hw_database();
+	for compress in [false, true] {
+	    let bytes = encode(&Database::HW(hwjson.clone()), compress).unwrap();
+	    assert!(is_binary(&bytes));
+	    assert_eq!(bytes[11], compress as u8);
+	    match decode("test.hw.bin", &bytes).unwrap() {
+		Database::HW(x) => { assert_eq!(x, hwjson); }
+		Database::C(_) => { panic!("decoded a C database"); }
+	    }
+	}
+
+	let mut cjson = CJson { version: "570.86.16".to_string(), ..Default::default() };
+	cjson.types.insert("NAME".to_string(), CTypes { ctype: CType::Enum, alias_of: "tag".to_string(), ..Default::default() });
+	match decode("test.bin", &encode(&Database::C(cjson.clone()), true).unwrap()).unwrap() {
+	    Database::C(x) => { assert_eq!(x, cjson); }
+	    Database::HW(_) => { panic!("decoded a HW database"); }
+	}
+    }
+
+    #[test]
+    fn schema_version_must_match() {
+	let mut bytes = encode(&Database::HW(hw_database()), false).unwrap();
+	bytes[6..10].copy_from_slice(&(SCHEMA_VERSION - 1).to_le_bytes());
+	let err = decode("old.hw.bin", &bytes).err().unwrap();
+	assert_eq!(err.kind(), ErrorKind::InvalidData);
+	assert!(err.to_string().starts_with(&format!("old.hw.bin: binary database schema version {} doesn't match the supported version {}",
+						     SCHEMA_VERSION - 1, SCHEMA_VERSION)));
+    }
+
+    #[test]
+    fn bad_headers() {
+	let bytes = encode(&Database::HW(hw_database()), false).unwrap();
+	assert!(decode("x", b"{ \"version\": \"1\" }").is_err());
+	assert!(decode("x", &bytes[..HEADER_LEN - 1]).is_err());
+	let mut kind = bytes.clone();
+	kind[10] = b'X';
+	assert!(decode("x", &kind).err().unwrap().to_string().contains("unknown database kind"));
+	let mut compression = bytes.clone();
+	compression[11] = 2;
+	assert!(decode("x", &compression).err().unwrap().to_string().contains("unknown compression 2"));
+	assert!(decode("x", &bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn formats() {
+	assert_eq!(Format::from_name("json"), Some(Format::Json));
+	assert_eq!(Format::from_name("bin"), Some(Format::Binary));
+	assert_eq!(Format::from_name("zst"), Some(Format::Zstd));
+	assert_eq!(Format::from_name("bin.zst"), None);
+	assert_eq!(Format::from_path("jsondb/570.86.16.hw.bin.zst"), Format::Zstd);
+	assert_eq!(Format::from_path("jsondb/570.86.16.hw.bin"), Format::Binary);
+	assert_eq!(Format::from_path("jsondb/570.86.16.hw.json"), Format::Json);
+	assert_eq!(Format::from_path("out"), Format::Json);
+	for format in [Format::Json, Format::Binary, Format::Zstd] {
+	    assert_eq!(Format::from_path(&format!("x.{}", format.extension())), format);
+	}
+    }
+}
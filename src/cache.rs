@@ -0,0 +1,236 @@
+// Cache of per header extraction results.
+//
+// Entries are found by the header path and the clang arguments, and
+// are only used if every file the header pulled in still has the same
+// contents and every file an unresolved #include could have found is
+// still missing, so a new point release only re-parses the headers
+// that changed or include something that changed.  Entries also record a
+// hash of the json binary that wrote them, so a rebuilt extractor
+// never picks up results from an older one.
+
+use clang::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::HeaderResult;
+
+// bump when the extracted data changes shape or meaning
+const CACHE_FORMAT: u32 = 8;
+
+// for unique temporary entry names within the process
+static NEXT_TMP: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry<R> {
+    format: u32,
+    tool_build: String,
+    header: String,
+    args: Vec<String>,
+    // every file the header depended on and its sha256, None for
+    // files that didn't exist
+    deps: BTreeMap<PathBuf, Option<String>>,
+    result: R,
+}
+
+pub struct Cache {
+    dir: PathBuf,
+    // sha256 of the running binary, None if it couldn't be read
+    build: Option<String>,
+    // file hashes are shared by all workers, most headers include the
+    // same few dozen common headers.
+    hashes: Mutex<HashMap<PathBuf, Option<String>>>,
+}
+
+fn hash_bytes(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+// the files a header result depends on
+#[derive(Default)]
+pub struct HeaderDeps {
+    pub files: BTreeSet<PathBuf>,
+    // where unresolved includes were looked for, creating any of
+    // them changes the result
+    pub missing: BTreeSet<PathBuf>,
+}
+
+// the header itself, everything it included, and any file a top level
+// declaration came from, which catches -include'd headers. An include
+// clang couldn't find could be in the directory of the including file
+// or in any of the include_dirs.
+pub fn dependency_files<'a>(tu: &TranslationUnit<'a>, header: &str, include_dirs: &[PathBuf]) -> HeaderDeps {
+    let mut deps: HeaderDeps = Default::default();
+    deps.files.insert(PathBuf::from(header));
+
+    for child in tu.get_entity().get_children() {
+	let location = child.get_location().and_then(|l| l.get_file_location().file).map(|x| x.get_path());
+	if child.get_kind() == EntityKind::InclusionDirective {
+	    match (child.get_file(), child.get_display_name()) {
+		(Some(x), _) => { deps.files.insert(x.get_path()); }
+		(None, Some(name)) => {
+		    let including_dir = location.as_ref().and_then(|x| x.parent()).map(|x| x.to_path_buf());
+		    for dir in including_dir.iter().chain(include_dirs) {
+			deps.missing.insert(dir.join(&name));
+		    }
+		}
+		(None, None) => {}
+	    }
+	}
+	if let Some(x) = location {
+	    deps.files.insert(x);
+	}
+    }
+    deps
+}
+
+impl Cache {
+    pub fn new(dir: &str) -> std::io::Result<Cache> {
+	std::fs::create_dir_all(dir)?;
+	let build = std::env::current_exe().and_then(std::fs::read).ok().map(|x| hash_bytes(&x));
+	Ok(Cache { dir: PathBuf::from(dir), build, hashes: Default::default() })
+    }
+
+    // for files that aren't on disk, e.g. read from git
+    pub fn add_file(&self, path: &Path, contents: &[u8]) {
+	self.hashes.lock().unwrap().insert(path.to_path_buf(), Some(hash_bytes(contents)));
+    }
+
+    fn file_hash(&self, path: &Path) -> Option<String> {
+	if let Some(x) = self.hashes.lock().unwrap().get(path) {
+	    return x.clone();
+	}
+	let hash = std::fs::read(path).ok().map(|x| hash_bytes(&x));
+	self.hashes.lock().unwrap().insert(path.to_path_buf(), hash.clone());
+	hash
+    }
+
+    fn entry_path(&self, header: &str, args: &Vec<String>) -> PathBuf {
+	let mut key = header.to_string();
+	for arg in args {
+	    key += "\0";
+	    key += arg;
+	}
+	self.dir.join(hash_bytes(key.as_bytes()) + ".json")
+    }
+
+    pub fn lookup(&self, header: &str, args: &Vec<String>) -> Option<HeaderResult> {
+	let build = self.build.as_ref()?;
+	let file = File::open(self.entry_path(header, args)).ok()?;
+	let entry: CacheEntry<HeaderResult> = serde_json::from_reader(BufReader::new(file)).ok()?;
+
+	if entry.format != CACHE_FORMAT || entry.tool_build != *build ||
+	    entry.header != header || entry.args != *args {
+		return None;
+	    }
+	for (path, hash) in &entry.deps {
+	    if self.file_hash(path) != *hash {
+		return None;
+	    }
+	}
+	Some(entry.result)
+    }
+
+    pub fn store(&self, header: &str, args: &Vec<String>, deps: &HeaderDeps, result: &HeaderResult) -> std::io::Result<()> {
+	// without a build hash the entry could never be validated
+	let build = match &self.build {
+	    Some(x) => { x }
+	    None => { return Ok(()); }
+	};
+	let mut entry = CacheEntry {
+	    format: CACHE_FORMAT,
+	    tool_build: build.clone(),
+	    header: header.to_string(),
+	    args: args.clone(),
+	    deps: Default::default(),
+	    result,
+	};
+	for path in &deps.files {
+	    // a file we can't read can't be validated, don't cache
+	    let hash = match self.file_hash(path) {
+		Some(x) => { x }
+		None => { return Ok(()); }
+	    };
+	    entry.deps.insert(path.clone(), Some(hash));
+	}
+	for path in &deps.missing {
+	    entry.deps.insert(path.clone(), self.file_hash(path));
+	}
+
+	// write then rename so an interrupted run never leaves half an
+	// entry, the temporary name is unique to this process and store
+	// as runs sharing a cache can write the same entry
+	let path = self.entry_path(header, args);
+	let tmppath = path.with_extension(format!("{}.{}.tmp", std::process::id(), NEXT_TMP.fetch_add(1, Ordering::Relaxed)));
+	let file = File::create(&tmppath)?;
+	let mut writer = BufWriter::new(file);
+	serde_json::to_writer(&mut writer, &entry)?;
+	writer.flush()?;
+	std::fs::rename(tmppath, path)?;
+	Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch_tree;
+
+    fn result() -> HeaderResult {
+	HeaderResult { cjson: Default::default(), hwjson: Default::default(), report: Default::default() }
+    }
+
+    // a.h includes b.h and a missing c.h that could be in inc
+    fn store_entry(tree: &Path) -> (String, Vec<String>) {
+	let header = tree.join("a.h").to_string_lossy().to_string();
+	let args = vec!["-DX".to_string()];
+	let deps = HeaderDeps {
+	    files: [tree.join("a.h"), tree.join("b.h")].into_iter().collect(),
+	    missing: [tree.join("c.h"), tree.join("inc/c.h")].into_iter().collect(),
+	};
+	Cache::new(tree.join("cache").to_str().unwrap()).unwrap().store(&header, &args, &deps, &result()).unwrap();
+	(header, args)
+    }
+
+    // each run starts with an empty set of file hashes
+    fn lookup(tree: &Path, header: &str, args: &Vec<String>) -> bool {
+	Cache::new(tree.join("cache").to_str().unwrap()).unwrap().lookup(header, args).is_some()
+    }
+
+    #[test]
+    fn hit_until_a_dependency_changes() {
+	let tree = scratch_tree(&[("a.h", "#include \"b.h\"\n"), ("b.h", "#define B 1\n")]);
+	let (header, args) = store_entry(&tree);
+	assert!(lookup(&tree, &header, &args));
+	// nothing but the entry is left behind
+	assert_eq!(std::fs::read_dir(tree.join("cache")).unwrap().count(), 1);
+
+	std::fs::write(tree.join("b.h"), "#define B 2\n").unwrap();
+	assert!(!lookup(&tree, &header, &args));
+	std::fs::remove_dir_all(tree).unwrap();
+    }
+
+    #[test]
+    fn miss_for_other_arguments() {
+	let tree = scratch_tree(&[("a.h", ""), ("b.h", "")]);
+	let (header, args) = store_entry(&tree);
+	assert!(!lookup(&tree, &header, &vec!["-DY".to_string()]));
+	assert!(lookup(&tree, &header, &args));
+	std::fs::remove_dir_all(tree).unwrap();
+    }
+
+    #[test]
+    fn miss_once_a_missing_include_appears() {
+	let tree = scratch_tree(&[("a.h", ""), ("b.h", "")]);
+	let (header, args) = store_entry(&tree);
+	std::fs::create_dir_all(tree.join("inc")).unwrap();
+	std::fs::write(tree.join("inc/c.h"), "").unwrap();
+	assert!(!lookup(&tree, &header, &args));
+	std::fs::remove_dir_all(tree).unwrap();
+    }
+}
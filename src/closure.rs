@@ -0,0 +1,320 @@
+// The transitive closure of the symbols a want list asks for.
+//
+// A wanted struct is only usable if the structs and enums its fields
+// use are generated too, and a define written in terms of other
+// defines needs those. The generators add every wanted symbol to a
+// Closure, which hands back whatever hasn't been emitted yet in
+// dependency order and remembers what was pulled in without being
+// asked for so it can be reported.
+
+use std::collections::HashSet;
+use std::io::Write;
+
+use crate::schema::*;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum SymbolKind {
+    Define,
+    Enum,
+    Struct,
+    Typedef,
+}
+
+impl SymbolKind {
+    pub fn name(&self) -> &'static str {
+	match self {
+	    SymbolKind::Define => { "define" }
+	    SymbolKind::Enum => { "enum" }
+	    SymbolKind::Struct => { "struct" }
+	    SymbolKind::Typedef => { "typedef" }
+	}
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Symbol {
+    pub kind: SymbolKind,
+    pub name: String,
+}
+
+impl Symbol {
+    pub fn new(kind: SymbolKind, name: &str) -> Symbol {
+	Symbol { kind, name: name.to_string() }
+    }
+}
+
+// the symbols a symbol refers to directly
+pub trait Dependencies {
+    fn dependencies(&self, symbol: &Symbol) -> Vec<Symbol>;
+}
+
+// C identifiers in a type name or macro body
+fn identifiers(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+	.filter(|x| x.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_'))
+}
+
+impl Dependencies for HWJson {
+    // scalars map to builtin types in the generators, so only struct
+    // and enum typed fields count. Evaluated defines are emitted as
+    // their value and don't need the defines they were written with.
+    fn dependencies(&self, symbol: &Symbol) -> Vec<Symbol> {
+	let mut deps: Vec<Symbol> = Default::default();
+	match symbol.kind {
+	    SymbolKind::Struct => {
+		for fld in self.get_struct(&symbol.name).map(|x| &x.fields[..]).unwrap_or_default() {
+		    if fld.isint == 0 && self.get_struct(&fld.val_type).is_some() {
+			deps.push(Symbol::new(SymbolKind::Struct, &fld.val_type));
+		    }
+		    if self.get_enum(&fld.enum_type).is_some() {
+			deps.push(Symbol::new(SymbolKind::Enum, &fld.enum_type));
+		    }
+		}
+	    }
+	    SymbolKind::Define => {
+		// enum constants come with their enum
+		if let Some(define) = self.get_define(&symbol.name).filter(|x| self.get_enum(&x.enum_name).is_some()) {
+		    deps.push(Symbol::new(SymbolKind::Enum, &define.enum_name));
+		}
+		if let Some(define) = self.get_define(&symbol.name).filter(|x| x.value.is_none()) {
+		    for val in &define.vals {
+			deps.extend(identifiers(val).filter(|x| *x != symbol.name && self.get_define(x).is_some())
+				    .map(|x| Symbol::new(SymbolKind::Define, x)));
+		    }
+		}
+	    }
+	    // typedefs of an enum need the enum they name
+	    SymbolKind::Enum => {
+		if let Some(hwenum) = self.get_enum(&symbol.name).filter(|x| self.get_enum(&x.alias_of).is_some()) {
+		    deps.push(Symbol::new(SymbolKind::Enum, &hwenum.alias_of));
+		}
+	    }
+	    SymbolKind::Typedef => {}
+	}
+	deps
+    }
+}
+
+impl CJson {
+    // the kind of symbol a type is emitted as, the nvtypes.h scalars
+    // come from the headers the output is used with
+    fn symbol_of(&self, name: &str) -> Option<Symbol> {
+	if is_nvtypes_scalar(name) {
+	    return None;
+	}
+	let kind = match self.get_type(name)?.ctype {
+	    CType::Value => { SymbolKind::Define }
+	    CType::Struct => { SymbolKind::Struct }
+	    CType::Typedef => { SymbolKind::Typedef }
+	    CType::Enum => { SymbolKind::Enum }
+	    CType::Unknown => { return None; }
+	};
+	Some(Symbol::new(kind, name))
+    }
+}
+
+impl Dependencies for CJson {
+    fn dependencies(&self, symbol: &Symbol) -> Vec<Symbol> {
+	let ctype = match self.get_type(&symbol.name) {
+	    Some(x) => { x }
+	    None => { return vec![]; }
+	};
+	let texts: Vec<&String> = match symbol.kind {
+	    SymbolKind::Struct => { ctype.fields.iter().filter(|f| f.fldtype == FieldType::Member).map(|f| &f.ftype).collect() }
+	    SymbolKind::Typedef | SymbolKind::Define => { ctype.vals.iter().collect() }
+	    SymbolKind::Enum => { vec![] }
+	};
+	let mut deps: Vec<Symbol> = Default::default();
+	// enum constants come with their enum, enum typedefs need the
+	// enum they name
+	for name in [&ctype.enum_name, &ctype.alias_of] {
+	    if ctype.ctype != CType::Struct && self.get_type(name).map(|x| &x.ctype) == Some(&CType::Enum) {
+		deps.push(Symbol::new(SymbolKind::Enum, name));
+	    }
+	}
+	for text in texts {
+	    for name in identifiers(text) {
+		match self.symbol_of(name) {
+		    // defines only need other defines
+		    Some(x) if x.name != symbol.name && (symbol.kind != SymbolKind::Define || x.kind == SymbolKind::Define) => {
+			deps.push(x);
+		    }
+		    _ => {}
+		}
+	    }
+	}
+	deps
+    }
+}
+
+#[derive(Default)]
+pub struct Closure {
+    seen: HashSet<Symbol>,
+    // symbols nobody asked for and the symbol that needed them
+    pub implicit: Vec<(Symbol, Symbol)>,
+}
+
+impl Closure {
+    pub fn new() -> Closure {
+	Default::default()
+    }
+
+    // the symbol and everything it needs that wasn't added before,
+    // dependencies first
+    pub fn add(&mut self, db: &impl Dependencies, symbol: Symbol) -> Vec<Symbol> {
+	self.implicit.retain(|(x, _)| *x != symbol);
+	let mut order: Vec<Symbol> = Default::default();
+	self.visit(db, symbol, None, &mut order);
+	order
+    }
+
+    fn visit(&mut self, db: &impl Dependencies, symbol: Symbol, needed_by: Option<&Symbol>, order: &mut Vec<Symbol>) {
+	// marked before the dependencies so cycles through pointers end
+	if !self.seen.insert(symbol.clone()) {
+	    return;
+	}
+	for dep in db.dependencies(&symbol) {
+	    self.visit(db, dep, Some(&symbol), order);
+	}
+	if let Some(x) = needed_by {
+	    self.implicit.push((symbol.clone(), x.clone()));
+	}
+	order.push(symbol);
+    }
+
+    pub fn report(&self, out: &mut impl Write) -> std::io::Result<()> {
+	for (symbol, needed_by) in &self.implicit {
+	    writeln!(out, "pulled in {} {} for {} {}", symbol.kind.name(), symbol.name, needed_by.kind.name(), needed_by.name)?;
+	}
+	Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hw_enum(alias_of: &str, members: &[&str]) -> HWEnum {
+	HWEnum {
+	    size: 32,
+	    members: members.iter().map(|x| HWEnumMember { name: x.to_string(), value: 0 }).collect(),
+	    alias_of: alias_of.to_string(),
+	    ..Default::default()
+	}
+    }
+
+    // typedef enum tag { A } NAME;
+    fn typedef_enum() -> HWJson {
+	let mut hwjson: HWJson = Default::default();
+	hwjson.enums.insert("tag".to_string(), hw_enum("", &["A"]));
+	hwjson.enums.insert("NAME".to_string(), hw_enum("tag", &[]));
+	hwjson.defines.insert("A".to_string(), HWDefine { enum_name: "tag".to_string(), value: Some(0), ..Default::default() });
+	hwjson
+    }
+
+    fn names(symbols: Vec<Symbol>) -> Vec<String> {
+	symbols.into_iter().map(|x| format!("{} {}", x.kind.name(), x.name)).collect()
+    }
+
+    #[test]
+    fn enum_constants_bring_their_enum() {
+	let hwjson = typedef_enum();
+	let mut closure = Closure::new();
+	assert_eq!(names(closure.add(&hwjson, Symbol::new(SymbolKind::Define, "A"))), ["enum tag", "define A"]);
+	assert_eq!(names(closure.add(&hwjson, Symbol::new(SymbolKind::Enum, "tag"))), Vec::<String>::new());
+    }
+
+    #[test]
+    fn enum_typedefs_need_the_enum() {
+	let hwjson = typedef_enum();
+	let mut closure = Closure::new();
+	assert_eq!(names(closure.add(&hwjson, Symbol::new(SymbolKind::Enum, "NAME"))), ["enum tag", "enum NAME"]);
+	assert_eq!(closure.implicit, [(Symbol::new(SymbolKind::Enum, "tag"), Symbol::new(SymbolKind::Enum, "NAME"))]);
+    }
+
+    #[test]
+    fn c_enum_constants_and_typedefs() {
+	let mut cjson: CJson = Default::default();
+	cjson.types.insert("tag".to_string(), CTypes { ctype: CType::Enum, ..Default::default() });
+	cjson.types.insert("NAME".to_string(), CTypes { ctype: CType::Enum, alias_of: "tag".to_string(), ..Default::default() });
+	cjson.types.insert("A".to_string(), CTypes { ctype: CType::Value, vals: vec!["0".to_string()], enum_name: "tag".to_string(), ..Default::default() });
+	let mut closure = Closure::new();
+	assert_eq!(names(closure.add(&cjson, Symbol::new(SymbolKind::Define, "A"))), ["enum tag", "define A"]);
+	assert_eq!(names(closure.add(&cjson, Symbol::new(SymbolKind::Enum, "NAME"))), ["enum NAME"]);
+    }
+
+    fn hw_field(name: &str, val_type: &str, isint: u32, enum_type: &str) -> HWStructField {
+	HWStructField { name: name.to_string(), size: 32, group_len: NOT_ARRAY, isint, val_type: val_type.to_string(), enum_type: enum_type.to_string(), ..Default::default() }
+    }
+
+    // OUTER { INNER inner; E e; NvU32 x; }, INNER { NODE *node; },
+    // NODE { NODE *next; INNER *inner; }
+    fn nested_structs() -> HWJson {
+	let mut hwjson: HWJson = Default::default();
+	let hw_struct = |fields: Vec<HWStructField>| HWStruct { total_size: 64, fields, ..Default::default() };
+	hwjson.structs.insert("OUTER".to_string(), hw_struct(vec![hw_field("inner", "INNER", 0, ""), hw_field("e", "NvU32", 1, "E"), hw_field("x", "NvU32", 1, "")]));
+	hwjson.structs.insert("INNER".to_string(), hw_struct(vec![hw_field("node", "NODE", 0, "")]));
+	hwjson.structs.insert("NODE".to_string(), hw_struct(vec![hw_field("next", "NODE", 0, ""), hw_field("inner", "INNER", 0, "")]));
+	hwjson.enums.insert("E".to_string(), hw_enum("", &["E_A"]));
+	hwjson
+    }
+
+    #[test]
+    fn struct_fields_are_pulled_in_first() {
+	let hwjson = nested_structs();
+	let mut closure = Closure::new();
+	assert_eq!(names(closure.add(&hwjson, Symbol::new(SymbolKind::Struct, "OUTER"))), ["struct NODE", "struct INNER", "enum E", "struct OUTER"]);
+	// already emitted
+	assert_eq!(names(closure.add(&hwjson, Symbol::new(SymbolKind::Struct, "INNER"))), Vec::<String>::new());
+	assert_eq!(names(closure.add(&hwjson, Symbol::new(SymbolKind::Struct, "OUTER"))), Vec::<String>::new());
+    }
+
+    #[test]
+    fn pulled_in_symbols_are_reported_once_unless_wanted() {
+	let hwjson = nested_structs();
+	let mut closure = Closure::new();
+	closure.add(&hwjson, Symbol::new(SymbolKind::Struct, "OUTER"));
+	// asked for after it was pulled in
+	closure.add(&hwjson, Symbol::new(SymbolKind::Enum, "E"));
+	let mut out: Vec<u8> = Default::default();
+	closure.report(&mut out).unwrap();
+	assert_eq!(String::from_utf8(out).unwrap(), "pulled in struct NODE for struct INNER\n\
+						     pulled in struct INNER for struct OUTER\n");
+    }
+
+    #[test]
+    fn define_dependencies() {
+	let mut hwjson: HWJson = Default::default();
+	let define = |vals: &[&str], value: Option<i128>| HWDefine { vals: vals.iter().map(|x| x.to_string()).collect(), value, ..Default::default() };
+	hwjson.defines.insert("BASE".to_string(), define(&["0x100"], Some(0x100)));
+	hwjson.defines.insert("REG".to_string(), define(&["(BASE + 4)"], Some(0x104)));
+	hwjson.defines.insert("FIELD".to_string(), define(&["31:0"], None));
+	hwjson.defines.insert("REG_FIELD".to_string(), define(&["BASE", "FIELD", "FIELD", "OTHER(x)"], None));
+	hwjson.defines.insert("SELF".to_string(), define(&["SELF"], None));
+	let mut closure = Closure::new();
+	// evaluated defines are emitted as their value
+	assert_eq!(names(closure.add(&hwjson, Symbol::new(SymbolKind::Define, "REG"))), ["define REG"]);
+	assert_eq!(names(closure.add(&hwjson, Symbol::new(SymbolKind::Define, "REG_FIELD"))), ["define BASE", "define FIELD", "define REG_FIELD"]);
+	assert_eq!(names(closure.add(&hwjson, Symbol::new(SymbolKind::Define, "SELF"))), ["define SELF"]);
+	// not in the database
+	assert_eq!(names(closure.add(&hwjson, Symbol::new(SymbolKind::Define, "MISSING"))), ["define MISSING"]);
+    }
+
+    #[test]
+    fn c_structs_and_typedefs() {
+	let member = |ftype: &str, name: &str| CStructField {
+	    fldtype: FieldType::Member, ftype: ftype.to_string(), name: name.to_string(), is_array: false,
+	    size: 32, is_aligned: false, alignment: 0, bit_width: 0, anon_name: "".to_string(),
+	};
+	let mut cjson: CJson = Default::default();
+	cjson.types.insert("HANDLE".to_string(), CTypes { ctype: CType::Typedef, vals: vec!["NvU32".to_string()], ..Default::default() });
+	cjson.types.insert("NvU32".to_string(), CTypes { ctype: CType::Typedef, vals: vec!["unsigned int".to_string()], ..Default::default() });
+	cjson.types.insert("LIST".to_string(), CTypes { ctype: CType::Struct, fields: vec![
+	    member("HANDLE", "handle"), member("NvU32", "count"), member("struct LIST *", "next"),
+	], ..Default::default() });
+	let mut closure = Closure::new();
+	// the nvtypes.h scalars come from elsewhere
+	assert_eq!(names(closure.add(&cjson, Symbol::new(SymbolKind::Struct, "LIST"))), ["typedef HANDLE", "struct LIST"]);
+	assert_eq!(closure.implicit, [(Symbol::new(SymbolKind::Typedef, "HANDLE"), Symbol::new(SymbolKind::Struct, "LIST"))]);
+    }
+}
@@ -0,0 +1,301 @@
+// Extraction settings, the directories to scan, include paths, forced
+// includes and defines handed to clang.
+//
+// These live in a json config file (extract_config.json by default)
+// with optional per firmware version overrides, and can be extended
+// from the command line.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use gsp_parse::Format;
+
+use crate::source;
+
+const DEFAULT_CONFIG_FILE: &'static str = "extract_config.json";
+
+// used when there is no config file in the current directory
+const BUILTIN_CONFIG: &'static str = include_str!("../extract_config.json");
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct ExtractSettings {
+    // directories under the tree to walk for headers
+    #[serde(default)]
+    pub scan_paths: Vec<String>,
+    // -I paths, relative to the tree
+    #[serde(default)]
+    pub include_paths: Vec<String>,
+    // headers passed with -include
+    #[serde(default)]
+    pub include_files: Vec<String>,
+    // NAME or NAME=VALUE, without the -D
+    #[serde(default)]
+    pub defines: Vec<String>,
+}
+
+// A per version override adds to the base settings, remove drops
+// entries (from any of the lists) that no longer apply, e.g. when
+// a header moved directory between releases.
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct VersionOverride {
+    #[serde(flatten)]
+    add: ExtractSettings,
+    #[serde(default)]
+    remove: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct ConfigFile {
+    #[serde(flatten)]
+    base: ExtractSettings,
+    // keyed on a version or a dotted version prefix, "535" matches
+    // every 535.x release, "535.113.01" just that one.
+    #[serde(default)]
+    versions: BTreeMap<String, VersionOverride>,
+}
+
+pub struct Options {
+    pub version: String,
+    pub tree: String,
+    pub outdir: String,
+    pub settings: ExtractSettings,
+    // fail if any scanned header has clang errors
+    pub strict: bool,
+    // number of threads looking headers up in the cache ahead of
+    // the parser
+    pub jobs: usize,
+    // directory for per header results reused between runs
+    pub cache: Option<String>,
+    // read the headers from this revision of the git repository in
+    // tree instead of from the files in it
+    pub git_rev: Option<String>,
+    // of the written databases
+    pub format: Format,
+}
+
+fn version_matches(key: &str, version: &str) -> bool {
+    version == key || version.starts_with(&(key.to_owned() + "."))
+}
+
+impl ExtractSettings {
+    fn extend(&mut self, other: &ExtractSettings) {
+	self.scan_paths.extend(other.scan_paths.iter().cloned());
+	self.include_paths.extend(other.include_paths.iter().cloned());
+	self.include_files.extend(other.include_files.iter().cloned());
+	self.defines.extend(other.defines.iter().cloned());
+    }
+
+    fn remove(&mut self, entries: &Vec<String>) {
+	self.scan_paths.retain(|x| !entries.contains(x));
+	self.include_paths.retain(|x| !entries.contains(x));
+	self.include_files.retain(|x| !entries.contains(x));
+	self.defines.retain(|x| !entries.contains(x));
+    }
+
+    // the clang arguments for parsing a header from the tree
+    pub fn clang_args(&self, tree: &str) -> Vec<String> {
+	let mut args : Vec<String> = Default::default();
+
+	for define in &self.defines {
+	    args.push("-D".to_string() + define);
+	}
+
+	for incpath in &self.include_paths {
+	    args.push("-I".to_string() + &Path::new(tree).join(incpath).to_string_lossy());
+	}
+
+	for incfile in &self.include_files {
+	    args.push("-include".to_string());
+	    args.push(incfile.to_string());
+	}
+	args
+    }
+}
+
+fn load_config(path: Option<&String>) -> std::io::Result<ConfigFile> {
+    let contents = match path {
+	Some(x) => { std::fs::read_to_string(x)? }
+	None => {
+	    match std::fs::read_to_string(DEFAULT_CONFIG_FILE) {
+		Ok(x) => { x }
+		Err(_) => { BUILTIN_CONFIG.to_string() }
+	    }
+	}
+    };
+    Ok(serde_json::from_str(&contents)?)
+}
+
+// settings for a version, overrides are applied least specific first
+// so "535.113.01" wins over "535".
+fn settings_for_version(config: &ConfigFile, version: &str) -> ExtractSettings {
+    let mut settings = config.base.clone();
+    let mut overrides: Vec<(&String, &VersionOverride)> = config.versions.iter()
+	.filter(|(key, _)| version_matches(key, version))
+	.collect();
+    overrides.sort_by_key(|(key, _)| key.split('.').count());
+
+    for (_, over) in overrides {
+	settings.remove(&over.remove);
+	settings.extend(&over.add);
+    }
+    settings
+}
+
+fn usage(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput,
+	       format!("{}\nusage: json [--strict] [-j jobs] [--cache dir] [--git-rev rev] [--format json|bin|zst] [--config file] [-I path] [-D define] [--include header] [--path dir] [version] <tree> <outdir>", msg))
+}
+
+// options that take a value accept both "-I path" and "-Ipath"
+fn option_value(arg: &str, flag: &str, iter: &mut impl Iterator<Item = String>) -> std::io::Result<Option<String>> {
+    if arg == flag {
+	return match iter.next() {
+	    Some(x) => { Ok(Some(x)) }
+	    None => { Err(usage(&format!("{} needs a value", flag))) }
+	};
+    }
+    if flag.len() == 2 && arg.starts_with(flag) {
+	return Ok(Some(arg[2..].to_string()));
+    }
+    if let Some(x) = arg.strip_prefix(&(flag.to_owned() + "=")) {
+	return Ok(Some(x.to_string()));
+    }
+    Ok(None)
+}
+
+pub fn parse_args(args: impl Iterator<Item = String>) -> std::io::Result<Options> {
+    let mut config_path: Option<String> = None;
+    let mut extra: ExtractSettings = Default::default();
+    let mut positional: Vec<String> = Default::default();
+    let mut strict = false;
+    let mut cache: Option<String> = None;
+    let mut git_rev: Option<String> = None;
+    let mut format = Format::Json;
+    let mut jobs = match std::thread::available_parallelism() {
+	Ok(x) => { x.get() }
+	Err(_) => { 1 }
+    };
+
+    let mut iter = args.skip(1);
+    while let Some(arg) = iter.next() {
+	if arg == "--strict" {
+	    strict = true;
+	} else if let Some(x) = option_value(&arg, "-j", &mut iter)? {
+	    jobs = match x.parse::<usize>() {
+		Ok(x) if x > 0 => { x }
+		_ => { return Err(usage(&format!("bad job count {}", x))); }
+	    };
+	} else if let Some(x) = option_value(&arg, "--cache", &mut iter)? {
+	    cache = Some(x);
+	} else if let Some(x) = option_value(&arg, "--git-rev", &mut iter)? {
+	    git_rev = Some(x);
+	} else if let Some(x) = option_value(&arg, "--format", &mut iter)? {
+	    format = match Format::from_name(&x) {
+		Some(x) => { x }
+		None => { return Err(usage(&format!("unknown format {}", x))); }
+	    };
+	} else if let Some(x) = option_value(&arg, "--config", &mut iter)? {
+	    config_path = Some(x);
+	} else if let Some(x) = option_value(&arg, "-I", &mut iter)? {
+	    extra.include_paths.push(x);
+	} else if let Some(x) = option_value(&arg, "-D", &mut iter)? {
+	    extra.defines.push(x);
+	} else if let Some(x) = option_value(&arg, "--include", &mut iter)? {
+	    extra.include_files.push(x);
+	} else if let Some(x) = option_value(&arg, "--path", &mut iter)? {
+	    extra.scan_paths.push(x);
+	} else if arg.starts_with("-") {
+	    return Err(usage(&format!("unknown option {}", arg)));
+	} else {
+	    positional.push(arg);
+	}
+    }
+
+    // without a version it comes from the tree's version.mk
+    if positional.len() == 2 {
+	positional.insert(0, source::tree_version(&positional[0], git_rev.as_ref())?);
+    }
+    if positional.len() != 3 {
+	return Err(usage("expected version, tree and output directory"));
+    }
+
+    let config = load_config(config_path.as_ref())?;
+    let mut settings = settings_for_version(&config, &positional[0]);
+    settings.extend(&extra);
+
+    Ok(Options {
+	version: positional[0].clone(),
+	tree: positional[1].clone(),
+	outdir: positional[2].clone(),
+	settings,
+	strict,
+	jobs,
+	cache,
+	git_rev,
+	format,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(json: &str) -> ConfigFile {
+	serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn version_prefixes() {
+	assert!(version_matches("535", "535.113.01"));
+	assert!(version_matches("535.113.01", "535.113.01"));
+	assert!(!version_matches("53", "535.113.01"));
+	assert!(!version_matches("535.113", "535.11.01"));
+    }
+
+    #[test]
+    fn overrides_apply_least_specific_first() {
+	let config = config(r#"{
+	    "scan_paths": ["src/common/sdk"],
+	    "defines": ["BASE"],
+	    "versions": {
+		"535.113.01": { "remove": ["FROM_535"], "defines": ["EXACT"] },
+		"535": { "remove": ["BASE"], "defines": ["FROM_535"], "include_paths": ["inc"] },
+		"570": { "defines": ["OTHER"] }
+	    }
+	}"#);
+	let settings = settings_for_version(&config, "535.113.01");
+	assert_eq!(settings.scan_paths, ["src/common/sdk"]);
+	assert_eq!(settings.include_paths, ["inc"]);
+	assert_eq!(settings.defines, ["EXACT"]);
+
+	let settings = settings_for_version(&config, "535.54.03");
+	assert_eq!(settings.defines, ["FROM_535"]);
+
+	let settings = settings_for_version(&config, "550.40.07");
+	assert_eq!(settings.defines, ["BASE"]);
+	assert!(settings.include_paths.is_empty());
+    }
+
+    #[test]
+    fn clang_args_are_relative_to_the_tree() {
+	let settings = ExtractSettings {
+	    include_paths: vec!["src/common/inc".to_string()],
+	    include_files: vec!["nvtypes.h".to_string()],
+	    defines: vec!["NVRM".to_string(), "X=1".to_string()],
+	    ..Default::default()
+	};
+	assert_eq!(settings.clang_args("/tree"), ["-DNVRM", "-DX=1", "-I/tree/src/common/inc", "-include", "nvtypes.h"]);
+    }
+
+    #[test]
+    fn option_values() {
+	let mut rest = vec!["value".to_string()].into_iter();
+	assert_eq!(option_value("-I", "-I", &mut rest).unwrap(), Some("value".to_string()));
+	assert_eq!(option_value("-Ipath", "-I", &mut rest).unwrap(), Some("path".to_string()));
+	assert_eq!(option_value("--cache=dir", "--cache", &mut rest).unwrap(), Some("dir".to_string()));
+	assert_eq!(option_value("--cachedir", "--cache", &mut rest).unwrap(), None);
+	assert!(option_value("-j", "-j", &mut rest).is_err());
+    }
+}
@@ -0,0 +1,13 @@
+// Library side of gsp-parse, the jsondb schema shared by the
+// extractor, the generators and any other tool reading the databases,
+// stored as json or in the compact binary format.
+
+pub mod binary;
+pub mod closure;
+pub mod schema;
+pub mod selector;
+
+pub use binary::Format;
+pub use closure::{Closure, Symbol, SymbolKind};
+pub use schema::*;
+pub use selector::{HasSource, Selection, Selector, WantSelectors};
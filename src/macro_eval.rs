@@ -0,0 +1,616 @@
+// Evaluate object-like macro definitions to integer values.
+//
+// Macros are expanded recursively, function-like ones by substituting
+// their arguments, and the result is evaluated as a C constant
+// expression, tracking the width and signedness the C type rules
+// would give it (LP64, so long is 64 bits).
+
+use std::collections::BTreeMap;
+
+// fallbacks for the NVIDIA bit helpers when a header uses them
+// without including nvmisc.h, definitions in the headers win.
+const BUILTIN_MACROS: &'static [(&'static str, &'static str)] = &[
+    ("NVBIT", "( 1U << ( b ) )"),
+    ("NVBIT32", "( ( NvU32 ) 1U << ( b ) )"),
+    ("NVBIT64", "( ( NvU64 ) 1ULL << ( b ) )"),
+    ("BIT", "( 1U << ( b ) )"),
+    ("BIT32", "( ( NvU32 ) 1U << ( b ) )"),
+    ("BIT64", "( ( NvU64 ) 1ULL << ( b ) )"),
+];
+
+// typedef names used in casts, width in bits and signedness
+const CAST_TYPES: &'static [(&'static str, u32, bool)] = &[
+    ("NvU8", 8, false),
+    ("NvU16", 16, false),
+    ("NvU32", 32, false),
+    ("NvU64", 64, false),
+    ("NvS8", 8, true),
+    ("NvS16", 16, true),
+    ("NvS32", 32, true),
+    ("NvS64", 64, true),
+    ("NvV8", 8, false),
+    ("NvV16", 16, false),
+    ("NvV32", 32, false),
+    ("NvV64", 64, false),
+    ("NvBool", 8, false),
+    ("NvHandle", 32, false),
+    ("NvLength", 64, false),
+    ("NvUPtr", 64, false),
+    ("NvSPtr", 64, true),
+    ("NvP64", 64, false),
+    ("uint8_t", 8, false),
+    ("uint16_t", 16, false),
+    ("uint32_t", 32, false),
+    ("uint64_t", 64, false),
+    ("int8_t", 8, true),
+    ("int16_t", 16, true),
+    ("int32_t", 32, true),
+    ("int64_t", 64, true),
+    ("size_t", 64, false),
+];
+
+const C_TYPE_WORDS: &'static [&'static str] = &[
+    "unsigned", "signed", "char", "short", "int", "long", "const", "volatile",
+];
+
+// stop runaway expansion of badly formed macros
+const MAX_DEPTH: u32 = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MacroValue {
+    pub value: i128,
+    pub width: u32,
+    pub signed: bool,
+}
+
+impl MacroValue {
+    fn new(value: i128, width: u32, signed: bool) -> MacroValue {
+	let mask = (1i128 << width) - 1;
+	let mut value = value & mask;
+	if signed && value & (1i128 << (width - 1)) != 0 {
+	    value -= 1i128 << width;
+	}
+	MacroValue { value, width, signed }
+    }
+
+    fn int(value: i128) -> MacroValue {
+	MacroValue::new(value, 32, true)
+    }
+
+    // C integer promotion, anything smaller than int becomes int
+    fn promote(self) -> MacroValue {
+	if self.width < 32 {
+	    return MacroValue::int(self.value);
+	}
+	self
+    }
+
+    // the C literal for the value, with a suffix giving its type
+    pub fn to_literal(&self) -> String {
+	match (self.signed, self.width) {
+	    (true, 64) => format!("{}LL", self.value),
+	    (true, _) => format!("{}", self.value),
+	    (false, 64) => format!("{:#x}ULL", self.value),
+	    (false, _) => format!("{:#x}U", self.value),
+	}
+    }
+}
+
+// the usual arithmetic conversions between two operands
+fn common_type(a: MacroValue, b: MacroValue) -> (u32, bool) {
+    let a = a.promote();
+    let b = b.promote();
+    if a.signed == b.signed {
+	return (std::cmp::max(a.width, b.width), a.signed);
+    }
+    let (s, u) = if a.signed { (a, b) } else { (b, a) };
+    if u.width >= s.width {
+	(u.width, false)
+    } else {
+	(s.width, true)
+    }
+}
+
+struct MacroDef {
+    params: Option<Vec<String>>,
+    body: Vec<String>,
+}
+
+pub struct MacroTable {
+    macros: BTreeMap<String, MacroDef>,
+}
+
+impl MacroTable {
+    pub fn new() -> MacroTable {
+	let mut table = MacroTable { macros: Default::default() };
+	for (name, body) in BUILTIN_MACROS {
+	    table.add(name.to_string(),
+		      Some(vec!("b".to_string())),
+		      body.split(' ').map(|t| t.to_string()).collect());
+	}
+	table
+    }
+
+    // params is None for object-like macros
+    pub fn add(&mut self, name: String, params: Option<Vec<String>>, body: Vec<String>) {
+	self.macros.insert(name, MacroDef { params, body });
+    }
+
+    pub fn get_body(&self, name: &str) -> Option<&Vec<String>> {
+	self.macros.get(name).map(|def| &def.body)
+    }
+
+    // evaluate an object-like macro by name
+    pub fn evaluate(&self, name: &str) -> Option<MacroValue> {
+	let def = self.macros.get(name)?;
+	if def.params.is_some() {
+	    return None;
+	}
+	let mut active = vec!(name.to_string());
+	let tokens = self.expand(&def.body, &mut active, 0)?;
+	evaluate_tokens(&tokens)
+    }
+
+    fn expand(&self, tokens: &[String], active: &mut Vec<String>, depth: u32) -> Option<Vec<String>> {
+	if depth > MAX_DEPTH {
+	    return None;
+	}
+	let mut out: Vec<String> = Default::default();
+	let mut idx = 0;
+	while idx < tokens.len() {
+	    let tok = &tokens[idx];
+	    idx += 1;
+	    let def = match self.macros.get(tok) {
+		Some(def) if !active.contains(tok) => def,
+		_ => {
+		    out.push(tok.clone());
+		    continue;
+		}
+	    };
+	    // stringizing and pasting never produce values we can use
+	    if def.body.iter().any(|t| t == "#" || t == "##") {
+		return None;
+	    }
+	    let body = match &def.params {
+		None => def.body.clone(),
+		Some(params) => {
+		    // a function-like macro name without arguments isn't a call
+		    if idx >= tokens.len() || tokens[idx] != "(" {
+			out.push(tok.clone());
+			continue;
+		    }
+		    let (args, next) = split_args(tokens, idx)?;
+		    idx = next;
+		    if args.len() != params.len() && !(params.len() == 0 && args.len() == 1 && args[0].len() == 0) {
+			return None;
+		    }
+		    let mut expanded_args: Vec<Vec<String>> = Default::default();
+		    for arg in args {
+			expanded_args.push(self.expand(&arg, active, depth + 1)?);
+		    }
+		    let mut body: Vec<String> = Default::default();
+		    for t in &def.body {
+			match params.iter().position(|p| p == t) {
+			    Some(pidx) => body.extend(expanded_args[pidx].iter().cloned()),
+			    None => body.push(t.clone()),
+			}
+		    }
+		    body
+		}
+	    };
+	    active.push(tok.clone());
+	    let expanded = self.expand(&body, active, depth + 1);
+	    active.pop();
+	    out.extend(expanded?);
+	}
+	Some(out)
+    }
+}
+
+// split the arguments of a macro call, start points at the opening
+// paren, returns the arguments and the index after the closing paren.
+fn split_args(tokens: &[String], start: usize) -> Option<(Vec<Vec<String>>, usize)> {
+    let mut args: Vec<Vec<String>> = vec!(Default::default());
+    let mut depth = 0;
+    for (idx, tok) in tokens.iter().enumerate().skip(start) {
+	match tok.as_str() {
+	    "(" => {
+		depth += 1;
+		if depth == 1 {
+		    continue;
+		}
+	    }
+	    ")" => {
+		depth -= 1;
+		if depth == 0 {
+		    return Some((args, idx + 1));
+		}
+	    }
+	    "," if depth == 1 => {
+		args.push(Default::default());
+		continue;
+	    }
+	    _ => {}
+	}
+	args.last_mut().unwrap().push(tok.clone());
+    }
+    None
+}
+
+// join macro body tokens back into readable expression text
+pub fn expr_text(tokens: &[String]) -> String {
+    let mut text = String::new();
+    for (idx, tok) in tokens.iter().enumerate() {
+	if idx > 0 && tokens[idx - 1] != "(" && tok != ")" && tok != "," {
+	    text += " ";
+	}
+	text += tok;
+    }
+    text
+}
+
+// parse a C integer or character literal and work out its type
+pub fn parse_literal(tok: &str) -> Option<MacroValue> {
+    if tok.starts_with('\'') {
+	return parse_char_literal(tok);
+    }
+    let lower = tok.to_ascii_lowercase();
+    let digits = lower.trim_end_matches(|c| c == 'u' || c == 'l');
+    let suffix = &lower[digits.len()..];
+    let is_unsigned = suffix.contains('u');
+    let is_long = suffix.contains('l');
+
+    let (radix, digits) = if digits.starts_with("0x") {
+	(16, &digits[2..])
+    } else if digits.starts_with("0b") {
+	(2, &digits[2..])
+    } else if digits.len() > 1 && digits.starts_with('0') {
+	(8, &digits[1..])
+    } else {
+	(10, digits)
+    };
+    let value = u64::from_str_radix(digits, radix).ok()? as i128;
+
+    // the first type from the C list for this literal that fits
+    let candidates: &[(u32, bool)] = match (is_unsigned, is_long, radix == 10) {
+	(true, false, _) => &[(32, false), (64, false)],
+	(true, true, _) => &[(64, false)],
+	(false, false, true) => &[(32, true), (64, true), (64, false)],
+	(false, false, false) => &[(32, true), (32, false), (64, true), (64, false)],
+	(false, true, _) => &[(64, true), (64, false)],
+    };
+    for (width, signed) in candidates {
+	let max = if *signed { (1i128 << (width - 1)) - 1 } else { (1i128 << width) - 1 };
+	if value <= max {
+	    return Some(MacroValue::new(value, *width, *signed));
+	}
+    }
+    None
+}
+
+fn parse_char_literal(tok: &str) -> Option<MacroValue> {
+    let inner = tok.strip_prefix('\'')?.strip_suffix('\'')?;
+    let value = match inner.strip_prefix('\\') {
+	None => {
+	    let mut chars = inner.chars();
+	    let c = chars.next()?;
+	    if chars.next().is_some() {
+		return None;
+	    }
+	    c as i128
+	}
+	Some("n") => 10,
+	Some("t") => 9,
+	Some("r") => 13,
+	Some("\\") => 92,
+	Some("'") => 39,
+	Some(esc) if esc.starts_with('x') => i128::from_str_radix(&esc[1..], 16).ok()?,
+	Some(esc) => i128::from_str_radix(esc, 8).ok()?,
+    };
+    Some(MacroValue::int(value))
+}
+
+// evaluate a fully expanded token list
+pub fn evaluate_tokens(tokens: &[String]) -> Option<MacroValue> {
+    let mut eval = Evaluator { tokens, pos: 0 };
+    let val = eval.ternary()?;
+    if eval.pos != tokens.len() {
+	return None;
+    }
+    Some(val)
+}
+
+struct Evaluator<'a> {
+    tokens: &'a [String],
+    pos: usize,
+}
+
+fn binary_precedence(op: &str) -> Option<u32> {
+    match op {
+	"||" => Some(1),
+	"&&" => Some(2),
+	"|" => Some(3),
+	"^" => Some(4),
+	"&" => Some(5),
+	"==" | "!=" => Some(6),
+	"<" | ">" | "<=" | ">=" => Some(7),
+	"<<" | ">>" => Some(8),
+	"+" | "-" => Some(9),
+	"*" | "/" | "%" => Some(10),
+	_ => None,
+    }
+}
+
+fn apply_binary(op: &str, a: MacroValue, b: MacroValue) -> Option<MacroValue> {
+    let truth = |v: bool| Some(MacroValue::int(v as i128));
+    match op {
+	"||" => return truth(a.value != 0 || b.value != 0),
+	"&&" => return truth(a.value != 0 && b.value != 0),
+	"<<" | ">>" => {
+	    let a = a.promote();
+	    if b.value < 0 || b.value >= a.width as i128 {
+		return None;
+	    }
+	    let shift = b.value as u32;
+	    return match op {
+		"<<" => Some(MacroValue::new(a.value << shift, a.width, a.signed)),
+		_ => Some(MacroValue::new(a.value >> shift, a.width, a.signed)),
+	    };
+	}
+	_ => {}
+    }
+    let (width, signed) = common_type(a, b);
+    let x = MacroValue::new(a.value, width, signed).value;
+    let y = MacroValue::new(b.value, width, signed).value;
+    let value = match op {
+	"|" => x | y,
+	"^" => x ^ y,
+	"&" => x & y,
+	"==" => return truth(x == y),
+	"!=" => return truth(x != y),
+	"<" => return truth(x < y),
+	">" => return truth(x > y),
+	"<=" => return truth(x <= y),
+	">=" => return truth(x >= y),
+	"+" => x + y,
+	"-" => x - y,
+	"*" => x.checked_mul(y)?,
+	"/" => x.checked_div(y)?,
+	"%" => x.checked_rem(y)?,
+	_ => return None,
+    };
+    Some(MacroValue::new(value, width, signed))
+}
+
+impl<'a> Evaluator<'a> {
+    fn peek(&self) -> Option<&'a str> {
+	self.tokens.get(self.pos).map(|t| t.as_str())
+    }
+
+    fn expect(&mut self, tok: &str) -> Option<()> {
+	if self.peek()? != tok {
+	    return None;
+	}
+	self.pos += 1;
+	Some(())
+    }
+
+    fn ternary(&mut self) -> Option<MacroValue> {
+	let cond = self.binary(1)?;
+	if self.peek() != Some("?") {
+	    return Some(cond);
+	}
+	self.pos += 1;
+	let a = self.ternary()?;
+	self.expect(":")?;
+	let b = self.ternary()?;
+	let (width, signed) = common_type(a, b);
+	let chosen = if cond.value != 0 { a } else { b };
+	Some(MacroValue::new(chosen.value, width, signed))
+    }
+
+    // precedence climbing over the left associative binary operators
+    fn binary(&mut self, min_prec: u32) -> Option<MacroValue> {
+	let mut lhs = self.unary()?;
+	while let Some(op) = self.peek() {
+	    let prec = match binary_precedence(op) {
+		Some(prec) if prec >= min_prec => prec,
+		_ => break,
+	    };
+	    self.pos += 1;
+	    let rhs = self.binary(prec + 1)?;
+	    lhs = apply_binary(op, lhs, rhs)?;
+	}
+	Some(lhs)
+    }
+
+    fn unary(&mut self) -> Option<MacroValue> {
+	match self.peek()? {
+	    "-" => {
+		self.pos += 1;
+		let v = self.unary()?.promote();
+		Some(MacroValue::new(-v.value, v.width, v.signed))
+	    }
+	    "+" => {
+		self.pos += 1;
+		Some(self.unary()?.promote())
+	    }
+	    "~" => {
+		self.pos += 1;
+		let v = self.unary()?.promote();
+		Some(MacroValue::new(!v.value, v.width, v.signed))
+	    }
+	    "!" => {
+		self.pos += 1;
+		let v = self.unary()?;
+		Some(MacroValue::int((v.value == 0) as i128))
+	    }
+	    "(" => {
+		if let Some((width, signed, next)) = self.cast_type() {
+		    self.pos = next;
+		    let v = self.unary()?;
+		    return Some(MacroValue::new(v.value, width, signed));
+		}
+		self.pos += 1;
+		let v = self.ternary()?;
+		self.expect(")")?;
+		Some(v)
+	    }
+	    tok => {
+		self.pos += 1;
+		parse_literal(tok)
+	    }
+	}
+    }
+
+    // check for a (type) cast at the current position, returning the
+    // type and the position after the closing paren.
+    fn cast_type(&self) -> Option<(u32, bool, usize)> {
+	let mut idx = self.pos + 1;
+	let mut words: Vec<&str> = Default::default();
+	while idx < self.tokens.len() && self.tokens[idx] != ")" {
+	    words.push(&self.tokens[idx]);
+	    idx += 1;
+	}
+	if words.len() == 0 || idx == self.tokens.len() {
+	    return None;
+	}
+	if words.len() == 1 {
+	    if let Some((_, width, signed)) = CAST_TYPES.iter().find(|(name, _, _)| *name == words[0]) {
+		return Some((*width, *signed, idx + 1));
+	    }
+	}
+	if !words.iter().all(|w| C_TYPE_WORDS.contains(w)) {
+	    return None;
+	}
+	let signed = !words.contains(&"unsigned");
+	let width = if words.contains(&"char") {
+	    8
+	} else if words.contains(&"short") {
+	    16
+	} else if words.contains(&"long") {
+	    64
+	} else {
+	    32
+	};
+	Some((width, signed, idx + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // bodies are space separated tokens
+    fn table(defines: &[(&str, &str)]) -> MacroTable {
+	let mut table = MacroTable::new();
+	for (name, body) in defines {
+	    match name.split_once('(') {
+		Some((name, params)) => {
+		    let params = params.trim_end_matches(')').split(',').map(|p| p.trim().to_string()).collect();
+		    table.add(name.to_string(), Some(params), body.split_whitespace().map(|t| t.to_string()).collect());
+		}
+		None => {
+		    table.add(name.to_string(), None, body.split_whitespace().map(|t| t.to_string()).collect());
+		}
+	    }
+	}
+	table
+    }
+
+    fn eval(body: &str) -> Option<MacroValue> {
+	table(&[("X", body)]).evaluate("X")
+    }
+
+    fn val(value: i128, width: u32, signed: bool) -> Option<MacroValue> {
+	Some(MacroValue { value, width, signed })
+    }
+
+    #[test]
+    fn literals() {
+	assert_eq!(eval("16"), val(16, 32, true));
+	assert_eq!(eval("0x80000000"), val(0x80000000, 32, false));
+	assert_eq!(eval("2147483648"), val(2147483648, 64, true));
+	assert_eq!(eval("1ULL"), val(1, 64, false));
+	assert_eq!(eval("'A'"), val(65, 32, true));
+    }
+
+    #[test]
+    fn shifts() {
+	assert_eq!(eval("( 1 << 4 )"), val(16, 32, true));
+	assert_eq!(eval("( 1U << 31 )"), val(0x80000000, 32, false));
+	assert_eq!(eval("( 1ULL << 40 )"), val(1 << 40, 64, false));
+	assert_eq!(eval("( 0x100 >> 4 )"), val(0x10, 32, true));
+	assert_eq!(eval("( - 16 >> 2 )"), val(-4, 32, true));
+	// shifting by the width or more is undefined
+	assert_eq!(eval("( 1 << 32 )"), None);
+	assert_eq!(eval("( 1 << - 1 )"), None);
+    }
+
+    #[test]
+    fn nvbit() {
+	let macros = table(&[("A", "NVBIT ( 5 )"), ("B", "NVBIT64 ( 40 )"), ("C", "( BIT ( 1 ) | BIT ( 0 ) )")]);
+	assert_eq!(macros.evaluate("A"), val(32, 32, false));
+	assert_eq!(macros.evaluate("B"), val(1 << 40, 64, false));
+	assert_eq!(macros.evaluate("C"), val(3, 32, false));
+
+	// a definition in the headers replaces the builtin one
+	let macros = table(&[("NVBIT(b)", "( 2U << ( b ) )"), ("A", "NVBIT ( 5 )")]);
+	assert_eq!(macros.evaluate("A"), val(64, 32, false));
+    }
+
+    #[test]
+    fn drf_ranges() {
+	let macros = table(&[
+	    ("DRF_SHIFT(drf)", "( ( 0 ? drf ) % 32 )"),
+	    ("DRF_MASK(drf)", "( 0xFFFFFFFFU >> ( 31 - ( ( 1 ? drf ) % 32 ) + ( ( 0 ? drf ) % 32 ) ) )"),
+	    ("NV_FOO_BAR", "7 : 4"),
+	    ("NV_FOO_BAR_SHIFT", "DRF_SHIFT ( NV_FOO_BAR )"),
+	    ("NV_FOO_BAR_MASK", "DRF_MASK ( NV_FOO_BAR )"),
+	]);
+	// the range itself isn't a value
+	assert_eq!(macros.evaluate("NV_FOO_BAR"), None);
+	assert_eq!(macros.evaluate("NV_FOO_BAR_SHIFT"), val(4, 32, true));
+	assert_eq!(macros.evaluate("NV_FOO_BAR_MASK"), val(0xf, 32, false));
+    }
+
+    #[test]
+    fn negative_and_casts() {
+	assert_eq!(eval("( - 1 )"), val(-1, 32, true));
+	assert_eq!(eval("( ~ 0 )"), val(-1, 32, true));
+	assert_eq!(eval("( ( NvU32 ) - 1 )"), val(0xffffffff, 32, false));
+	assert_eq!(eval("( ( NvS8 ) 0xFF )"), val(-1, 8, true));
+	assert_eq!(eval("( ( NvU16 ) 0x12345 )"), val(0x2345, 16, false));
+	assert_eq!(eval("( ( unsigned long ) - 1 )"), val(u64::MAX as i128, 64, false));
+	// mixed signedness converts to unsigned
+	assert_eq!(eval("( - 1 + 0U )"), val(0xffffffff, 32, false));
+	assert_eq!(eval("( - 1 < 0U )"), val(0, 32, true));
+
+	assert_eq!(MacroValue { value: -1, width: 32, signed: true }.to_literal(), "-1");
+	assert_eq!(MacroValue { value: 0xffffffff, width: 32, signed: false }.to_literal(), "0xffffffffU");
+	assert_eq!(MacroValue { value: 1 << 40, width: 64, signed: false }.to_literal(), "0x10000000000ULL");
+    }
+
+    #[test]
+    fn not_a_constant() {
+	let macros = table(&[
+	    ("SELF", "SELF + 1"),
+	    ("FN(x)", "( x + 1 )"),
+	    ("STR(x)", "# x"),
+	    ("USES_STR", "STR ( 1 )"),
+	    ("BAD_ARGS", "FN ( 1 , 2 )"),
+	    ("EMPTY", ""),
+	]);
+	assert_eq!(macros.evaluate("MISSING"), None);
+	assert_eq!(macros.evaluate("SELF"), None);
+	assert_eq!(macros.evaluate("FN"), None);
+	assert_eq!(macros.evaluate("USES_STR"), None);
+	assert_eq!(macros.evaluate("BAD_ARGS"), None);
+	assert_eq!(macros.evaluate("EMPTY"), None);
+	assert_eq!(eval("UNDEFINED_NAME + 1"), None);
+	assert_eq!(eval("\"string\""), None);
+	assert_eq!(eval("( 1 / 0 )"), None);
+	assert_eq!(eval("( 1 + )"), None);
+	assert_eq!(eval("1 2"), None);
+    }
+}
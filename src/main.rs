@@ -2,117 +2,319 @@
 
 extern crate clang;
 
+mod cache;
+mod config;
+mod macro_eval;
+mod merge;
+mod provenance;
+mod report;
+mod source;
+
 use clang::*;
+use clang::token::Token;
+use cache::Cache;
+use config::ExtractSettings;
+use macro_eval::{MacroTable, expr_text};
+use merge::{Conflicts, Sourced};
+use provenance::{Provenance, relative_path};
+use report::{ExtractReport, HeaderReport};
+use source::SourceTree;
+use gsp_parse::*;
 use std::env;
 use serde::{Deserialize, Serialize};
 
-use std::fs::File;
 use std::collections::BTreeMap;
-use std::io::{BufWriter, Write};
-use walkdir::{DirEntry, WalkDir};
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+impl Sourced for HWDefine {
+    fn source_mut(&mut self) -> &mut Option<SourceInfo> { &mut self.source }
+}
 
-// start/end are in bits
-#[derive(Serialize, Deserialize)]
-struct HWStructField {
-    name: String,
-    start: u32,
-    size: u32,
-    group_len: u32,
-    isint: u32,
-    val_type: String,
+impl Sourced for HWStruct {
+    fn source_mut(&mut self) -> &mut Option<SourceInfo> { &mut self.source }
+    fn is_opaque(&self) -> bool { self.opaque }
 }
 
-#[derive(Serialize, Deserialize)]
-struct HWStruct {
-    total_size: u32,
-    fields: Vec<HWStructField>,
+impl Sourced for HWEnum {
+    fn source_mut(&mut self) -> &mut Option<SourceInfo> { &mut self.source }
 }
 
-#[derive(Serialize, Deserialize, Default)]
-enum HWDefineType {
-    #[default]
-    Unknown,
-    Value,
+impl Sourced for HWScalar {
+    fn source_mut(&mut self) -> &mut Option<SourceInfo> { &mut self.source }
 }
 
-#[derive(Serialize, Deserialize, Default)]
-struct HWDefine {
-    hwtype: HWDefineType,
-    vals: Vec<String>,
+impl Sourced for CTypes {
+    fn source_mut(&mut self) -> &mut Option<SourceInfo> { &mut self.source }
+    fn is_opaque(&self) -> bool { self.opaque }
 }
 
-#[derive(Serialize, Deserialize, Default)]
-struct HWJson {
-    version: String,
-    defines: BTreeMap<String, HWDefine>,
-    structs: BTreeMap<String, HWStruct>,
+fn get_type_size(fld_type: Type) -> usize {
+    let mut size = 0;
+    if fld_type.is_elaborated().unwrap() {
+	let field_elab_type = fld_type.get_elaborated_type().unwrap();
+	if field_elab_type.get_kind() == TypeKind::Typedef {
+	    let field_elab_typedef = field_elab_type.get_declaration().unwrap().get_typedef_underlying_type();
+	    size = field_elab_typedef.unwrap().get_sizeof().unwrap() * 8;
+
+	} else if field_elab_type.get_kind() == TypeKind::Enum {
+	    size = field_elab_type.get_sizeof().unwrap() * 8;
+	}
+    } else if fld_type.is_integer() {
+	size = fld_type.get_sizeof().unwrap() * 8;
+    }
+    size
 }
 
-#[derive(Serialize, Deserialize)]
-enum FieldType {
-    Member,
-    UnionStart,
-    UnionEnd,
-    StructStart,
-    StructEnd,
+// parse a C integer literal as used in alignment attributes
+fn parse_c_integer(val: &str) -> Option<usize> {
+    let val = val.trim_end_matches(|c| c == 'u' || c == 'U' || c == 'l' || c == 'L');
+    if val.starts_with("0x") || val.starts_with("0X") {
+	usize::from_str_radix(&val[2..], 16).ok()
+    } else {
+	val.parse::<usize>().ok()
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-struct CStructField {
-    fldtype: FieldType,
-    ftype: String,
-    name: String,
-    is_array: bool,
-    size: u32,
-    is_aligned: bool,
-    alignment: u32,
+// look for the alignment value in either __attribute__((aligned(N)))
+// or NV_DECLARE_ALIGNED(TYPE_VAR, N) tokens.
+fn find_alignment_value(tokens: &Vec<Token>) -> Option<usize> {
+    for (idx, token) in tokens.iter().enumerate() {
+	let spelling = token.get_spelling();
+	if (spelling == "aligned" || spelling == "__aligned__") &&
+	    idx + 2 < tokens.len() && tokens[idx + 1].get_spelling() == "(" {
+		return parse_c_integer(&tokens[idx + 2].get_spelling());
+	    }
+	if spelling.ends_with("_ALIGNED") && idx + 1 < tokens.len() && tokens[idx + 1].get_spelling() == "(" {
+	    // the alignment is the last macro argument
+	    let mut depth = 0;
+	    for (end, token) in tokens.iter().enumerate().skip(idx + 1) {
+		match token.get_spelling().as_str() {
+		    "(" => depth += 1,
+		    ")" => {
+			depth -= 1;
+			if depth == 0 {
+			    return parse_c_integer(&tokens[end - 1].get_spelling());
+			}
+		    }
+		    _ => {}
+		}
+	    }
+	}
+    }
+    None
 }
 
-#[derive(Serialize, Deserialize, Default)]
-enum CType {
-    #[default]
-    Unknown,
-    Value,
-    Struct,
-    Typedef,
+// returns whether the field has a declared alignment and its
+// alignment in bytes, the natural alignment of the type if not
+// declared. 0 when clang can't lay the type out, e.g. for flexible
+// array members.
+fn get_field_alignment(fld: &Entity) -> (bool, usize) {
+    let natural = fld.get_type().unwrap().get_alignof().unwrap_or(0);
+
+    if !fld.has_attributes() {
+	return (false, natural);
+    }
+    let aligned = fld.get_children().into_iter().filter(|attr| {
+	attr.get_kind() == EntityKind::AlignedAttr
+    }).collect::<Vec<_>>();
+
+    if aligned.len() == 0 {
+	return (false, natural);
+    }
+
+    // The attribute range usually points inside the macro that added
+    // it, so try the attribute first and then the whole declaration.
+    for attr in aligned {
+	if let Some(val) = attr.get_range().and_then(|r| find_alignment_value(&r.tokenize())) {
+	    return (true, val);
+	}
+    }
+    if let Some(val) = fld.get_range().and_then(|r| find_alignment_value(&r.tokenize())) {
+	return (true, val);
+    }
+    let location = fld.get_location().map(|l| l.get_file_location());
+    let file = location.as_ref().and_then(|l| l.file).map(|f| f.get_path().display().to_string()).unwrap_or_default();
+    eprintln!("{}:{}: can't find the declared alignment of {}, using the alignment of its type",
+	      file, location.map_or(0, |l| l.line), fld.get_display_name().unwrap_or_default());
+    (true, natural)
 }
 
-#[derive(Serialize, Deserialize, Default)]
-struct CTypes {
-    ctype: CType,
-    vals: Vec<String>,
-    is_anon_struct: bool,
-    // for structs
-    fields: Vec<CStructField>,
+// work out the alignment of a record and whether it is packed, either
+// by __attribute__((packed)) or by being inside a #pragma pack region.
+// pragma pack leaves no attribute behind, so look for fields that
+// got less than their alignment.
+fn get_record_packing(decl: &Entity) -> (usize, bool) {
+    let rtype = decl.get_type().unwrap();
+    let alignment = rtype.get_alignof().unwrap_or(1);
+    let mut packed = decl.get_children().into_iter().any(|attr| {
+	attr.get_kind() == EntityKind::PackedAttr
+    });
+
+    for fld in rtype.get_fields().unwrap_or_default() {
+	let (_, fld_align) = get_field_alignment(&fld);
+	// nothing to check for fields without a layout
+	if fld_align == 0 {
+	    continue;
+	}
+	if fld_align > alignment {
+	    packed = true;
+	}
+	if !fld.is_bit_field() && fld.get_offset_of_field().unwrap_or(0) % (fld_align * 8) != 0 {
+	    packed = true;
+	}
+    }
+    (alignment, packed)
 }
 
-#[derive(Serialize, Deserialize, Default)]
-struct CJson {
-    version: String,
-    types: BTreeMap<String, CTypes>,
+// work out the storage unit a bitfield lives in, normally the
+// naturally aligned unit of its declared type, but packed structs
+// can put bitfields across that, so fall back to the smallest
+// byte aligned unit that holds all the bits.
+fn bitfield_unit(bit_start: usize, width: usize, type_size: usize) -> (usize, usize) {
+    let unit_start = bit_start - (bit_start % type_size);
+    if bit_start + width <= unit_start + type_size {
+	return (unit_start, type_size);
+    }
+    let unit_start = bit_start - (bit_start % 8);
+    let mut unit_size = 8;
+    while unit_start + unit_size < bit_start + width {
+	unit_size *= 2;
+    }
+    (unit_start, unit_size)
 }
 
-fn get_type_size(fld_type: Type) -> usize {
-    let mut size = 0;
-    if fld_type.is_elaborated().unwrap() {
-	let field_elab_type = fld_type.get_elaborated_type().unwrap();
-	if field_elab_type.get_kind() == TypeKind::Typedef {
-	    let field_elab_typedef = field_elab_type.get_declaration().unwrap().get_typedef_underlying_type();
-	    size = field_elab_typedef.unwrap().get_sizeof().unwrap() * 8;
+fn is_union_decl(record_type: Type) -> bool {
+    match record_type.get_declaration() {
+	Some(x) => { x.get_kind() == EntityKind::UnionDecl }
+	None => { false }
+    }
+}
+
+// an unnamed struct or union member, accessed as if its fields
+// were members of the parent.
+fn is_anon_record_field(fld: &Entity) -> bool {
+    fld.get_display_name().is_none() &&
+	fld.get_type().map_or(false, |t| t.get_canonical_type().get_kind() == TypeKind::Record)
+}
 
+// anonymous records get a synthetic name from their position among
+// the anonymous records of the parent, anon0, anon1, ...
+fn anon_record_name(fld: &Entity) -> String {
+    let mut idx = 0;
+    let siblings = fld.get_semantic_parent()
+	.and_then(|p| p.get_type())
+	.and_then(|t| t.get_fields())
+	.unwrap_or_default();
+    for sibling in siblings {
+	if sibling == *fld {
+	    break;
+	}
+	if is_anon_record_field(&sibling) {
+	    idx += 1;
 	}
-    } else if fld_type.is_integer() {
-	size = fld_type.get_sizeof().unwrap() * 8;
     }
-    size
+    format!("anon{}", idx)
+}
+
+// the fields of a union all start at base_offset, each member is
+// flattened on its own so the fields it covers can be recorded as
+// one alternative.
+fn handle_union(base_offset: usize,
+		newfields: &mut Vec<HWStructField>,
+		unions: &mut Vec<HWUnion>,
+		union_type: Type,
+		name: &str,
+		parent: &str,
+		name_prefix: &str,
+		parent_union: Option<(u32, u32)>) {
+    let union_idx = unions.len();
+    unions.push(HWUnion {
+	name: name.to_string(),
+	parent: parent.to_string(),
+	parent_union,
+	start: base_offset as u32,
+	size: (union_type.get_sizeof().unwrap_or(0) * 8) as u32,
+	alternatives: Default::default(),
+    });
+
+    for (alt_idx, member) in union_type.get_fields().unwrap().into_iter().enumerate() {
+	let first_field = newfields.len();
+	let alt_name = match member.get_display_name() {
+	    Some(x) => { x }
+	    None => { anon_record_name(&member) }
+	};
+	handle_record(base_offset, newfields, unions, vec![member], name_prefix,
+		      Some((union_idx as u32, alt_idx as u32)));
+	let fields = newfields[first_field..].iter().map(|f| f.name.clone()).collect();
+	unions[union_idx].alternatives.push(HWUnionAlternative {
+	    name: alt_name,
+	    fields,
+	});
+    }
+}
+
+// signed, float, boolean for the canonical kinds that are scalars,
+// pointers are treated as unsigned integers of pointer size.
+fn scalar_kind(kind: TypeKind) -> Option<(bool, bool, bool)> {
+    match kind {
+	TypeKind::CharS | TypeKind::SChar | TypeKind::Short | TypeKind::Int |
+	TypeKind::Long | TypeKind::LongLong | TypeKind::Int128 => { Some((true, false, false)) }
+	TypeKind::CharU | TypeKind::UChar | TypeKind::UShort | TypeKind::UInt |
+	TypeKind::ULong | TypeKind::ULongLong | TypeKind::UInt128 | TypeKind::Pointer => { Some((false, false, false)) }
+	TypeKind::Float | TypeKind::Double => { Some((true, true, false)) }
+	TypeKind::Bool => { Some((false, false, true)) }
+	_ => { None }
+    }
+}
+
+// the type name a field refers to in scalars, the element type for
+// arrays, empty for anything that isn't a scalar.
+fn get_scalar_name(fld_type: Type) -> String {
+    let mut scalar_type = fld_type;
+    while scalar_type.get_canonical_type().get_kind() == TypeKind::ConstantArray {
+	// a typedef of an array only has its element in the canonical type
+	if scalar_type.get_kind() != TypeKind::ConstantArray {
+	    scalar_type = scalar_type.get_canonical_type();
+	}
+	scalar_type = scalar_type.get_element_type().unwrap();
+    }
+    let canon_type = scalar_type.get_canonical_type();
+    if scalar_kind(canon_type.get_kind()).is_none() {
+	return "".to_string();
+    }
+    // plain pointers have no name of their own to register
+    if scalar_type.get_kind() == TypeKind::Pointer {
+	return "".to_string();
+    }
+    scalar_type.get_display_name().replace("const ", "").replace("volatile ", "")
+}
+
+fn make_hw_scalar(name: &str, typedef_type: Type, source: Option<SourceInfo>) -> Option<HWScalar> {
+    let canon_type = typedef_type.get_canonical_type();
+    let (signed, float, boolean) = scalar_kind(canon_type.get_kind())?;
+    Some(HWScalar {
+	size: (canon_type.get_sizeof().ok()? * 8) as u32,
+	signed,
+	float,
+	boolean: boolean || name == "NvBool",
+	canonical: canon_type.get_display_name(),
+	source,
+    })
 }
 
 // recursive function that handles records inside records.
 // used for handling union/struct nesting
 fn handle_record(base_offset: usize,
 		 newfields: &mut Vec<HWStructField>,
+		 unions: &mut Vec<HWUnion>,
 		 record_fields: Vec<Entity>,
-		 name_prefix: &str) -> usize {
+		 name_prefix: &str,
+		 parent_union: Option<(u32, u32)>) -> usize {
 //    println!("handle_record {:?}", record_fields);    
     let mut end_offset = base_offset;
     for fld in record_fields {
@@ -121,6 +323,35 @@ fn handle_record(base_offset: usize,
 	let mut valname = "".to_string();
 	let this_base_offset = base_offset + fld.get_offset_of_field().unwrap();
 
+	if fld.is_bit_field() {
+	    let width = fld.get_bit_field_width().unwrap();
+	    // unnamed bitfields are just padding
+	    if width == 0 || fld.get_display_name().is_none() {
+		continue;
+	    }
+	    let mut type_size = get_type_size(fld_type);
+	    if type_size == 0 {
+		type_size = fld_type.get_sizeof().unwrap() * 8;
+	    }
+	    let (unit_start, unit_size) = bitfield_unit(this_base_offset, width, type_size);
+
+	    end_offset = unit_start + unit_size;
+	    newfields.push(HWStructField {
+		name: name_prefix.to_owned() + &fld.get_display_name().unwrap(),
+		val_type: fld_type.get_display_name(),
+		start: unit_start as u32,
+		size: unit_size as u32,
+		group_len: 0xffffffff,
+		isint: 1,
+		bit_offset: (this_base_offset - unit_start) as u32,
+		bit_width: width as u32,
+		alignment: get_field_alignment(&fld).1 as u32,
+		enum_type: get_enum_name(fld_type).unwrap_or_default(),
+		scalar: get_scalar_name(fld_type),
+	    });
+	    continue;
+	}
+
 //	println!("fld_type {:?}", fld_type);
 	if fld_type.get_kind() == TypeKind::ConstantArray {
 	    group_size = fld_type.get_size().unwrap();
@@ -132,26 +363,40 @@ fn handle_record(base_offset: usize,
 
 	    if fld_type.get_elaborated_type().unwrap().get_kind() == TypeKind::Record {
 		let mut lc = 1;
-		if group_size != 0xffffffff && fld_type.get_elaborated_type().unwrap().get_declaration().unwrap().get_kind() == EntityKind::StructDecl {
+		let decl_kind = fld_type.get_elaborated_type().unwrap().get_declaration().unwrap().get_kind();
+		if group_size != 0xffffffff && (decl_kind == EntityKind::StructDecl || decl_kind == EntityKind::UnionDecl) {
 		    lc = group_size;
 		}
 
 		for l in 0..lc {
 		    let sz = fld_type.get_elaborated_type().unwrap().get_sizeof().unwrap();
 		    //		println!("field {}", fld.get_display_name().unwrap());
-		    let mut name = fld.get_display_name().unwrap() + "_";
+		    let mut name = name_prefix.to_owned() + &fld.get_display_name().unwrap() + "_";
 		    if lc > 1 {
-			name = fld.get_display_name().unwrap() + "_" + &l.to_string() + "_";
+			name = name_prefix.to_owned() + &fld.get_display_name().unwrap() + "_" + &l.to_string() + "_";
+		    }
+		    let record_type = fld_type.get_elaborated_type().unwrap();
+		    if is_union_decl(record_type) {
+			handle_union(this_base_offset + l * sz * 8, newfields, unions, record_type,
+				     name.strip_suffix("_").unwrap(), name_prefix, &name, parent_union);
+		    } else {
+			handle_record(this_base_offset + l * sz * 8, newfields, unions,
+				      record_type.get_fields().unwrap(), &name, parent_union);
 		    }
-		    handle_record(this_base_offset + l * sz * 8, newfields,
-				  fld_type.get_elaborated_type().unwrap().get_fields().unwrap(), &name);
 		    end_offset += fld_type.get_elaborated_type().unwrap().get_sizeof().unwrap();
 		}
 		continue;
 	    }
 	}
+	// anonymous members keep the parent prefix, their fields are
+	// accessed as fields of the parent in C
 	if fld_type.get_kind() == TypeKind::Record {
-	    handle_record(this_base_offset, newfields, fld.get_type().unwrap().get_fields().unwrap(), "");//fld_type.get_display_name());
+	    if is_union_decl(fld_type) {
+		handle_union(this_base_offset, newfields, unions, fld_type,
+			     &(name_prefix.to_owned() + &anon_record_name(&fld)), name_prefix, name_prefix, parent_union);
+	    } else {
+		handle_record(this_base_offset, newfields, unions, fld.get_type().unwrap().get_fields().unwrap(), name_prefix, parent_union);
+	    }
 	    end_offset += fld.get_type().unwrap().get_sizeof().unwrap();
 	    continue;
 	}
@@ -228,13 +473,19 @@ fn handle_record(base_offset: usize,
 	    size: size as u32,
 	    group_len: group_size as u32,
 	    isint,
+	    bit_offset: 0,
+	    bit_width: 0,
+	    alignment: get_field_alignment(&fld).1 as u32,
+	    enum_type: get_enum_name(fld_type).unwrap_or_default(),
+	    scalar: get_scalar_name(fld_type),
 	})
     }
     end_offset
 }
 
 // recursive function that handles records inside records.
-// used for handling union/struct nesting
+// used for handling union/struct nesting, name_prefix is the path
+// of nested members only used to name anonymous records.
 fn handle_c_parser_record(newfields: &mut Vec<CStructField>,
 			  record_fields: Vec<Entity>,
 			  name_prefix: &str) -> usize {
@@ -244,8 +495,6 @@ fn handle_c_parser_record(newfields: &mut Vec<CStructField>,
 	let mut incomplete_array = false;
 	let mut array_size = 0xffffffff;
 	let mut valname = "".to_string();
-	let mut is_aligned = false;
-	let mut aligned_val = 0;
 	
 //	println!("{:?} {:?}", fld_type, fld_type.get_declaration());
 	if fld_type.get_kind() == TypeKind::IncompleteArray {
@@ -273,7 +522,9 @@ fn handle_c_parser_record(newfields: &mut Vec<CStructField>,
 			is_array: false,
 			size: 0,
 			is_aligned: false,
-			alignment: 0
+			alignment: 0,
+			bit_width: 0,
+			anon_name: "".to_string(),
 		    });
 		}
 		if fld_type.get_elaborated_type().unwrap().get_declaration().unwrap().get_kind() == EntityKind::StructDecl {
@@ -284,12 +535,15 @@ fn handle_c_parser_record(newfields: &mut Vec<CStructField>,
 			is_array: false,
 			size: 0,
 			is_aligned: false,
-			alignment: 0
+			alignment: 0,
+			bit_width: 0,
+			anon_name: "".to_string(),
 		    });
 		}
 
 		handle_c_parser_record(newfields,
-				       fld_type.get_elaborated_type().unwrap().get_fields().unwrap(), "");
+				       fld_type.get_elaborated_type().unwrap().get_fields().unwrap(),
+				       &(name_prefix.to_owned() + &fld.get_display_name().unwrap() + "_"));
 		if fld_type.get_elaborated_type().unwrap().get_declaration().unwrap().get_kind() == EntityKind::StructDecl {
 		    newfields.push(CStructField {
 			fldtype: FieldType::StructEnd,
@@ -298,7 +552,9 @@ fn handle_c_parser_record(newfields: &mut Vec<CStructField>,
 			is_array: incomplete_array || array_size != 0xffffffff,
 			size: array_size as u32,
 			is_aligned: false,
-			alignment: 0
+			alignment: 0,
+			bit_width: 0,
+			anon_name: "".to_string(),
 		    });
 		}
 		if fld_type.get_elaborated_type().unwrap().get_declaration().unwrap().get_kind() == EntityKind::UnionDecl {
@@ -309,36 +565,47 @@ fn handle_c_parser_record(newfields: &mut Vec<CStructField>,
 			is_array: incomplete_array || array_size != 0xffffffff,
 			size: array_size as u32,
 			is_aligned: false,
-			alignment: 0
+			alignment: 0,
+			bit_width: 0,
+			anon_name: "".to_string(),
 		    });
 		}
 		continue;
 	    }
 	}
 	if fld_type.get_kind() == TypeKind::Record {
+	    // C has no name for these, anon_name identifies them
+	    let mut anon_name = "".to_string();
+	    if is_anon_record_field(&fld) {
+		anon_name = name_prefix.to_owned() + &anon_record_name(&fld);
+	    }
 	    if fld_type.get_declaration().unwrap().get_kind() == EntityKind::UnionDecl {
 		newfields.push(CStructField {
 		    fldtype: FieldType::UnionStart,
 		    ftype: "".to_string(),
-		    name: fld.get_display_name().unwrap(),
+		    name: fld.get_display_name().unwrap_or_default(),
 		    is_array: false,
 		    size: 0,
 		    is_aligned: false,
-		    alignment: 0
+		    alignment: 0,
+		    bit_width: 0,
+		    anon_name: anon_name.clone(),
 		});
 	    }
 	    if fld_type.get_declaration().unwrap().get_kind() == EntityKind::StructDecl {
 		newfields.push(CStructField {
 		    fldtype: FieldType::StructStart,
 		    ftype: "".to_string(),
-		    name: fld.get_display_name().unwrap(),					    
+		    name: fld.get_display_name().unwrap_or_default(),					    
 		    is_array: false,
 		    size: 0,
 		    is_aligned: false,
-		    alignment: 0
+		    alignment: 0,
+		    bit_width: 0,
+		    anon_name: anon_name.clone(),
 		});
 	    }
-	    handle_c_parser_record(newfields, fld.get_type().unwrap().get_fields().unwrap(), "");//fld_type.get_display_name());
+	    handle_c_parser_record(newfields, fld.get_type().unwrap().get_fields().unwrap(), &(anon_name.clone() + "_"));
 	    if fld_type.get_declaration().unwrap().get_kind() == EntityKind::StructDecl {
 		newfields.push(CStructField {
 		    fldtype: FieldType::StructEnd,
@@ -347,7 +614,9 @@ fn handle_c_parser_record(newfields: &mut Vec<CStructField>,
 		    is_array: incomplete_array || array_size != 0xffffffff,
 		    size: array_size as u32,		    
 		    is_aligned: false,
-		    alignment: 0
+		    alignment: 0,
+		    bit_width: 0,
+		    anon_name: anon_name.clone(),
 		});
 	    }
 	    if fld_type.get_declaration().unwrap().get_kind() == EntityKind::UnionDecl {
@@ -358,7 +627,9 @@ fn handle_c_parser_record(newfields: &mut Vec<CStructField>,
 		    is_array: incomplete_array || array_size != 0xffffffff,
 		    size: array_size as u32,		    		    
 		    is_aligned: false,
-		    alignment: 0
+		    alignment: 0,
+		    bit_width: 0,
+		    anon_name: anon_name.clone(),
 		});
 	    }
 	    continue;
@@ -368,19 +639,7 @@ fn handle_c_parser_record(newfields: &mut Vec<CStructField>,
 	let mut isint = 1;
 
 	size = get_type_size(fld_type);
-	if fld.has_attributes() {
-	    let aligned = fld.get_children().into_iter().filter(|attr| {
-		attr.get_kind() == EntityKind::AlignedAttr
-	    }).collect::<Vec<_>>();
-
-	    if (aligned.len() > 0) {
-		// Finding alignment with libclang is difficult, I tried tokenise
-		// but failed.
-		// Assume 8 here
-		is_aligned = true;
-		aligned_val = 8;
-	    }
-	}
+	let (is_aligned, aligned_val) = get_field_alignment(&fld);
 	if fld_type.is_integer() == false {
 	    if fld_type.is_elaborated().unwrap() {
 		let elab_type = fld_type.get_elaborated_type().unwrap();
@@ -410,46 +669,261 @@ fn handle_c_parser_record(newfields: &mut Vec<CStructField>,
 		valname = fld_type.get_display_name();
 	    }
 	}
+
+	// bitfields keep their declared type, unnamed ones are padding
+	let mut bit_width = 0;
+	if fld.is_bit_field() {
+	    bit_width = fld.get_bit_field_width().unwrap();
+	    if valname == "" {
+		valname = fld_type.get_display_name();
+	    }
+	}
     
 	newfields.push(CStructField {
 	    fldtype: FieldType::Member,
-	    name: name_prefix.to_owned() + &fld.get_display_name().unwrap(),
+	    name: fld.get_display_name().unwrap_or_default(),
 	    ftype: valname,
 	    is_array: array_size != 0xffffffff,
 	    size: array_size as u32,
 	    is_aligned: is_aligned,
-	    alignment: aligned_val,
+	    alignment: aligned_val as u32,
+	    bit_width: bit_width as u32,
+	    anon_name: "".to_string(),
 	})
     }
     0
 }
 
-fn setup_parser<'a>(index: &'a Index, path: &str, prefix: &String) -> std::io::Result<TranslationUnit<'a>> {
+fn setup_parser<'a>(index: &'a Index, path: &str, tree: &str, settings: &ExtractSettings, unsaved: &[Unsaved]) -> Result<TranslationUnit<'a>, SourceError> {
     // Parse a source file into a translation unit
     let mut parser = index.parser(path);
 
-    let mut args : Vec<String> = Default::default();
+    let args = settings.clang_args(tree);
+
+    // turn on detailed preprocessing to get defines
+    parser.detailed_preprocessing_record(true);
+    parser.arguments(&args);
+    parser.unsaved(unsaved);
+    parser.parse()
+}
 
-    for define in DEFINES {
-	args.push(define.to_string());
+// the simple define shapes, plain values, (value), (a << b),
+// ((a << b) * c) and a:b bit ranges, keep their original spelling.
+fn simple_define_vals(tokens: &Vec<Token>) -> Vec<String> {
+    let mut vals: Vec<String> = Default::default();
+
+    if tokens.len() != 2 && tokens.len() != 4 && tokens.len() != 6 && tokens.len() != 10 {
+	return vals;
     }
 
-    for incpath in INCPATHS {
-	args.push("-I".to_string() + prefix + incpath);
+    if tokens.len() == 10 &&
+	tokens[1].get_spelling() == "(" && tokens[9].get_spelling() == ")" &&
+	tokens[4].get_spelling() == "<<" && tokens[7].get_spelling() == "*" {
+	    vals.push("(".to_owned() + &tokens[3].get_spelling() + "<<" + &tokens[5].get_spelling() + ") * " + &tokens[8].get_spelling());
+	}
+    else if tokens.len() == 6 &&
+	tokens[1].get_spelling() == "(" && tokens[5].get_spelling() == ")" &&
+	tokens[3].get_spelling() == "<<" {
+	    vals.push(tokens[2].get_spelling() + "<<" + &tokens[4].get_spelling());
+	}
+    if tokens.len() == 2 {
+	vals.push(tokens[1].get_spelling());
+    } else if tokens[1].get_spelling() == "(" && tokens[3].get_spelling() == ")" {
+	vals.push(tokens[2].get_spelling());
+    } else if tokens[2].get_spelling() == ":" {
+	vals.push(tokens[1].get_spelling());
+	vals.push(tokens[3].get_spelling());
     }
+    vals
+}
 
-    for incfile in INCFILES {
-	args.push("-include".to_string());
-	args.push(incfile.to_string());
+// the size in bits of an enum, which packed enums shrink below
+// the underlying type, its signedness and whether it is packed.
+fn get_enum_layout(tenum: &Entity) -> (u32, bool, bool) {
+    let under_type = tenum.get_enum_underlying_type().unwrap().get_canonical_type();
+    let size = tenum.get_type().unwrap().get_sizeof().unwrap() * 8;
+    let packed = tenum.get_children().into_iter().any(|attr| {
+	attr.get_kind() == EntityKind::PackedAttr
+    });
+    (size as u32, under_type.is_signed_integer(), packed)
+}
+
+// enum constants typed by their enum
+fn enum_constant_value(tenum: &Entity, constant: &Entity) -> (i128, u32, bool) {
+    let (width, signed, _) = get_enum_layout(tenum);
+    let (svalue, uvalue) = constant.get_enum_constant_value().unwrap();
+    if signed {
+	(svalue as i128, width, signed)
+    } else {
+	(uvalue as i128, width, signed)
     }
+}
 
-    // turn on detailed preprocessing to get defines
-    parser.detailed_preprocessing_record(true);
-    parser.arguments(&args);
-    Ok(parser.parse().unwrap())
+fn get_enum_constants<'a>(tenum: &Entity<'a>) -> Vec<Entity<'a>> {
+    tenum.get_children().into_iter().filter(|e| {
+	e.get_kind() == EntityKind::EnumConstantDecl
+    }).collect::<Vec<_>>()
+}
+
+// the tag of an enum, struct or union, None for anonymous ones which newer libclang
+// names "(unnamed enum at file:line)"
+fn get_tag_name(tenum: &Entity) -> Option<String> {
+    let name = tenum.get_name()?;
+    if name.contains("(unnamed") || name.contains("(anonymous") {
+	return None;
+    }
+    Some(name)
+}
+
+// the name the enum a field type refers to is recorded under,
+// the typedef name if it has one, otherwise the tag.
+fn get_enum_name(fld_type: Type) -> Option<String> {
+    let canon_type = fld_type.get_canonical_type();
+    if canon_type.get_kind() != TypeKind::Enum {
+	return None;
+    }
+    if fld_type.is_elaborated().unwrap() {
+	let elab_type = fld_type.get_elaborated_type().unwrap();
+	if elab_type.get_kind() == TypeKind::Typedef {
+	    return Some(elab_type.get_display_name());
+	}
+    }
+    if fld_type.get_kind() == TypeKind::Typedef {
+	return Some(fld_type.get_display_name());
+    }
+    get_tag_name(&canon_type.get_declaration().unwrap())
+}
+
+// the name an enum and its constants are recorded under, the tag or
+// for an untagged enum its first typedef. None for untagged enums
+// that are only a list of constants.
+fn get_enum_key(tenum: &Entity, typedefs: &[Entity]) -> Option<String> {
+    if let Some(tag) = get_tag_name(tenum) {
+	return Some(tag);
+    }
+    typedefs.iter().find(|typedef| {
+	typedef.get_typedef_underlying_type().unwrap().get_canonical_type().get_declaration() == Some(*tenum)
+    }).map(|typedef| typedef.get_display_name().unwrap())
+}
+
+fn make_hw_enum(tenum: &Entity, source: Option<SourceInfo>) -> HWEnum {
+    let (size, signed, packed) = get_enum_layout(tenum);
+    HWEnum {
+	size,
+	signed,
+	packed,
+	members: get_enum_constants(tenum).iter().map(|child| HWEnumMember {
+	    name: child.get_display_name().unwrap(),
+	    value: enum_constant_value(tenum, child).0,
+	}).collect(),
+	alias_of: "".to_string(),
+	source,
+    }
+}
+
+fn make_c_enum(tenum: &Entity, source: Option<SourceInfo>) -> CTypes {
+    let (size, signed, packed) = get_enum_layout(tenum);
+    CTypes {
+	ctype: CType::Enum,
+	vals: vec!(tenum.get_enum_underlying_type().unwrap().get_display_name()),
+	width: size,
+	signed,
+	packed,
+	members: get_enum_constants(tenum).iter().map(|child| CEnumMember {
+	    name: child.get_display_name().unwrap(),
+	    value: enum_constant_value(tenum, child).0,
+	}).collect(),
+	source,
+	..Default::default()
+    }
+}
+
+// gather every macro definition in the translation unit so the
+// evaluator can expand references to other macros.
+fn collect_macros<'a>(tu: &TranslationUnit<'a>) -> MacroTable {
+    let mut table = MacroTable::new();
+
+    for define_ in tu.get_entity().get_children() {
+	if define_.get_kind() != EntityKind::MacroDefinition || define_.is_builtin_macro() {
+	    continue;
+	}
+	let tokens = define_.get_range().unwrap().tokenize().iter().map(|t| t.get_spelling()).collect::<Vec<_>>();
+	if tokens.len() == 0 {
+	    continue;
+	}
+	if define_.is_function_like_macro() {
+	    let close = match tokens.iter().position(|t| t == ")") {
+		Some(x) => { x }
+		None => { continue; }
+	    };
+	    let params = tokens[2..close].iter().filter(|t| *t != ",").cloned().collect();
+	    table.add(tokens[0].clone(), Some(params), tokens[close + 1..].to_vec());
+	} else {
+	    table.add(tokens[0].clone(), None, tokens[1..].to_vec());
+	}
+    }
+    table
+}
+
+// what a typedef of a record refers to, another typedef or the tag,
+// empty for typedef struct X X and anonymous records.
+fn get_typedef_alias(under_type: Type, tag: &String, name: &String) -> String {
+    let mut named_type = under_type;
+    if under_type.is_elaborated().unwrap() {
+	named_type = under_type.get_elaborated_type().unwrap();
+    }
+    if named_type.get_kind() == TypeKind::Typedef {
+	return named_type.get_display_name();
+    }
+    if tag != name {
+	return tag.clone();
+    }
+    "".to_string()
+}
+
+// the layout of a struct or union, records only declared in this
+// translation unit are opaque.
+fn make_hw_struct(record: &Entity, tag: String, alias_of: String, source: Option<SourceInfo>) -> HWStruct {
+    let decl = match record.get_definition() {
+	Some(x) => { x }
+	None => {
+	    return HWStruct { tag, alias_of, opaque: true, source, ..Default::default() };
+	}
+    };
+
+    let mut newfields : Vec<HWStructField> = Default::default();
+    let mut unions : Vec<HWUnion> = Default::default();
+
+    let base_offset = 0;
+    let is_union = decl.get_kind() == EntityKind::UnionDecl;
+    if is_union {
+	handle_union(base_offset, &mut newfields, &mut unions, decl.get_type().unwrap(), "", "", "", None);
+    } else {
+	handle_record(base_offset, &mut newfields, &mut unions, decl.get_type().unwrap().get_fields().unwrap(), "", None);
+    }
+    let total_size = match decl.get_type().unwrap().get_sizeof() {
+	Ok(x) => { x * 8 }
+	Err(_) => { 0 }
+    };
+
+    let (alignment, packed) = get_record_packing(&decl);
+    let holes = find_holes(&newfields, total_size as u32);
+    HWStruct {
+	total_size: total_size as u32,
+	fields: newfields,
+	is_union,
+	tag,
+	alias_of,
+	opaque: total_size == 0,
+	unions,
+	alignment: alignment as u32,
+	packed: packed,
+	holes,
+	source,
+    }
 }
 
-fn add_file_to_hwjson<'a>(tu: &TranslationUnit<'a>, json_output: &mut HWJson) -> std::io::Result<()> {
+fn add_file_to_hwjson<'a>(tu: &TranslationUnit<'a>, macros: &MacroTable, sources: &Provenance, json_output: &mut HWJson) -> std::io::Result<()> {
     // Get the declearations?
     let defines = tu.get_entity().get_children().into_iter().filter(|e| {
 	e.get_kind() == EntityKind::MacroDefinition &&
@@ -466,99 +940,158 @@ fn add_file_to_hwjson<'a>(tu: &TranslationUnit<'a>, json_output: &mut HWJson) ->
 	}
 	let tokens = define_.get_range().unwrap().tokenize();
 
-	// All the interesting ones have 4 tokens.
-	if tokens.len() != 2 && tokens.len() != 4 && tokens.len() != 6 && tokens.len() != 10 {
+	if tokens.len() < 2 {
 	    continue;
 	}
 
-	let mut vals: Vec<String> = Default::default();
-	if tokens.len() == 10 &&
-	    tokens[1].get_spelling() == "(" && tokens[9].get_spelling() == ")" &&
-	    tokens[4].get_spelling() == "<<" && tokens[7].get_spelling() == "*" {
-		hwtype = HWDefineType::Value;
-		vals.push("(".to_owned() + &tokens[3].get_spelling() + "<<" + &tokens[5].get_spelling() + ") * " + &tokens[8].get_spelling());
-	    }
-	else if tokens.len() == 6 &&
-	    tokens[1].get_spelling() == "(" && tokens[5].get_spelling() == ")" &&
-	    tokens[3].get_spelling() == "<<" {
-		hwtype = HWDefineType::Value;
-		vals.push(tokens[2].get_spelling() + "<<" + &tokens[4].get_spelling());
-	    }
-	if tokens.len() == 2 {
-	    hwtype = HWDefineType::Value;
-	    vals.push(tokens[1].get_spelling());
-	} else if tokens[1].get_spelling() == "(" && tokens[3].get_spelling() == ")" {
+	let mut vals = simple_define_vals(&tokens);
+	if vals.len() > 0 {
 	    hwtype = HWDefineType::Value;
-	    vals.push(tokens[2].get_spelling());
-	} else if tokens[2].get_spelling() == ":" {
+	}
+	let value = macros.evaluate(&name);
+	if let (HWDefineType::Unknown, Some(val)) = (&hwtype, value) {
 	    hwtype = HWDefineType::Value;
-	    vals.push(tokens[1].get_spelling());
-	    vals.push(tokens[3].get_spelling());
+	    vals.push(val.to_literal());
 	}
 
-	json_output.defines.insert(name, HWDefine {
+	json_output.defines.insert(name.clone(), HWDefine {
 	    hwtype,
 	    vals,
+	    expr: expr_text(macros.get_body(&name).unwrap()),
+	    value: value.map(|v| v.value),
+	    width: value.map_or(0, |v| v.width),
+	    signed: value.map_or(false, |v| v.signed),
+	    enum_name: "".to_string(),
+	    source: sources.source_of(&define_),
 	});
     }
 
+    let typedefs = tu.get_entity().get_children().into_iter().filter(|e| {
+        e.get_kind() == EntityKind::TypedefDecl
+    }).collect::<Vec<_>>();
+
     let enums = tu.get_entity().get_children().into_iter().filter(|e| {
 	e.get_kind() == EntityKind::EnumDecl
     }).collect::<Vec<_>>();
 
     for tenum in enums {
-	for child in tenum.get_children() {
-
-	    //	    println!("{:?} {:?}", child.get_display_name(), child.get_enum_constant_value());
-	    if child.get_display_name().unwrap() == "packed" {
-		continue;
-	    }
+	// the constants stay in defines as well for the want lists
+	let key = get_enum_key(&tenum, &typedefs);
+	for child in get_enum_constants(&tenum) {
+	    let (value, width, signed) = enum_constant_value(&tenum, &child);
 	    json_output.defines.insert(child.get_display_name().unwrap(), HWDefine {
 		hwtype: HWDefineType::Value,
 		vals: vec!(child.get_enum_constant_value().unwrap().1.to_string()),
+		expr: value.to_string(),
+		value: Some(value),
+		width,
+		signed,
+		enum_name: key.clone().unwrap_or_default(),
+		source: sources.source_of(&child),
 	    });
 	}
+	if let Some(name) = get_tag_name(&tenum) {
+	    json_output.enums.insert(name, make_hw_enum(&tenum, sources.source_of(&tenum)));
+	}
     }
-    let typedefs = tu.get_entity().get_children().into_iter().filter(|e| {
-        e.get_kind() == EntityKind::TypedefDecl
+    for (name, size, signed, float, boolean) in BUILTIN_SCALARS {
+	json_output.scalars.insert(name.to_string(), HWScalar::builtin(name, *size, *signed, *float, *boolean));
+    }
+
+    // tagged records, the typedefs of them are added below
+    let records = tu.get_entity().get_children().into_iter().filter(|e| {
+	e.get_kind() == EntityKind::StructDecl || e.get_kind() == EntityKind::UnionDecl
     }).collect::<Vec<_>>();
 
-    for typedef in typedefs {
+    for record in records {
+	let tag = match get_tag_name(&record) {
+	    Some(x) => { x }
+	    None => { continue }
+	};
+	let source = sources.source_of(&record.get_definition().unwrap_or(record));
+	json_output.structs.insert(tag.clone(), make_hw_struct(&record, tag, "".to_string(), source));
+    }
+
+    for &typedef in &typedefs {
 	let under_type = typedef.get_typedef_underlying_type().unwrap();
 
-	if !under_type.is_elaborated().unwrap() {
+	// other names for an enum point at the one it is recorded under
+	if under_type.get_canonical_type().get_kind() == TypeKind::Enum {
+	    let tenum = under_type.get_canonical_type().get_declaration().unwrap();
+	    let thisname = typedef.get_display_name().unwrap();
+	    let mut hwenum = make_hw_enum(&tenum, sources.source_of(&typedef));
+	    let key = get_enum_key(&tenum, &typedefs).unwrap_or_default();
+	    if key != thisname {
+		hwenum.members.clear();
+		hwenum.alias_of = key;
+	    }
+	    json_output.enums.insert(thisname, hwenum);
 	    continue
 	}
 
-	let elab_type = under_type.get_elaborated_type().unwrap();
-	if elab_type.get_kind() != TypeKind::Record {
+	let thisname = typedef.get_display_name().unwrap();
+	if let Some(scalar) = make_hw_scalar(&thisname, under_type, sources.source_of(&typedef)) {
+	    json_output.scalars.insert(thisname, scalar);
 	    continue
 	}
 
-	let mut newfields : Vec<HWStructField> = Default::default();
-
-	let base_offset = 0;
-	let decl = elab_type.get_declaration().unwrap();
-	handle_record(base_offset, &mut newfields, decl.get_type().unwrap().get_fields().unwrap(), "");
-	let total_size = match decl.get_type().unwrap().get_sizeof() {
-	    Ok(x) => { x * 8 }
-	    Err(_) => { 0 }
-	};
-
-	if total_size == 0 {
-	    continue;
+	if under_type.get_canonical_type().get_kind() != TypeKind::Record {
+	    continue
 	}
-	let thisname = typedef.get_display_name().unwrap();
+
+	let record = under_type.get_canonical_type().get_declaration().unwrap();
+	let tag = get_tag_name(&record).unwrap_or_default();
+	let alias_of = get_typedef_alias(under_type, &tag, &thisname);
 	json_output.structs.insert(thisname,
-				   HWStruct {
-				       total_size: total_size as u32,
-				       fields: newfields,
-				   });
+				   make_hw_struct(&record, tag, alias_of, sources.source_of(&typedef)));
     }
     Ok(())
 }
 
-fn add_file_to_cjson<'a>(tu: &TranslationUnit<'a>, json_output: &mut CJson) -> std::io::Result<()> {
+fn make_c_struct(record: &Entity, tag: String, alias_of: String, source: Option<SourceInfo>) -> CTypes {
+    let is_union = record.get_kind() == EntityKind::UnionDecl;
+    let decl = match record.get_definition() {
+	Some(x) => { x }
+	None => {
+	    return CTypes {
+		ctype: CType::Struct,
+		vals: vec!("".to_string()),
+		is_anon_struct: false,
+		is_union,
+		tag,
+		alias_of,
+		opaque: true,
+		source,
+		..Default::default()
+	    };
+	}
+    };
+
+    let mut newfields : Vec<CStructField> = Default::default();
+    handle_c_parser_record(&mut newfields, decl.get_type().unwrap().get_fields().unwrap(), "");
+    let total_size = match decl.get_type().unwrap().get_sizeof() {
+	Ok(x) => { x * 8 }
+	Err(_) => { 0 }
+    };
+
+    let (alignment, packed) = get_record_packing(&decl);
+    CTypes {
+	ctype: CType::Struct,
+	vals: vec!("".to_string()),
+	is_anon_struct: false,
+	fields: newfields,
+	alignment: alignment as u32,
+	packed: packed,
+	is_union,
+	tag,
+	alias_of,
+	opaque: total_size == 0,
+	source,
+	..Default::default()
+    }
+}
+
+fn add_file_to_cjson<'a>(tu: &TranslationUnit<'a>, macros: &MacroTable, sources: &Provenance, json_output: &mut CJson) -> std::io::Result<()> {
     // Get the declearations?
     let defines = tu.get_entity().get_children().into_iter().filter(|e| {
 	e.get_kind() == EntityKind::MacroDefinition &&
@@ -575,74 +1108,93 @@ fn add_file_to_cjson<'a>(tu: &TranslationUnit<'a>, json_output: &mut CJson) -> s
 	    continue;
 	}
 	let tokens = define_.get_range().unwrap().tokenize();
-	if tokens.len() != 2 && tokens.len() != 4 && tokens.len() != 6 && tokens.len() != 10 {
+	if tokens.len() < 2 {
 	    continue;
 	}
 
-	let mut vals: Vec<String> = Default::default();
-	if tokens.len() == 10 &&
-	    tokens[1].get_spelling() == "(" && tokens[9].get_spelling() == ")" &&
-	    tokens[4].get_spelling() == "<<" && tokens[7].get_spelling() == "*" {
-		ctype = CType::Value;
-		vals.push("(".to_owned() + &tokens[3].get_spelling() + "<<" + &tokens[5].get_spelling() + ") * " + &tokens[8].get_spelling());
-	    }
-	else if tokens.len() == 6 &&
-	    tokens[1].get_spelling() == "(" && tokens[5].get_spelling() == ")" &&
-	    tokens[3].get_spelling() == "<<" {
-		ctype = CType::Value;
-		vals.push(tokens[2].get_spelling() + "<<" + &tokens[4].get_spelling());
-	    }	
-	if tokens.len() == 2 {
+	let mut vals = simple_define_vals(&tokens);
+	if vals.len() > 0 {
 	    ctype = CType::Value;
-	    vals.push(tokens[1].get_spelling());
-	} else if tokens[1].get_spelling() == "(" && tokens[3].get_spelling() == ")" {
-	    ctype = CType::Value;
-	    vals.push(tokens[2].get_spelling());
-	} else if tokens[2].get_spelling() == ":" {
+	}
+	let value = macros.evaluate(&name);
+	if let (CType::Unknown, Some(val)) = (&ctype, value) {
 	    ctype = CType::Value;
-	    vals.push(tokens[1].get_spelling());
-	    vals.push(tokens[3].get_spelling());
+	    vals.push(val.to_literal());
 	}
 
-	json_output.types.insert(name, CTypes {
+	json_output.types.insert(name.clone(), CTypes {
 	    ctype,
 	    vals,
-	    is_anon_struct: false,
-	    fields: Default::default(),
+	    expr: expr_text(macros.get_body(&name).unwrap()),
+	    value: value.map(|v| v.value),
+	    width: value.map_or(0, |v| v.width),
+	    signed: value.map_or(false, |v| v.signed),
+	    source: sources.source_of(&define_),
+	    ..Default::default()
 	});
     }
 
+    let typedefs = tu.get_entity().get_children().into_iter().filter(|e| {
+        e.get_kind() == EntityKind::TypedefDecl
+    }).collect::<Vec<_>>();
+
     let enums = tu.get_entity().get_children().into_iter().filter(|e| {
 	e.get_kind() == EntityKind::EnumDecl
     }).collect::<Vec<_>>();
 
     for tenum in enums {
-	for child in tenum.get_children() {
-	    if child.get_display_name().unwrap() == "packed" {
-		continue;
-	    }
+	let key = get_enum_key(&tenum, &typedefs);
+	for child in get_enum_constants(&tenum) {
+	    let (value, width, signed) = enum_constant_value(&tenum, &child);
 	    json_output.types.insert(child.get_display_name().unwrap(), CTypes {
 		ctype: CType::Value,
 		vals: vec!(child.get_enum_constant_value().unwrap().1.to_string()),
 		is_anon_struct: false,
 		fields: Default::default(),
+		expr: value.to_string(),
+		value: Some(value),
+		width,
+		signed,
+		enum_name: key.clone().unwrap_or_default(),
+		source: sources.source_of(&child),
+		..Default::default()
 	    });
 	}
-	json_output.types.insert(tenum.get_display_name().unwrap(), CTypes {
-	    ctype: CType::Typedef,
-	    vals: vec!("u32".to_string()),
-	    is_anon_struct: false,
-	    fields: Default::default(),
-	});
+	if let Some(name) = get_tag_name(&tenum) {
+	    json_output.types.insert(name, make_c_enum(&tenum, sources.source_of(&tenum)));
+	}
     }
 
-    let typedefs = tu.get_entity().get_children().into_iter().filter(|e| {
-        e.get_kind() == EntityKind::TypedefDecl
+    let records = tu.get_entity().get_children().into_iter().filter(|e| {
+	e.get_kind() == EntityKind::StructDecl || e.get_kind() == EntityKind::UnionDecl
     }).collect::<Vec<_>>();
 
-    for typedef in typedefs {
+    for record in records {
+	let tag = match get_tag_name(&record) {
+	    Some(x) => { x }
+	    None => { continue }
+	};
+	let source = sources.source_of(&record.get_definition().unwrap_or(record));
+	json_output.types.insert(tag.clone(), make_c_struct(&record, tag, "".to_string(), source));
+    }
+
+    for &typedef in &typedefs {
 	let under_type = typedef.get_typedef_underlying_type().unwrap();
 
+	// as for the HW database
+	if under_type.get_canonical_type().get_kind() == TypeKind::Enum {
+	    let tenum = under_type.get_canonical_type().get_declaration().unwrap();
+	    let thisname = typedef.get_display_name().unwrap();
+	    let mut cenum = make_c_enum(&tenum, sources.source_of(&typedef));
+	    let key = get_enum_key(&tenum, &typedefs).unwrap_or_default();
+	    if key != thisname {
+		cenum.members.clear();
+		cenum.alias_of = key;
+	    }
+	    json_output.types.insert(thisname, cenum);
+	    continue
+	}
+
 	if !under_type.is_elaborated().unwrap() {
 	    continue
 	}
@@ -651,166 +1203,304 @@ fn add_file_to_cjson<'a>(tu: &TranslationUnit<'a>, json_output: &mut CJson) -> s
 	if elab_type.get_kind() != TypeKind::Record {
 //	    println!("typedef {:?} {:?}", typedef, under_type);
 
-	    if elab_type.get_kind() != TypeKind::Enum {
-		json_output.types.insert(typedef.get_display_name().unwrap(),
-					 CTypes {
-					     ctype: CType::Typedef,
-					     vals: vec!(elab_type.get_display_name()),
-					     is_anon_struct: false,
-					     fields: Default::default(),
-					 });
-	    }
+	    json_output.types.insert(typedef.get_display_name().unwrap(),
+				     CTypes {
+					 ctype: CType::Typedef,
+					 vals: vec!(elab_type.get_display_name()),
+					 is_anon_struct: false,
+					 fields: Default::default(),
+					 source: sources.source_of(&typedef),
+					 ..Default::default()
+				     });
 	    continue
 	}
 
-	let mut newfields : Vec<CStructField> = Default::default();
-
-	let decl = elab_type.get_declaration().unwrap();
-	handle_c_parser_record(&mut newfields, decl.get_type().unwrap().get_fields().unwrap(), "");
-	let total_size = match decl.get_type().unwrap().get_sizeof() {
-	    Ok(x) => { x * 8 }
-	    Err(_) => { 0 }
-	};
-
-	if total_size == 0 {
-	    continue;
-	}
+	let record = elab_type.get_declaration().unwrap();
 	let thisname = typedef.get_display_name().unwrap();
+	let tag = get_tag_name(&record).unwrap_or_default();
+	let alias_of = get_typedef_alias(under_type, &tag, &thisname);
 	json_output.types.insert(thisname,
-				 CTypes {
-				     ctype: CType::Struct,
-				     vals: vec!("".to_string()),
-				     is_anon_struct: false,
-				     fields: newfields,
-				 });
+				 make_c_struct(&record, tag, alias_of, sources.source_of(&typedef)));
     }
 
     Ok(())
 }
 
-fn just_headers(entry: &DirEntry) -> bool {
-    entry.file_name()
-         .to_str()
-         .map(|s| s.ends_with(".h"))
-         .unwrap_or(false)
-}
-
-const INCFILES: &'static [&'static str] = &[
-    "stddef.h",
-    "cpuopsys.h",
-    "gpu/mem_mgr/mem_desc.h",
-    "vgpu/sdk-structures.h",
-    "g_rpc-structures.h",
-    "g_rpc-message-header.h",
-    "objrpc.h",
-];
-
-const INCPATHS: &'static [&'static str] = &[
-    "src/common/inc",
-    "src/common/inc/swref/published",
-    "src/common/nvlink/inbound/interface",
-    "src/common/shared/msgq/inc",
-    "src/common/sdk/nvidia/inc",
-    "src/common/shared/msgq/inc/msgq",
-    "src/nvidia/",
-    "src/nvidia/generated/",
-    "src/nvidia/inc/",
-    "src/nvidia/inc/kernel/",
-    "src/nvidia/inc/libraries/",
-    "src/nvidia/interface/",
-    "src/nvidia/kernel/inc",
-    "src/nvidia/arch/nvalloc/common/inc",
-    "src/nvidia/arch/nvalloc/common/inc/gsp",
-];
-
-const DEFINES: &'static [&'static str] = &[
-    "-DRPC_MESSAGE_GENERIC_UNION",
-    "-DRPC_MESSAGE_STRUCTURES",
-    "-DRPC_STRUCTURES",
-    "-DRPC_GENERIC_UNION",
-    "-DPORT_MODULE_memory=1",
-    "-DPORT_MODULE_cpu=1",
-    "-DPORT_MODULE_core=1",
-    "-DPORT_MODULE_debug=1",
-    "-DPORT_MODULE_util=1",
-    "-DPORT_MODULE_safe=1",
-    "-DPORT_MODULE_thread=1",
-    "-DNVRM",
-    "-D_LANGUAGE_C",
-    "-D__NO_CTYPE",
-    "-DRS_STANDALONE=0",
-    "-DPORT_IS_CHECKED_BUILD=1",
-    "-DPORT_IS_KERNEL_BUILD=1",
-];
-
-const PATHS: &'static [&'static str] = &[
-    "src/common/sdk/nvidia/inc",
-    "src/common/inc/swref/published/",
-    "src/common/shared/msgq/inc/msgq",
-    "src/nvidia/inc/kernel/gpu/gsp/",
-    "src/nvidia/arch/nvalloc/common/inc/",
-    "src/nvidia/kernel/inc/vgpu/",
-    "src/common/uproc/os/common/include/",
-    "src/nvidia/generated/",
-];
+// everything extracted from one header, merged into the databases
+// in header order so the output doesn't depend on thread scheduling.
+#[derive(Serialize, Deserialize)]
+struct HeaderResult {
+    cjson: CJson,
+    hwjson: HWJson,
+    report: HeaderReport,
+}
+
+// parse a header and store the result in the cache if there is one
+fn parse_header(index: &Index, path: &str, source: &SourceTree, settings: &ExtractSettings, cache: Option<&Cache>) -> std::io::Result<HeaderResult> {
+    let mut result = HeaderResult {
+	cjson: Default::default(),
+	hwjson: Default::default(),
+	report: Default::default(),
+    };
+
+    let header = relative_path(Path::new(&source.root), Path::new(path));
+    let unsaved = source.unsaved_for(path, settings);
+    let tu = match setup_parser(index, path, &source.root, settings, &unsaved) {
+	Ok(x) => { x }
+	Err(e) => {
+	    result.report = HeaderReport::failed(&header, &e);
+	    return Ok(result);
+	}
+    };
+    let macros = collect_macros(&tu);
+    let sources = Provenance::new(&tu, &source.root);
+    result.report = HeaderReport::from_tu(&header, &tu, &sources);
+    add_file_to_cjson(&tu, &macros, &sources, &mut result.cjson)?;
+    add_file_to_hwjson(&tu, &macros, &sources, &mut result.hwjson)?;
+
+    if let Some(cache) = cache {
+	let include_dirs: Vec<PathBuf> = settings.include_paths.iter().map(|x| Path::new(&source.root).join(x)).collect();
+	cache.store(path, &settings.clang_args(&source.root), &cache::dependency_files(&tu, path, &include_dirs), &result)?;
+    }
+    Ok(result)
+}
 
 fn main() -> std::io::Result<()> {
-    let args: Vec<String> = env::args().collect();
+    let opts = config::parse_args(env::args())?;
 
     // Acquire an instance of `Clang`
     let clang = Clang::new().unwrap();
 
-    // Create a new `Index`
-    let index = Index::new(&clang, false, false);
-
     let mut cjson_output : CJson = Default::default();
     let mut hwjson_output : HWJson = Default::default();
 
-    cjson_output.version = args[1].clone();
-    hwjson_output.version = args[1].clone();
+    cjson_output.version = opts.version.clone();
+    hwjson_output.version = opts.version.clone();
+    cjson_output.schema_version = SCHEMA_VERSION;
+    hwjson_output.schema_version = SCHEMA_VERSION;
+    let mut report = ExtractReport::new(&opts.version);
+
+    let cache = match &opts.cache {
+	Some(x) => { Some(Cache::new(x)?) }
+	None => { None }
+    };
+
+    let source = SourceTree::open(&opts.tree, opts.git_rev.as_ref(), &opts.settings)?;
+    if let Some(cache) = &cache {
+	for (path, contents) in source.files() {
+	    cache.add_file(path, contents.as_bytes());
+	}
+    }
+    let headers = source.headers(&opts.settings.scan_paths);
+
+    // clang-rs allows a single Clang, which can't be shared with other
+    // threads, so headers are parsed one at a time on this thread in
+    // header order. Workers look the headers up in the cache ahead of
+    // it, results that arrive ahead of an earlier header wait in
+    // pending and the bounded channel stops the workers from running
+    // far ahead of the merge.
+    let index = Index::new(&clang, false, false);
+    let args = opts.settings.clang_args(&source.root);
+    let next_header = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::sync_channel(opts.jobs);
+    let conflicts = thread::scope(|scope| -> std::io::Result<Conflicts> {
+	for _ in 0..opts.jobs {
+	    let sender = sender.clone();
+	    let headers = &headers;
+	    let next_header = &next_header;
+	    let args = &args;
+	    let cache = cache.as_ref();
+	    scope.spawn(move || {
+		loop {
+		    let idx = next_header.fetch_add(1, Ordering::Relaxed);
+		    if idx >= headers.len() {
+			break;
+		    }
+		    let cached = cache.and_then(|c| c.lookup(&headers[idx], args));
+		    if sender.send((idx, cached)).is_err() {
+			break;
+		    }
+		}
+	    });
+	}
+	drop(sender);
+
+	let mut conflicts: Conflicts = Default::default();
+	let mut pending: BTreeMap<usize, Option<HeaderResult>> = Default::default();
+	let mut next_merge = 0;
+	// on an error the receiver is dropped, which stops the workers
+	for (idx, cached) in receiver {
+	    pending.insert(idx, cached);
+	    while let Some(cached) = pending.remove(&next_merge) {
+		let result = match cached {
+		    Some(x) => { x }
+		    None => {
+			println!("parsing {:?}", headers[next_merge]);
+			parse_header(&index, &headers[next_merge], &source, &opts.settings, cache.as_ref())?
+		    }
+		};
+		result.report.print_errors();
+		report.add(result.report);
+		conflicts.merge("type", &mut cjson_output.types, result.cjson.types);
+		conflicts.merge("define", &mut hwjson_output.defines, result.hwjson.defines);
+		conflicts.merge("struct", &mut hwjson_output.structs, result.hwjson.structs);
+		conflicts.merge("enum", &mut hwjson_output.enums, result.hwjson.enums);
+		conflicts.merge("scalar", &mut hwjson_output.scalars, result.hwjson.scalars);
+		next_merge += 1;
+	    }
+	}
+	Ok(conflicts)
+    })?;
+    report.set_conflicts(conflicts.into_report());
 
-    for path in PATHS {
-	let newpath = args[2].clone() + "/" + path;
-	for entry in WalkDir::new(&newpath).into_iter() {
+    let reportname = opts.outdir.clone() + "/" + &opts.version + ".report.json";
+    let file = File::create(reportname)?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, &report)?;
+    writer.flush()?;
 
-	    let ent = match entry {
-		Err(_) => { continue; }
-		Ok(x) => { x }
-	    };
+    if report.headers_with_errors() > 0 {
+	eprintln!("{} headers had errors, see {}.report.json", report.headers_with_errors(), opts.version);
+	if opts.strict {
+	    return Err(Error::new(ErrorKind::Other, "headers failed to parse in strict mode"));
+	}
+    }
 
-	    if !just_headers(&ent) {
-		continue
-	    }
-	    let path = ent.path().to_str().unwrap();
+    if report.conflict_count() > 0 {
+	eprintln!("{} symbols have conflicting definitions, see {}.report.json", report.conflict_count(), opts.version);
+	if opts.strict {
+	    return Err(Error::new(ErrorKind::Other, "conflicting definitions in strict mode"));
+	}
+    }
 
-	    if path != "/home/airlied/devel/open-gpu-kernel-modules//src/nvidia/inc/kernel/gpu/gsp/message_queue_priv.h" && path != "/home/airlied/devel/open-gpu-kernel-modules//src/nvidia/arch/nvalloc/common/inc/gsp/gsp_fw_wpr_meta.h" {
-//		continue
-	    }
+    let mut problems = cjson_output.validate();
+    problems.extend(hwjson_output.validate());
+    for problem in &problems {
+	eprintln!("{}: {}", opts.version, problem);
+    }
 
-	    if path != "/home/airlied/devel/open-gpu-kernel-modules//src/nvidia/inc/kernel/gpu/gsp/gsp_fw_heap.h" {
-//		continue
-	    }
-	    if path != "/home/airlied/devel/gsp-parse/gitrepo/open-gpu-kernel-modules/src/common/sdk/nvidia/inc/ctrl/ctrl90f1.h" {
-//		continue
-	    }
-	    println!("parsing {:?}", path);
+    let ext = opts.format.extension();
+    Database::C(cjson_output).save_as(&format!("{}/{}.{}", opts.outdir, opts.version, ext), opts.format)?;
+    Database::HW(hwjson_output).save_as(&format!("{}/{}.hw.{}", opts.outdir, opts.version, ext), opts.format)?;
+    Ok(())
+}
 
-	    let tu = setup_parser(&index, path, &args[2])?;
-	    add_file_to_cjson(&tu, &mut cjson_output)?;
-	    add_file_to_hwjson(&tu, &mut hwjson_output)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // clang-rs allows one Clang at a time
+    static CLANG_LOCK: Mutex<()> = Mutex::new(());
+    static NEXT_TREE: AtomicUsize = AtomicUsize::new(0);
+
+    // a fresh directory holding the given files
+    pub fn scratch_tree(files: &[(&str, &str)]) -> PathBuf {
+	let tree = std::env::temp_dir().join(format!("gsp-parse-test-{}-{}", std::process::id(), NEXT_TREE.fetch_add(1, Ordering::Relaxed)));
+	let _ = std::fs::remove_dir_all(&tree);
+	for (name, contents) in files {
+	    let path = tree.join(name);
+	    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+	    std::fs::write(path, contents).unwrap();
 	}
+	tree
     }
 
-    let cjsonname = args[3].clone() + "/" + &args[1].clone() + ".json";
-    let file = File::create(cjsonname)?;
-    let mut writer = BufWriter::new(file);
-    serde_json::to_writer_pretty(&mut writer, &cjson_output)?;
-    writer.flush()?;
+    // parse header in tree with settings, through cache if given
+    pub fn extract_in(tree: &Path, header: &str, settings: &ExtractSettings, cache: Option<&Cache>) -> HeaderResult {
+	let _lock = CLANG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+	let clang = Clang::new().unwrap();
+	let index = Index::new(&clang, false, false);
+	let source = SourceTree::dir(tree.to_str().unwrap());
+	let path = tree.join(header).to_string_lossy().to_string();
+	let args = settings.clang_args(&source.root);
+	if let Some(x) = cache.and_then(|c| c.lookup(&path, &args)) {
+	    return x;
+	}
+	parse_header(&index, &path, &source, settings, cache).unwrap()
+    }
 
-    let hwjsonname = args[3].clone() + "/" + &args[1].clone() + ".hw.json";
-    let file = File::create(hwjsonname)?;
-    let mut writer = BufWriter::new(file);
-    serde_json::to_writer_pretty(&mut writer, &hwjson_output)?;
-    writer.flush()?;
-    Ok(())
+    pub fn extract(contents: &str) -> HeaderResult {
+	let tree = scratch_tree(&[("test.h", contents)]);
+	let result = extract_in(&tree, "test.h", &Default::default(), None);
+	std::fs::remove_dir_all(tree).unwrap();
+	result
+    }
+
+    #[test]
+    fn flexible_and_zero_length_arrays() {
+	let result = extract("typedef struct { unsigned int count; unsigned int entries[]; } FLEX;\n\
+			      typedef struct { unsigned int count; unsigned int none[0]; } ZERO;\n");
+	for name in ["FLEX", "ZERO"] {
+	    let hwstruct = result.hwjson.get_struct(name).unwrap();
+	    assert_eq!(hwstruct.total_size, 32);
+	    assert_eq!(hwstruct.field("count").unwrap().start, 0);
+	    assert!(!hwstruct.packed);
+	}
+    }
+
+    #[test]
+    fn pragma_pack_and_aligned_fields() {
+	let result = extract("#pragma pack(4)\n\
+			      typedef struct { unsigned int a; unsigned long long b; } PACKED4;\n\
+			      #pragma pack()\n\
+			      typedef struct { unsigned int a; unsigned int b __attribute__((aligned(8))); } ALIGNED;\n");
+	let packed = result.hwjson.get_struct("PACKED4").unwrap();
+	assert!(packed.packed);
+	assert_eq!(packed.alignment, 4);
+	assert_eq!(packed.field("b").unwrap().start, 32);
+	assert_eq!(packed.field("b").unwrap().alignment, 8);
+
+	let aligned = result.hwjson.get_struct("ALIGNED").unwrap();
+	assert!(!aligned.packed);
+	assert_eq!(aligned.alignment, 8);
+	assert_eq!(aligned.field("b").unwrap().start, 64);
+	assert_eq!(aligned.field("b").unwrap().alignment, 8);
+	assert_eq!(aligned.holes, vec![HWHole { start: 32, size: 32 }, HWHole { start: 96, size: 32 }]);
+    }
+
+    #[test]
+    fn enums_are_recorded_once() {
+	let result = extract("typedef enum tag { A = 1, B = 2 } NAME;\n\
+			      typedef enum { C = 3 } UNTAGGED;\n\
+			      enum { D = 4 };\n");
+	let hwjson = &result.hwjson;
+	assert_eq!(hwjson.get_enum("tag").unwrap().members.len(), 2);
+	assert_eq!(hwjson.get_enum("NAME").unwrap().alias_of, "tag");
+	assert!(hwjson.get_enum("NAME").unwrap().members.is_empty());
+	assert_eq!(hwjson.get_enum("UNTAGGED").unwrap().alias_of, "");
+	assert_eq!(hwjson.get_enum("UNTAGGED").unwrap().members.len(), 1);
+
+	assert_eq!(hwjson.get_define("A").unwrap().enum_name, "tag");
+	assert_eq!(hwjson.get_define("C").unwrap().enum_name, "UNTAGGED");
+	assert_eq!(hwjson.get_define("D").unwrap().enum_name, "");
+	assert_eq!(hwjson.get_define("D").unwrap().value, Some(4));
+
+	let cjson = &result.cjson;
+	assert_eq!(cjson.get_type("NAME").unwrap().alias_of, "tag");
+	assert!(cjson.get_type("NAME").unwrap().members.is_empty());
+	assert_eq!(cjson.get_type("A").unwrap().enum_name, "tag");
+	assert_eq!(cjson.get_type("D").unwrap().enum_name, "");
+    }
+
+    #[test]
+    fn union_arrays_have_a_view_per_element() {
+	let result = extract("union U { unsigned int a; unsigned short b; };\n\
+			      struct S { unsigned int x; union U u[2]; };\n");
+	let hwstruct = result.hwjson.get_struct("S").unwrap();
+	assert_eq!(hwstruct.total_size, 96);
+	assert_eq!(hwstruct.field("u_0_a").unwrap().start, 32);
+	assert_eq!(hwstruct.field("u_1_a").unwrap().start, 64);
+	assert_eq!(hwstruct.field("u_1_b").unwrap().start, 64);
+	let views: Vec<(&str, u32)> = hwstruct.unions.iter().map(|u| (u.name.as_str(), u.start)).collect();
+	assert_eq!(views, [("u_0", 32), ("u_1", 64)]);
+    }
+
+    #[test]
+    fn untagged_typedefs_are_not_anonymous_members() {
+	let result = extract("typedef struct { unsigned int a; } UNTAGGED;\n");
+	let ctype = result.cjson.get_type("UNTAGGED").unwrap();
+	assert_eq!(ctype.tag, "");
+	assert!(!ctype.is_anon_struct);
+    }
 }
@@ -0,0 +1,147 @@
+// Merge per header results into the databases.
+//
+// Most definitions are seen many times, once for every header that
+// includes the one defining them, and those copies are identical. A
+// define or type given a different value or layout by two headers is
+// a conflict: every variant is recorded for the report, and the one
+// from the first source file (by path, then line) is kept so what ends
+// up in the database doesn't depend on the order headers were scanned.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::report::{Conflict, ConflictVariant};
+use gsp_parse::SourceInfo;
+
+pub trait Sourced {
+    fn source_mut(&mut self) -> &mut Option<SourceInfo>;
+    // a declaration without a layout, any definition replaces it
+    fn is_opaque(&self) -> bool { false }
+}
+
+// compare two definitions ignoring where they came from
+fn same_definition<T: Sourced + PartialEq>(a: &mut T, b: &mut T) -> bool {
+    let a_source = a.source_mut().take();
+    let b_source = b.source_mut().take();
+    let same = a == b;
+    *a.source_mut() = a_source;
+    *b.source_mut() = b_source;
+    same
+}
+
+// definitions without a location sort after everything else
+fn source_key(source: &Option<SourceInfo>) -> (bool, String, u32) {
+    match source {
+	Some(x) => { (false, x.file.clone(), x.line) }
+	None => { (true, "".to_string(), 0) }
+    }
+}
+
+fn make_variant<T: Sourced + Serialize>(def: &mut T) -> ConflictVariant {
+    let source = def.source_mut().take();
+    let definition = serde_json::to_value(&*def).unwrap_or_default();
+    *def.source_mut() = source.clone();
+    ConflictVariant { source, definition }
+}
+
+#[derive(Default)]
+pub struct Conflicts {
+    // keyed on kind and name
+    variants: BTreeMap<(String, String), Vec<ConflictVariant>>,
+}
+
+impl Conflicts {
+    fn add_variant(&mut self, kind: &str, name: &str, variant: ConflictVariant) {
+	let variants = self.variants.entry((kind.to_string(), name.to_string())).or_default();
+	if !variants.iter().any(|v| v.definition == variant.definition) {
+	    variants.push(variant);
+	}
+    }
+
+    pub fn merge<T: Sourced + PartialEq + Serialize>(&mut self, kind: &str,
+						      output: &mut BTreeMap<String, T>,
+						      input: BTreeMap<String, T>) {
+	for (name, mut def) in input {
+	    let existing = match output.get_mut(&name) {
+		Some(x) => { x }
+		None => {
+		    output.insert(name, def);
+		    continue;
+		}
+	    };
+	    if def.is_opaque() {
+		continue;
+	    }
+	    if existing.is_opaque() {
+		*existing = def;
+		continue;
+	    }
+	    if same_definition(existing, &mut def) {
+		continue;
+	    }
+	    self.add_variant(kind, &name, make_variant(existing));
+	    self.add_variant(kind, &name, make_variant(&mut def));
+	    if source_key(&def.source_mut()) < source_key(&existing.source_mut()) {
+		*existing = def;
+	    }
+	}
+    }
+
+    pub fn into_report(self) -> Vec<Conflict> {
+	self.variants.into_iter().map(|((kind, name), variants)| {
+	    Conflict { kind, name, variants }
+	}).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gsp_parse::{HWDefine, HWStruct};
+
+    fn define(value: i128, file: &str) -> HWDefine {
+	let source = SourceInfo { file: file.to_string(), line: 1, include_chain: Default::default() };
+	HWDefine { value: Some(value), source: Some(source), ..Default::default() }
+    }
+
+    fn defines(entries: &[(&str, HWDefine)]) -> BTreeMap<String, HWDefine> {
+	entries.iter().map(|(name, x)| (name.to_string(), x.clone())).collect()
+    }
+
+    #[test]
+    fn identical_copies_are_not_conflicts() {
+	let mut conflicts: Conflicts = Default::default();
+	let mut output = defines(&[("A", define(1, "a.h"))]);
+	conflicts.merge("define", &mut output, defines(&[("A", define(1, "b.h"))]));
+	assert!(conflicts.into_report().is_empty());
+	assert_eq!(output["A"].source.as_ref().unwrap().file, "a.h");
+    }
+
+    #[test]
+    fn first_source_file_wins() {
+	for (first, second) in [("a.h", "b.h"), ("b.h", "a.h")] {
+	    let mut conflicts: Conflicts = Default::default();
+	    let value = |file| if file == "a.h" { 1 } else { 2 };
+	    let mut output = defines(&[("A", define(value(first), first))]);
+	    conflicts.merge("define", &mut output, defines(&[("A", define(value(second), second))]));
+	    assert_eq!(output["A"].value, Some(1));
+
+	    let report = conflicts.into_report();
+	    assert_eq!(report.len(), 1);
+	    assert_eq!((report[0].kind.as_str(), report[0].name.as_str()), ("define", "A"));
+	    assert_eq!(report[0].variants.len(), 2);
+	}
+    }
+
+    #[test]
+    fn definitions_replace_declarations() {
+	let declared = HWStruct { opaque: true, ..Default::default() };
+	let defined = HWStruct { total_size: 32, ..Default::default() };
+	let mut conflicts: Conflicts = Default::default();
+	let mut output: BTreeMap<String, HWStruct> = [("S".to_string(), declared.clone())].into_iter().collect();
+	conflicts.merge("struct", &mut output, [("S".to_string(), defined.clone())].into_iter().collect());
+	conflicts.merge("struct", &mut output, [("S".to_string(), declared)].into_iter().collect());
+	assert_eq!(output["S"], defined);
+	assert!(conflicts.into_report().is_empty());
+    }
+}
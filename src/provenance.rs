@@ -0,0 +1,76 @@
+// Work out where in the NVIDIA tree a symbol was defined and the
+// chain of #includes that pulled that header into the translation unit.
+
+use clang::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use gsp_parse::SourceInfo;
+
+// paths inside the tree are made relative to its root, system
+// headers are left alone.
+pub fn relative_path(root: &Path, path: &Path) -> String {
+    match path.strip_prefix(root) {
+	Ok(x) => { x.to_string_lossy().to_string() }
+	Err(_) => { path.to_string_lossy().to_string() }
+    }
+}
+
+pub struct Provenance {
+    root: PathBuf,
+    // each header and the file that first included it
+    includers: HashMap<PathBuf, PathBuf>,
+}
+
+impl Provenance {
+    pub fn new<'a>(tu: &TranslationUnit<'a>, root: &str) -> Provenance {
+	let mut includers: HashMap<PathBuf, PathBuf> = Default::default();
+
+	for incl in tu.get_entity().get_children() {
+	    if incl.get_kind() != EntityKind::InclusionDirective {
+		continue;
+	    }
+	    let included = match incl.get_file() {
+		Some(x) => { x.get_path() }
+		None => { continue; }
+	    };
+	    let includer = match incl.get_location().and_then(|l| l.get_file_location().file) {
+		Some(x) => { x.get_path() }
+		None => { continue; }
+	    };
+	    includers.entry(included).or_insert(includer);
+	}
+	Provenance { root: PathBuf::from(root), includers }
+    }
+
+    pub fn relative(&self, path: &Path) -> String {
+	relative_path(&self.root, path)
+    }
+
+    // outermost file first, not including the file itself
+    fn include_chain(&self, path: &Path) -> Vec<String> {
+	let mut chain: Vec<String> = Default::default();
+	let mut cur = path;
+	while let Some(parent) = self.includers.get(cur) {
+	    let parent_str = self.relative(parent);
+	    // guard against include loops of headers without guards
+	    if chain.contains(&parent_str) || parent == path {
+		break;
+	    }
+	    chain.push(parent_str);
+	    cur = parent;
+	}
+	chain.reverse();
+	chain
+    }
+
+    pub fn source_of(&self, entity: &Entity) -> Option<SourceInfo> {
+	let location = entity.get_location()?.get_file_location();
+	let path = location.file?.get_path();
+	Some(SourceInfo {
+	    file: self.relative(&path),
+	    line: location.line,
+	    include_chain: self.include_chain(&path),
+	})
+    }
+}
@@ -0,0 +1,158 @@
+// The extraction report, clang diagnostics for every scanned header so
+// a header that failed to parse (missing include, unknown type) shows
+// up instead of silently leaving holes in the database.
+
+use clang::*;
+use clang::diagnostic::Severity;
+use serde::{Deserialize, Serialize};
+
+use crate::provenance::Provenance;
+use gsp_parse::SourceInfo;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, PartialOrd)]
+pub enum DiagSeverity {
+    Note,
+    Warning,
+    Error,
+    Fatal,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DiagnosticInfo {
+    severity: DiagSeverity,
+    file: String,
+    line: u32,
+    column: u32,
+    text: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct HeaderReport {
+    header: String,
+    // set when libclang didn't produce a translation unit at all
+    parse_error: Option<String>,
+    notes: u32,
+    warnings: u32,
+    // fatal diagnostics count as errors too
+    errors: u32,
+    diagnostics: Vec<DiagnosticInfo>,
+}
+
+// one of the definitions seen for a conflicting symbol
+#[derive(Serialize, Deserialize)]
+pub struct ConflictVariant {
+    pub source: Option<SourceInfo>,
+    pub definition: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Conflict {
+    // define, struct, enum or type (for the C database)
+    pub kind: String,
+    pub name: String,
+    pub variants: Vec<ConflictVariant>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct ExtractReport {
+    version: String,
+    headers_scanned: u32,
+    headers_with_errors: u32,
+    total_warnings: u32,
+    total_errors: u32,
+    headers: Vec<HeaderReport>,
+    // symbols defined differently by different headers
+    #[serde(default)]
+    conflicts: Vec<Conflict>,
+}
+
+impl HeaderReport {
+    pub fn from_tu<'a>(header: &str, tu: &TranslationUnit<'a>, sources: &Provenance) -> HeaderReport {
+	let mut report = HeaderReport { header: header.to_string(), ..Default::default() };
+
+	for diag in tu.get_diagnostics() {
+	    let severity = match diag.get_severity() {
+		Severity::Ignored => { continue; }
+		Severity::Note => { report.notes += 1; DiagSeverity::Note }
+		Severity::Warning => { report.warnings += 1; DiagSeverity::Warning }
+		Severity::Error => { report.errors += 1; DiagSeverity::Error }
+		Severity::Fatal => { report.errors += 1; DiagSeverity::Fatal }
+	    };
+	    let location = diag.get_location().get_file_location();
+	    let file = match location.file {
+		Some(x) => { sources.relative(&x.get_path()) }
+		None => { "".to_string() }
+	    };
+	    report.diagnostics.push(DiagnosticInfo {
+		severity,
+		file,
+		line: location.line,
+		column: location.column,
+		text: diag.get_text(),
+	    });
+	}
+	report
+    }
+
+    pub fn failed(header: &str, error: &SourceError) -> HeaderReport {
+	HeaderReport {
+	    header: header.to_string(),
+	    parse_error: Some(error.to_string()),
+	    errors: 1,
+	    ..Default::default()
+	}
+    }
+
+    pub fn has_errors(&self) -> bool {
+	self.errors > 0
+    }
+
+    // one line per error on stderr so problems show up in the build log
+    pub fn print_errors(&self) {
+	if let Some(err) = &self.parse_error {
+	    eprintln!("{}: failed to parse: {}", self.header, err);
+	}
+	for diag in &self.diagnostics {
+	    if diag.severity >= DiagSeverity::Error {
+		eprintln!("{}: {}:{}:{}: {}", self.header, diag.file, diag.line, diag.column, diag.text);
+	    }
+	}
+    }
+}
+
+impl ExtractReport {
+    pub fn new(version: &str) -> ExtractReport {
+	ExtractReport { version: version.to_string(), ..Default::default() }
+    }
+
+    pub fn add(&mut self, header: HeaderReport) {
+	self.headers_scanned += 1;
+	if header.has_errors() {
+	    self.headers_with_errors += 1;
+	}
+	self.total_warnings += header.warnings;
+	self.total_errors += header.errors;
+	self.headers.push(header);
+    }
+
+    pub fn headers_with_errors(&self) -> u32 {
+	self.headers_with_errors
+    }
+
+    pub fn set_conflicts(&mut self, conflicts: Vec<Conflict>) {
+	for conflict in &conflicts {
+	    eprintln!("conflicting definitions of {} {}:", conflict.kind, conflict.name);
+	    for variant in &conflict.variants {
+		match &variant.source {
+		    Some(x) => { eprintln!("    {}", x); }
+		    None => { eprintln!("    (unknown location)"); }
+		}
+	    }
+	}
+	self.conflicts = conflicts;
+    }
+
+    pub fn conflict_count(&self) -> usize {
+	self.conflicts.len()
+    }
+}
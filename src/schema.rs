@@ -0,0 +1,824 @@
+// The jsondb schema: the C database (<version>.json) written for
+// generate, the HW database (<version>.hw.json) written for generate_hw
+// and generate_rust, and the want lists naming what to generate.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde::de::{DeserializeOwned, IgnoredAny};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Write};
+
+use crate::binary::{self, Format};
+
+// Bump when the databases change in a way older generators would
+// misread, and teach migrate() to bring older files up to date.
+// Files from before versioning have no schema_version and load as 0.
+pub const SCHEMA_VERSION: u32 = 2;
+
+// group_len of a field that isn't an array
+pub const NOT_ARRAY: u32 = 0xffffffff;
+
+// builtin C types, LP64: name, size in bits, signed, float, boolean
+pub const BUILTIN_SCALARS: &[(&str, u32, bool, bool, bool)] = &[
+    ("char", 8, true, false, false),
+    ("signed char", 8, true, false, false),
+    ("unsigned char", 8, false, false, false),
+    ("short", 16, true, false, false),
+    ("unsigned short", 16, false, false, false),
+    ("int", 32, true, false, false),
+    ("unsigned int", 32, false, false, false),
+    ("long", 64, true, false, false),
+    ("unsigned long", 64, false, false, false),
+    ("long long", 64, true, false, false),
+    ("unsigned long long", 64, false, false, false),
+    ("_Bool", 8, false, false, true),
+    ("float", 32, true, true, false),
+    ("double", 64, true, true, false),
+    ("__int128", 128, true, false, false),
+    ("unsigned __int128", 128, false, false, false),
+];
+
+// the nvtypes.h typedefs, used to fill in the scalar registry of
+// databases written before it existed: name, canonical type
+const LEGACY_SCALARS: &[(&str, &str)] = &[
+    ("NvU8", "unsigned char"),
+    ("NvU16", "unsigned short"),
+    ("NvU32", "unsigned int"),
+    ("NvU64", "unsigned long long"),
+    ("NvS8", "signed char"),
+    ("NvS16", "short"),
+    ("NvS32", "int"),
+    ("NvS64", "long long"),
+    ("NvV8", "unsigned char"),
+    ("NvV16", "unsigned short"),
+    ("NvV32", "unsigned int"),
+    ("NvV64", "unsigned long long"),
+    ("NvF32", "float"),
+    ("NvF64", "double"),
+    ("NvBool", "unsigned char"),
+    ("NvHandle", "unsigned int"),
+    ("NvLength", "unsigned long long"),
+    ("NvUPtr", "unsigned long"),
+    ("NvP64", "unsigned long long"),
+];
+
+pub fn is_nvtypes_scalar(name: &str) -> bool {
+    LEGACY_SCALARS.iter().any(|(x, _)| *x == name)
+}
+
+// where a symbol was defined, file is relative to the NVIDIA tree
+// root and include_chain lists the headers that included it starting
+// from the one being parsed.
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug, PartialEq)]
+pub struct SourceInfo {
+    pub file: String,
+    pub line: u32,
+    #[serde(default)]
+    pub include_chain: Vec<String>,
+}
+
+// start/end are in bits
+// for bitfields start/size describe the storage unit holding the
+// field and bit_offset/bit_width the bits inside that unit.
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug, PartialEq)]
+pub struct HWStructField {
+    pub name: String,
+    pub start: u32,
+    pub size: u32,
+    // number of elements for arrays, NOT_ARRAY otherwise
+    pub group_len: u32,
+    // 0 when val_type names a struct in structs
+    pub isint: u32,
+    pub val_type: String,
+    #[serde(default)]
+    pub bit_offset: u32,
+    #[serde(default)]
+    pub bit_width: u32,
+    // declared alignment in bytes, natural alignment if not declared
+    #[serde(default)]
+    pub alignment: u32,
+    // set when the field is typed by an enum in enums
+    #[serde(default)]
+    pub enum_type: String,
+    // set when the field (or array element) is a type in scalars
+    #[serde(default)]
+    pub scalar: String,
+}
+
+// bits not covered by any field, including tail padding
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug, PartialEq)]
+pub struct HWHole {
+    pub start: u32,
+    pub size: u32,
+}
+
+// one member of a union and the (flattened) fields it covers
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug, PartialEq)]
+pub struct HWUnionAlternative {
+    // empty for an anonymous struct member
+    pub name: String,
+    pub fields: Vec<String>,
+}
+
+// fields that overlap because they are members of the same union
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug, PartialEq)]
+pub struct HWUnion {
+    // the union member with its name prefix, empty when anonymous
+    pub name: String,
+    // name prefix of the struct the union is a member of, empty for
+    // the outer struct
+    pub parent: String,
+    // for a union inside another union, the index in unions of that
+    // union and the alternative this one is part of
+    pub parent_union: Option<(u32, u32)>,
+    // in bits
+    pub start: u32,
+    pub size: u32,
+    pub alternatives: Vec<HWUnionAlternative>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug, PartialEq)]
+pub struct HWStruct {
+    pub total_size: u32,
+    pub fields: Vec<HWStructField>,
+    // a union type, unions[0] covers the whole type
+    #[serde(default)]
+    pub is_union: bool,
+    // struct/union tag, empty for anonymous records
+    #[serde(default)]
+    pub tag: String,
+    // for typedefs, the typedef or tag name this is an alias of,
+    // empty when the typedef and tag names are the same
+    #[serde(default)]
+    pub alias_of: String,
+    // declared but not defined in the header that used it, there is
+    // no layout
+    #[serde(default)]
+    pub opaque: bool,
+    // outer unions come before the unions nested in them
+    #[serde(default)]
+    pub unions: Vec<HWUnion>,
+    // in bytes
+    #[serde(default)]
+    pub alignment: u32,
+    #[serde(default)]
+    pub packed: bool,
+    #[serde(default)]
+    pub holes: Vec<HWHole>,
+    #[serde(default)]
+    pub source: Option<SourceInfo>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug, PartialEq)]
+pub enum HWDefineType {
+    #[default]
+    Unknown,
+    Value,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug, PartialEq)]
+pub struct HWDefine {
+    pub hwtype: HWDefineType,
+    pub vals: Vec<String>,
+    // macro body as written and its evaluated value if it is a
+    // constant integer expression
+    #[serde(default)]
+    pub expr: String,
+    #[serde(default)]
+    pub value: Option<i128>,
+    #[serde(default)]
+    pub width: u32,
+    #[serde(default)]
+    pub signed: bool,
+    // for enum constants, the enum they are a member of. The C
+    // generators emit the enum instead of a define for them.
+    #[serde(default)]
+    pub enum_name: String,
+    #[serde(default)]
+    pub source: Option<SourceInfo>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug, PartialEq)]
+pub struct HWEnumMember {
+    pub name: String,
+    pub value: i128,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug, PartialEq)]
+pub struct HWEnum {
+    // size in bits of the enum type
+    pub size: u32,
+    pub signed: bool,
+    pub packed: bool,
+    // in declaration order
+    pub members: Vec<HWEnumMember>,
+    // for a typedef of an enum recorded under another name (its tag),
+    // that name. Aliases have no members of their own.
+    #[serde(default)]
+    pub alias_of: String,
+    #[serde(default)]
+    pub source: Option<SourceInfo>,
+}
+
+// a builtin C type or a typedef of one, size in bits
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug, PartialEq)]
+pub struct HWScalar {
+    pub size: u32,
+    pub signed: bool,
+    pub float: bool,
+    // _Bool and NvBool, stored as an unsigned integer of size bits
+    pub boolean: bool,
+    // the builtin type it resolves to
+    pub canonical: String,
+    // none for builtin types
+    #[serde(default)]
+    pub source: Option<SourceInfo>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug, PartialEq)]
+pub struct HWJson {
+    #[serde(default)]
+    pub schema_version: u32,
+    pub version: String,
+    pub defines: BTreeMap<String, HWDefine>,
+    pub structs: BTreeMap<String, HWStruct>,
+    #[serde(default)]
+    pub enums: BTreeMap<String, HWEnum>,
+    #[serde(default)]
+    pub scalars: BTreeMap<String, HWScalar>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub enum FieldType {
+    Member,
+    UnionStart,
+    UnionEnd,
+    StructStart,
+    StructEnd,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct CStructField {
+    pub fldtype: FieldType,
+    pub ftype: String,
+    pub name: String,
+    pub is_array: bool,
+    pub size: u32,
+    pub is_aligned: bool,
+    pub alignment: u32,
+    // non-zero for bitfield members
+    #[serde(default)]
+    pub bit_width: u32,
+    // synthetic name of an anonymous struct or union, set on its
+    // start and end markers
+    #[serde(default)]
+    pub anon_name: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug, PartialEq)]
+pub enum CType {
+    #[default]
+    Unknown,
+    Value,
+    Struct,
+    Typedef,
+    Enum,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug, PartialEq)]
+pub struct CEnumMember {
+    pub name: String,
+    pub value: i128,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug, PartialEq)]
+pub struct CTypes {
+    pub ctype: CType,
+    pub vals: Vec<String>,
+    // false for every type in the database, an untagged struct or
+    // union has an empty tag
+    pub is_anon_struct: bool,
+    // for structs
+    pub fields: Vec<CStructField>,
+    // in bytes
+    #[serde(default)]
+    pub alignment: u32,
+    #[serde(default)]
+    pub packed: bool,
+    // for values, macro body as written and its evaluated value
+    // for enums, width and signed describe the enum type
+    #[serde(default)]
+    pub expr: String,
+    #[serde(default)]
+    pub value: Option<i128>,
+    #[serde(default)]
+    pub width: u32,
+    #[serde(default)]
+    pub signed: bool,
+    // for values, as in HWDefine
+    #[serde(default)]
+    pub enum_name: String,
+    // for enums, vals holds the underlying type
+    #[serde(default)]
+    pub members: Vec<CEnumMember>,
+    // for structs, as in HWStruct
+    #[serde(default)]
+    pub is_union: bool,
+    #[serde(default)]
+    pub tag: String,
+    // for structs as in HWStruct, for enums as in HWEnum
+    #[serde(default)]
+    pub alias_of: String,
+    // declared but never defined, emitted as an incomplete type
+    #[serde(default)]
+    pub opaque: bool,
+    #[serde(default)]
+    pub source: Option<SourceInfo>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug, PartialEq)]
+pub struct CJson {
+    #[serde(default)]
+    pub schema_version: u32,
+    pub version: String,
+    pub types: BTreeMap<String, CTypes>,
+}
+
+// the symbols to generate, cmds are keyed on the control group
+// (e.g. "0080") and list the command names in it. Every entry is a
+// selector, see selector.rs.
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug, PartialEq)]
+pub struct WantedJson {
+    pub structs: Vec<String>,
+    pub cmds: BTreeMap<String, Vec<String>>,
+    pub defines: Vec<String>,
+    #[serde(default)]
+    pub enums: Vec<String>,
+}
+
+// just enough of a database to tell which one it is and which
+// version of the schema it was written with
+#[derive(Deserialize)]
+struct SchemaHeader {
+    #[serde(default)]
+    schema_version: u32,
+    #[serde(default)]
+    types: Option<IgnoredAny>,
+    #[serde(default)]
+    structs: Option<IgnoredAny>,
+}
+
+pub enum Database {
+    C(CJson),
+    HW(HWJson),
+}
+
+fn file_error(path: &str, err: Error) -> Error {
+    Error::new(err.kind(), format!("{}: {}", path, err))
+}
+
+// read a json file, errors name the file
+pub fn load_json<T: DeserializeOwned>(path: &str) -> std::io::Result<T> {
+    let file = File::open(path).map_err(|e| file_error(path, e))?;
+    serde_json::from_reader(BufReader::new(file)).map_err(|e| file_error(path, e.into()))
+}
+
+pub fn save_json<T: Serialize>(path: &str, value: &T) -> std::io::Result<()> {
+    let file = File::create(path).map_err(|e| file_error(path, e))?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, value)?;
+    writer.flush()
+}
+
+// a database written by a newer extractor may use fields this build
+// doesn't know about, refuse it rather than silently misread it.
+fn check_schema_version(path: &str, version: u32) -> std::io::Result<()> {
+    if version > SCHEMA_VERSION {
+	return Err(Error::new(ErrorKind::InvalidData,
+			      format!("{}: database schema version {} is newer than the supported version {}, rebuild gsp-parse",
+				      path, version, SCHEMA_VERSION)));
+    }
+    Ok(())
+}
+
+fn read_database(path: &str, contents: &str) -> std::io::Result<SchemaHeader> {
+    let header: SchemaHeader = serde_json::from_str(contents).map_err(|e| file_error(path, e.into()))?;
+    check_schema_version(path, header.schema_version)?;
+    Ok(header)
+}
+
+fn parse_database<T: DeserializeOwned>(path: &str, contents: &str) -> std::io::Result<T> {
+    serde_json::from_str(contents).map_err(|e| file_error(path, e.into()))
+}
+
+impl Database {
+    // a C or HW database, whichever the file holds, json or binary
+    pub fn load(path: &str) -> std::io::Result<Database> {
+	let bytes = std::fs::read(path).map_err(|e| file_error(path, e))?;
+	if binary::is_binary(&bytes) {
+	    return binary::decode(path, &bytes);
+	}
+	let contents = String::from_utf8(bytes)
+	    .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path, e)))?;
+	let header = read_database(path, &contents)?;
+	if header.structs.is_some() {
+	    let mut db: HWJson = parse_database(path, &contents)?;
+	    db.migrate();
+	    Ok(Database::HW(db))
+	} else if header.types.is_some() {
+	    let mut db: CJson = parse_database(path, &contents)?;
+	    db.migrate();
+	    Ok(Database::C(db))
+	} else {
+	    Err(Error::new(ErrorKind::InvalidData, format!("{}: not a C or HW database", path)))
+	}
+    }
+
+    // in the format the file name asks for
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+	self.save_as(path, Format::from_path(path))
+    }
+
+    pub fn save_as(&self, path: &str, format: Format) -> std::io::Result<()> {
+	let bytes = match (self, format) {
+	    (Database::C(x), Format::Json) => { return save_json(path, x); }
+	    (Database::HW(x), Format::Json) => { return save_json(path, x); }
+	    (_, Format::Binary) => { binary::encode(self, false)? }
+	    (_, Format::Zstd) => { binary::encode(self, true)? }
+	};
+	std::fs::write(path, bytes).map_err(|e| file_error(path, e))
+    }
+
+    pub fn version(&self) -> &str {
+	match self {
+	    Database::C(x) => { &x.version }
+	    Database::HW(x) => { &x.version }
+	}
+    }
+}
+
+// find the bits of a struct not covered by any field, fields in unions
+// overlap so merge the covered ranges first.
+pub fn find_holes(fields: &[HWStructField], total_size: u32) -> Vec<HWHole> {
+    let mut covered: Vec<(u32, u32)> = Default::default();
+    for fld in fields {
+	if fld.bit_width > 0 {
+	    covered.push((fld.start + fld.bit_offset, fld.start + fld.bit_offset + fld.bit_width));
+	} else {
+	    covered.push((fld.start, fld.start + fld.total_size()));
+	}
+    }
+    covered.sort();
+
+    let mut holes: Vec<HWHole> = Default::default();
+    let mut pos = 0;
+    for (start, end) in covered {
+	if start > pos {
+	    holes.push(HWHole { start: pos, size: start - pos });
+	}
+	pos = std::cmp::max(pos, end);
+    }
+    if pos < total_size {
+	holes.push(HWHole { start: pos, size: total_size - pos });
+    }
+    holes
+}
+
+impl HWStructField {
+    pub fn is_array(&self) -> bool {
+	self.group_len != NOT_ARRAY
+    }
+
+    // bits covered by the field, all elements for arrays
+    pub fn total_size(&self) -> u32 {
+	if self.is_array() { self.size * self.group_len } else { self.size }
+    }
+}
+
+impl HWStruct {
+    pub fn field(&self, name: &str) -> Option<&HWStructField> {
+	self.fields.iter().find(|f| f.name == name)
+    }
+
+    // flattened names join the member path with _, which can still
+    // produce the same name twice, e.g. a_b_c from a.b_c and a_b.c.
+    pub fn name_collisions(&self) -> Vec<&str> {
+	let mut seen: HashSet<&str> = Default::default();
+	self.fields.iter().map(|f| f.name.as_str()).filter(|x| !seen.insert(*x)).collect()
+    }
+}
+
+impl fmt::Display for SourceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+// the comment the generators put before a symbol with --sources
+pub fn write_source_comment<W: Write>(out_writer: &mut W, source: &Option<SourceInfo>) -> std::io::Result<()> {
+    if let Some(source) = source {
+	writeln!(out_writer, "// from {}", source)?;
+    }
+    Ok(())
+}
+
+impl HWScalar {
+    pub fn builtin(name: &str, size: u32, signed: bool, float: bool, boolean: bool) -> HWScalar {
+	HWScalar { size, signed, float, boolean, canonical: name.to_string(), source: None }
+    }
+}
+
+impl HWJson {
+    pub fn load(path: &str) -> std::io::Result<HWJson> {
+	match Database::load(path)? {
+	    Database::HW(x) => { Ok(x) }
+	    Database::C(_) => { Err(Error::new(ErrorKind::InvalidData, format!("{}: is a C database, not a HW one", path))) }
+	}
+    }
+
+    // bring a database from an older extractor up to the current schema
+    pub fn migrate(&mut self) {
+	if self.schema_version < 1 {
+	    self.add_legacy_scalars();
+	    self.add_legacy_holes();
+	}
+	if self.schema_version < 2 {
+	    self.add_enum_names();
+	}
+	self.schema_version = SCHEMA_VERSION;
+    }
+
+    // enum constants used to be plain defines, point them at the enum
+    // they are a member of.
+    fn add_enum_names(&mut self) {
+	for (name, hwenum) in &self.enums {
+	    for member in &hwenum.members {
+		if let Some(define) = self.defines.get_mut(&member.name) {
+		    define.enum_name = name.clone();
+		}
+	    }
+	}
+    }
+
+    // older databases have no scalar registry, rebuild it from the
+    // builtin types and nvtypes.h and point integer fields at it.
+    fn add_legacy_scalars(&mut self) {
+	for (name, size, signed, float, boolean) in BUILTIN_SCALARS {
+	    self.scalars.entry(name.to_string())
+		.or_insert_with(|| HWScalar::builtin(name, *size, *signed, *float, *boolean));
+	}
+	for (name, canonical) in LEGACY_SCALARS {
+	    let mut scalar = match self.scalars.get(*canonical) {
+		Some(x) => { x.clone() }
+		None => { continue }
+	    };
+	    scalar.boolean = *name == "NvBool";
+	    self.scalars.entry(name.to_string()).or_insert(scalar);
+	}
+	for hwstruct in self.structs.values_mut() {
+	    for fld in &mut hwstruct.fields {
+		let val_type = fld.val_type.replace("const ", "").replace("volatile ", "");
+		if fld.isint != 0 && fld.scalar.is_empty() && self.scalars.contains_key(&val_type) {
+		    fld.scalar = val_type;
+		}
+	    }
+	}
+    }
+
+    // holes were added without bumping anything, fill them in for
+    // files that don't have them
+    fn add_legacy_holes(&mut self) {
+	for hwstruct in self.structs.values_mut() {
+	    if hwstruct.holes.is_empty() && !hwstruct.opaque {
+		hwstruct.holes = find_holes(&hwstruct.fields, hwstruct.total_size);
+	    }
+	}
+    }
+
+    pub fn get_define(&self, name: &str) -> Option<&HWDefine> {
+	self.defines.get(name)
+    }
+
+    pub fn get_struct(&self, name: &str) -> Option<&HWStruct> {
+	self.structs.get(name)
+    }
+
+    pub fn get_enum(&self, name: &str) -> Option<&HWEnum> {
+	self.enums.get(name)
+    }
+
+    pub fn get_scalar(&self, name: &str) -> Option<&HWScalar> {
+	self.scalars.get(name)
+    }
+
+    // the generators don't print sources unless asked to
+    pub fn strip_sources(&mut self) {
+	self.defines.values_mut().for_each(|d| d.source = None);
+	self.structs.values_mut().for_each(|s| s.source = None);
+	self.enums.values_mut().for_each(|e| e.source = None);
+	self.scalars.values_mut().for_each(|s| s.source = None);
+    }
+
+    // internal consistency problems, fields outside their struct and
+    // references to types that aren't in the database
+    pub fn validate(&self) -> Vec<String> {
+	let mut problems: Vec<String> = Default::default();
+	for (strname, hwstruct) in &self.structs {
+	    if hwstruct.opaque {
+		continue;
+	    }
+	    for fld in &hwstruct.fields {
+		if fld.start + fld.total_size() > hwstruct.total_size {
+		    problems.push(format!("struct {}: field {} extends past the end of the struct", strname, fld.name));
+		}
+		if fld.isint == 0 && !self.structs.contains_key(&fld.val_type) {
+		    problems.push(format!("struct {}: field {} has unknown struct type {}", strname, fld.name, fld.val_type));
+		}
+		if !fld.enum_type.is_empty() && !self.enums.contains_key(&fld.enum_type) {
+		    problems.push(format!("struct {}: field {} has unknown enum type {}", strname, fld.name, fld.enum_type));
+		}
+		if !fld.scalar.is_empty() && !self.scalars.contains_key(&fld.scalar) {
+		    problems.push(format!("struct {}: field {} has unknown scalar type {}", strname, fld.name, fld.scalar));
+		}
+	    }
+	    for hwunion in &hwstruct.unions {
+		for alt in &hwunion.alternatives {
+		    for fld in &alt.fields {
+			if hwstruct.field(fld).is_none() {
+			    problems.push(format!("struct {}: union {} lists unknown field {}", strname, hwunion.name, fld));
+			}
+		    }
+		}
+	    }
+	}
+	for (name, hwenum) in &self.enums {
+	    if !hwenum.alias_of.is_empty() && !self.enums.contains_key(&hwenum.alias_of) {
+		problems.push(format!("enum {}: alias of unknown enum {}", name, hwenum.alias_of));
+	    }
+	}
+	for (name, define) in &self.defines {
+	    if !define.enum_name.is_empty() && !self.enums.contains_key(&define.enum_name) {
+		problems.push(format!("define {}: constant of unknown enum {}", name, define.enum_name));
+	    }
+	}
+	problems
+    }
+}
+
+impl CJson {
+    pub fn load(path: &str) -> std::io::Result<CJson> {
+	match Database::load(path)? {
+	    Database::C(x) => { Ok(x) }
+	    Database::HW(_) => { Err(Error::new(ErrorKind::InvalidData, format!("{}: is a HW database, not a C one", path))) }
+	}
+    }
+
+    // bring a database from an older extractor up to the current schema
+    pub fn migrate(&mut self) {
+	if self.schema_version < 2 {
+	    self.add_enum_names();
+	}
+	self.schema_version = SCHEMA_VERSION;
+    }
+
+    // as for HWJson
+    fn add_enum_names(&mut self) {
+	let mut constants: Vec<(String, String)> = Default::default();
+	for (name, ctype) in self.types.iter().filter(|(_, x)| x.ctype == CType::Enum) {
+	    constants.extend(ctype.members.iter().map(|m| (m.name.clone(), name.clone())));
+	}
+	for (constant, name) in constants {
+	    if let Some(ctype) = self.types.get_mut(&constant).filter(|x| x.ctype == CType::Value) {
+		ctype.enum_name = name;
+	    }
+	}
+    }
+
+    pub fn get_type(&self, name: &str) -> Option<&CTypes> {
+	self.types.get(name)
+    }
+
+    pub fn strip_sources(&mut self) {
+	self.types.values_mut().for_each(|t| t.source = None);
+    }
+
+    // struct and union start/end markers have to pair up
+    pub fn validate(&self) -> Vec<String> {
+	let mut problems: Vec<String> = Default::default();
+	for (name, ctype) in &self.types {
+	    let mut open: Vec<&FieldType> = Default::default();
+	    for fld in &ctype.fields {
+		match fld.fldtype {
+		    FieldType::StructStart | FieldType::UnionStart => { open.push(&fld.fldtype); }
+		    FieldType::StructEnd | FieldType::UnionEnd => {
+			let expected = match fld.fldtype {
+			    FieldType::StructEnd => { FieldType::StructStart }
+			    _ => { FieldType::UnionStart }
+			};
+			if open.pop() != Some(&expected) {
+			    problems.push(format!("type {}: unbalanced {:?} marker", name, fld.fldtype));
+			}
+		    }
+		    FieldType::Member => {}
+		}
+	    }
+	    if !open.is_empty() {
+		problems.push(format!("type {}: unterminated struct or union", name));
+	    }
+	}
+	problems
+    }
+}
+
+impl WantedJson {
+    pub fn load(path: &str) -> std::io::Result<WantedJson> {
+	load_json(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a HW database from before versioning, without scalars, holes or
+    // enum constants pointing at their enum
+    const LEGACY_HW: &str = r#"{
+	"version": "525.60.13",
+	"defines": { "E_A": { "hwtype": "Value", "vals": ["1"] } },
+	"structs": { "S": { "total_size": 64, "fields": [
+	    { "name": "a", "start": 0, "size": 16, "group_len": 4294967295, "isint": 1, "val_type": "NvU16" },
+	    { "name": "b", "start": 32, "size": 32, "group_len": 4294967295, "isint": 1, "val_type": "const NvU32" }
+	] } },
+	"enums": { "E": { "size": 32, "signed": false, "packed": false, "members": [ { "name": "E_A", "value": 1 } ] } }
+    }"#;
+
+    fn write_temp(name: &str, contents: &str) -> String {
+	let path = std::env::temp_dir().join(format!("gsp-parse-schema-{}-{}", std::process::id(), name));
+	std::fs::write(&path, contents).unwrap();
+	path.to_string_lossy().to_string()
+    }
+
+    fn field(name: &str, start: u32, size: u32) -> HWStructField {
+	HWStructField { name: name.to_string(), start, size, group_len: NOT_ARRAY, isint: 1, ..Default::default() }
+    }
+
+    #[test]
+    fn legacy_hw_database_is_migrated() {
+	let path = write_temp("legacy.hw.json", LEGACY_HW);
+	let hwjson = HWJson::load(&path).unwrap();
+	std::fs::remove_file(&path).unwrap();
+
+	assert_eq!(hwjson.schema_version, SCHEMA_VERSION);
+	assert_eq!(hwjson.get_scalar("NvU32").unwrap().canonical, "unsigned int");
+	assert!(hwjson.get_scalar("NvBool").unwrap().boolean);
+	let hwstruct = hwjson.get_struct("S").unwrap();
+	assert_eq!(hwstruct.field("a").unwrap().scalar, "NvU16");
+	assert_eq!(hwstruct.field("b").unwrap().scalar, "NvU32");
+	assert_eq!(hwstruct.holes, [HWHole { start: 16, size: 16 }]);
+	assert_eq!(hwjson.get_define("E_A").unwrap().enum_name, "E");
+	assert!(hwjson.validate().is_empty());
+    }
+
+    #[test]
+    fn newer_schema_is_refused() {
+	let path = write_temp("newer.hw.json", &LEGACY_HW.replacen('{', &format!("{{ \"schema_version\": {},", SCHEMA_VERSION + 1), 1));
+	let err = HWJson::load(&path).err().unwrap();
+	std::fs::remove_file(&path).unwrap();
+	assert_eq!(err.kind(), ErrorKind::InvalidData);
+	assert!(err.to_string().starts_with(&path));
+	assert!(err.to_string().contains("newer than the supported version"));
+    }
+
+    #[test]
+    fn wrong_database_kind() {
+	let path = write_temp("kind.hw.json", LEGACY_HW);
+	assert!(CJson::load(&path).is_err());
+	std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn holes_skip_union_members_and_bitfields() {
+	let mut bits = field("bits", 32, 32);
+	bits.bit_offset = 0;
+	bits.bit_width = 4;
+	// a and b overlap as members of a union
+	let fields = [field("a", 0, 16), field("b", 0, 8), bits];
+	assert_eq!(find_holes(&fields, 96), [HWHole { start: 16, size: 16 }, HWHole { start: 36, size: 60 }]);
+    }
+
+    #[test]
+    fn c_enum_constants_are_migrated() {
+	let mut cjson: CJson = Default::default();
+	cjson.types.insert("E".to_string(), CTypes {
+	    ctype: CType::Enum,
+	    members: vec![CEnumMember { name: "E_A".to_string(), value: 1 }],
+	    ..Default::default()
+	});
+	cjson.types.insert("E_A".to_string(), CTypes { ctype: CType::Value, vals: vec!["1".to_string()], ..Default::default() });
+	cjson.migrate();
+	assert_eq!(cjson.schema_version, SCHEMA_VERSION);
+	assert_eq!(cjson.get_type("E_A").unwrap().enum_name, "E");
+    }
+}
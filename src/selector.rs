@@ -0,0 +1,345 @@
+// Selectors, the entries of want lists. Every entry of structs,
+// defines, enums and the command lists of cmds is one of
+//
+//   NAME          exactly that symbol
+//   NV_FOO_*      a glob, * matches any run of characters and ? one
+//   /regex/       a regular expression matching the whole name
+//   header:path   every symbol defined in a header, path may be a glob
+//                 and matches the end of the file name
+//   !selector     drops what the selector matches, wherever it is listed
+//
+// A symbol is selected when an entry matches it and no exclusion does,
+// in the order of the first entry that matches it. Exact names are
+// kept even when the database doesn't have them, the generators and
+// jsondb lint report those.
+
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+
+use crate::schema::*;
+
+// shell style wildcards, * matches any run of characters and ? one
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // where to resume after the last *, in pattern and name
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+	if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+	    p += 1;
+	    n += 1;
+	} else if p < pattern.len() && pattern[p] == '*' {
+	    star = Some((p + 1, n));
+	    p += 1;
+	} else if let Some((sp, sn)) = star {
+	    p = sp;
+	    n = sn + 1;
+	    star = Some((sp, sn + 1));
+	} else {
+	    return false;
+	}
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+pub enum Pattern {
+    Name(String),
+    Glob(String),
+    Regex(Regex),
+    Header(String),
+}
+
+impl Pattern {
+    pub fn parse(entry: &str) -> Result<Pattern, String> {
+	if let Some(path) = entry.strip_prefix("header:") {
+	    return Ok(Pattern::Header(path.to_string()));
+	}
+	if entry.len() >= 2 && entry.starts_with('/') && entry.ends_with('/') {
+	    return match Regex::new(&format!("^(?:{})$", &entry[1..entry.len() - 1])) {
+		Ok(x) => { Ok(Pattern::Regex(x)) }
+		// syntax errors draw the pattern with a marker, keep the message
+		Err(e) => { Err(e.to_string().lines().last().unwrap_or_default().trim_start_matches("error: ").to_string()) }
+	    };
+	}
+	if entry.contains(['*', '?']) {
+	    return Ok(Pattern::Glob(entry.to_string()));
+	}
+	Ok(Pattern::Name(entry.to_string()))
+    }
+
+    pub fn matches(&self, name: &str, source: Option<&SourceInfo>) -> bool {
+	match self {
+	    Pattern::Name(x) => { x == name }
+	    Pattern::Glob(x) => { glob_match(x, name) }
+	    Pattern::Regex(x) => { x.is_match(name) }
+	    Pattern::Header(x) => {
+		match source {
+		    Some(source) => { glob_match(x, &source.file) || glob_match(&format!("*/{}", x), &source.file) }
+		    None => { false }
+		}
+	    }
+	}
+    }
+}
+
+// symbols that know where they were defined, for header: selectors
+pub trait HasSource {
+    fn source(&self) -> Option<&SourceInfo>;
+}
+
+impl HasSource for HWDefine {
+    fn source(&self) -> Option<&SourceInfo> { self.source.as_ref() }
+}
+
+impl HasSource for HWStruct {
+    fn source(&self) -> Option<&SourceInfo> { self.source.as_ref() }
+}
+
+impl HasSource for HWEnum {
+    fn source(&self) -> Option<&SourceInfo> { self.source.as_ref() }
+}
+
+impl HasSource for HWScalar {
+    fn source(&self) -> Option<&SourceInfo> { self.source.as_ref() }
+}
+
+impl HasSource for CTypes {
+    fn source(&self) -> Option<&SourceInfo> { self.source.as_ref() }
+}
+
+#[derive(Default)]
+pub struct Selector {
+    pub include: Vec<Pattern>,
+    pub exclude: Vec<Pattern>,
+}
+
+impl Selector {
+    pub fn parse(entries: &[String]) -> std::io::Result<Selector> {
+	let mut selector: Selector = Default::default();
+	for entry in entries {
+	    let (list, pattern) = match entry.strip_prefix('!') {
+		Some(x) => { (&mut selector.exclude, x) }
+		None => { (&mut selector.include, entry.as_str()) }
+	    };
+	    match Pattern::parse(pattern) {
+		Ok(x) => { list.push(x); }
+		Err(e) => { return Err(Error::new(ErrorKind::InvalidInput, format!("invalid selector {}: {}", entry, e))); }
+	    }
+	}
+	Ok(selector)
+    }
+
+    pub fn matches(&self, name: &str, source: Option<&SourceInfo>) -> bool {
+	self.include.iter().any(|x| x.matches(name, source)) && !self.exclude.iter().any(|x| x.matches(name, source))
+    }
+
+    pub fn select<'a>(&self, symbols: impl IntoIterator<Item = (&'a String, Option<&'a SourceInfo>)>) -> Vec<String> {
+	let symbols: Vec<(&String, Option<&SourceInfo>)> = symbols.into_iter().collect();
+	let sources: HashMap<&str, Option<&SourceInfo>> = symbols.iter().map(|(name, source)| (name.as_str(), *source)).collect();
+	let mut seen: HashSet<&str> = Default::default();
+	let mut selected: Vec<String> = Default::default();
+	for pattern in &self.include {
+	    if let Pattern::Name(x) = pattern {
+		let source = sources.get(x.as_str()).copied().flatten();
+		if !self.exclude.iter().any(|e| e.matches(x, source)) && seen.insert(x) {
+		    selected.push(x.clone());
+		}
+		continue;
+	    }
+	    for (name, source) in &symbols {
+		if pattern.matches(name, *source) && !self.exclude.iter().any(|e| e.matches(name, *source)) && seen.insert(name) {
+		    selected.push(name.to_string());
+		}
+	    }
+	}
+	selected
+    }
+
+    pub fn select_from<T: HasSource>(&self, map: &BTreeMap<String, T>) -> Vec<String> {
+	self.select(map.iter().map(|(name, x)| (name, x.source())))
+    }
+}
+
+// the selectors of a want list
+pub struct WantSelectors {
+    pub structs: Selector,
+    pub defines: Selector,
+    pub enums: Selector,
+    pub cmds: BTreeMap<String, Selector>,
+}
+
+// what a want list selects from a database, the cmds by group
+#[derive(Default, Debug)]
+pub struct Selection {
+    pub defines: Vec<String>,
+    pub enums: Vec<String>,
+    pub structs: Vec<String>,
+    pub cmds: Vec<(String, Vec<String>)>,
+}
+
+impl Selection {
+    // NV<group>_CTRL_CMD_<cmd> and NV<group>_CTRL_<cmd>, the prefixes of
+    // the defines of each command
+    pub fn cmd_prefixes(&self) -> Vec<(String, String)> {
+	let mut prefixes: Vec<(String, String)> = Default::default();
+	for (group, cmds) in &self.cmds {
+	    for cmd in cmds {
+		prefixes.push((format!("NV{}_CTRL_CMD_{}", group, cmd), format!("NV{}_CTRL_{}", group, cmd)));
+	    }
+	}
+	prefixes
+    }
+}
+
+impl WantedJson {
+    pub fn selectors(&self) -> std::io::Result<WantSelectors> {
+	let mut cmds: BTreeMap<String, Selector> = Default::default();
+	for (group, list) in &self.cmds {
+	    cmds.insert(group.clone(), Selector::parse(list)?);
+	}
+	Ok(WantSelectors {
+	    structs: Selector::parse(&self.structs)?,
+	    defines: Selector::parse(&self.defines)?,
+	    enums: Selector::parse(&self.enums)?,
+	    cmds,
+	})
+    }
+}
+
+// the commands of a group are the NV<group>_CTRL_CMD_<cmd> defines,
+// selected by <cmd>
+fn select_cmds<'a>(want: &WantSelectors, defines: impl Iterator<Item = (&'a String, Option<&'a SourceInfo>)> + Clone) -> Vec<(String, Vec<String>)> {
+    let mut cmds: Vec<(String, Vec<String>)> = Default::default();
+    for (group, selector) in &want.cmds {
+	let prefix = format!("NV{}_CTRL_CMD_", group);
+	let names: Vec<(String, Option<&SourceInfo>)> = defines.clone()
+	    .filter_map(|(name, source)| Some((name.strip_prefix(&prefix)?.to_string(), source)))
+	    .collect();
+	cmds.push((group.clone(), selector.select(names.iter().map(|(name, source)| (name, *source)))));
+    }
+    cmds
+}
+
+impl HWJson {
+    pub fn select(&self, want: &WantSelectors) -> Selection {
+	Selection {
+	    defines: want.defines.select_from(&self.defines),
+	    enums: want.enums.select_from(&self.enums),
+	    structs: want.structs.select_from(&self.structs),
+	    cmds: select_cmds(want, self.defines.iter().map(|(name, x)| (name, x.source()))),
+	}
+    }
+}
+
+impl CJson {
+    // each list only matches types of its kind, exact names are
+    // taken as they are
+    fn select_kind(&self, selector: &Selector, ctype: CType) -> Vec<String> {
+	selector.select(self.types.iter().filter(|(_, x)| x.ctype == ctype).map(|(name, x)| (name, x.source())))
+    }
+
+    pub fn select(&self, want: &WantSelectors) -> Selection {
+	Selection {
+	    defines: self.select_kind(&want.defines, CType::Value),
+	    enums: self.select_kind(&want.enums, CType::Enum),
+	    structs: self.select_kind(&want.structs, CType::Struct),
+	    cmds: select_cmds(want, self.types.iter().filter(|(_, x)| x.ctype == CType::Value).map(|(name, x)| (name, x.source()))),
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn select(entries: &[&str], names: &[&str]) -> Vec<String> {
+	let entries: Vec<String> = entries.iter().map(|x| x.to_string()).collect();
+	let names: Vec<String> = names.iter().map(|x| x.to_string()).collect();
+	Selector::parse(&entries).unwrap().select(names.iter().map(|x| (x, None)))
+    }
+
+    fn defines(files: &[(&str, &str)]) -> BTreeMap<String, HWDefine> {
+	files.iter().map(|(name, file)| {
+	    let source = SourceInfo { file: file.to_string(), line: 1, include_chain: Default::default() };
+	    (name.to_string(), HWDefine { source: Some(source), ..Default::default() })
+	}).collect()
+    }
+
+    #[test]
+    fn glob_star_in_the_middle() {
+	assert!(glob_match("NV_*_CTRL", "NV_FOO_CTRL"));
+	assert!(glob_match("NV_*_CTRL", "NV_FOO_BAR_CTRL"));
+	assert!(!glob_match("NV_*_CTRL", "NV_CTRL"));
+	assert!(!glob_match("NV_*_CTRL", "NV_FOO_CTRLX"));
+	// needs to backtrack past the first b
+	assert!(glob_match("a*b*c", "aXbYbZc"));
+	assert!(!glob_match("a*b*c", "aXbYbZ"));
+    }
+
+    #[test]
+    fn glob_question_mark() {
+	assert!(glob_match("NV?_X", "NV2_X"));
+	assert!(!glob_match("NV?_X", "NV_X"));
+	assert!(!glob_match("NV?_X", "NV12_X"));
+    }
+
+    #[test]
+    fn glob_trailing_star() {
+	assert!(glob_match("NV_FOO*", "NV_FOO"));
+	assert!(glob_match("NV_FOO*", "NV_FOOBAR"));
+	assert!(!glob_match("NV_FOO*", "NV_FO"));
+	assert!(glob_match("*", ""));
+    }
+
+    #[test]
+    fn exclusion_wins_over_exact_name() {
+	assert!(select(&["NV_A", "!NV_A"], &["NV_A"]).is_empty());
+	assert_eq!(select(&["NV_A", "NV_B", "!NV_A*"], &["NV_A", "NV_B"]), vec!["NV_B"]);
+	// exclusions apply wherever they are listed
+	assert!(select(&["!NV_A", "NV_A"], &["NV_A"]).is_empty());
+    }
+
+    #[test]
+    fn exact_names_kept_when_missing() {
+	assert_eq!(select(&["NV_MISSING"], &["NV_A"]), vec!["NV_MISSING"]);
+    }
+
+    #[test]
+    fn first_match_ordering() {
+	assert_eq!(select(&["NV_B", "NV_*"], &["NV_A", "NV_B", "NV_C"]), vec!["NV_B", "NV_A", "NV_C"]);
+	assert_eq!(select(&["/NV_[BC]/", "NV_A", "NV_?"], &["NV_A", "NV_B", "NV_C"]), vec!["NV_B", "NV_C", "NV_A"]);
+    }
+
+    #[test]
+    fn header_without_glob() {
+	let map = defines(&[("NV_A", "src/common/sdk/nvidia/inc/ctrl/ctrl2080/ctrl2080gpu.h"),
+			    ("NV_B", "src/common/sdk/nvidia/inc/ctrl/ctrl2080/ctrl2080bus.h")]);
+	let selector = Selector::parse(&["header:ctrl2080gpu.h".to_string()]).unwrap();
+	assert_eq!(selector.select_from(&map), vec!["NV_A"]);
+	let selector = Selector::parse(&["header:ctrl2080/ctrl2080bus.h".to_string()]).unwrap();
+	assert_eq!(selector.select_from(&map), vec!["NV_B"]);
+	// matches whole path components, not the end of a name
+	let selector = Selector::parse(&["header:gpu.h".to_string()]).unwrap();
+	assert!(selector.select_from(&map).is_empty());
+    }
+
+    #[test]
+    fn header_with_glob() {
+	let map = defines(&[("NV_A", "src/common/sdk/nvidia/inc/ctrl/ctrl2080/ctrl2080gpu.h"),
+			    ("NV_B", "src/common/sdk/nvidia/inc/ctrl/ctrl0073/ctrl0073dp.h")]);
+	let selector = Selector::parse(&["header:ctrl/ctrl2080/*.h".to_string()]).unwrap();
+	assert_eq!(selector.select_from(&map), vec!["NV_A"]);
+	let selector = Selector::parse(&["header:ctrl*.h".to_string(), "!header:*dp.h".to_string()]).unwrap();
+	assert_eq!(selector.select_from(&map), vec!["NV_A"]);
+    }
+
+    #[test]
+    fn invalid_regex() {
+	let err = Selector::parse(&["/NV_(/".to_string()]).err().unwrap();
+	assert_eq!(err.kind(), ErrorKind::InvalidInput);
+	assert!(err.to_string().starts_with("invalid selector /NV_(/"));
+	assert!(Pattern::parse("/NV_[/").is_err());
+    }
+}
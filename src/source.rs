@@ -0,0 +1,569 @@
+// Where the headers come from, a tree on disk, a revision of a git
+// repository or a release tarball.
+//
+// A revision or a tarball is read into memory and handed to clang as
+// unsaved files under a directory that doesn't exist, so nothing is
+// checked out or unpacked and any number of versions can be extracted
+// from one clone at the same time. Each header only gets the files it
+// can include (found by following #include lines) as unsaved files,
+// handing every header in the tree to every parse would be slow.
+
+use clang::Unsaved;
+use flate2::read::GzDecoder;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use walkdir::{DirEntry, WalkDir};
+use xz2::read::XzDecoder;
+
+use crate::config::ExtractSettings;
+
+// where the files of a git revision or tarball appear to clang, the
+// same for every version so cached results carry over between them
+const VIRTUAL_ROOT: &'static str = ".gsp-parse-rev";
+
+// at the top of the tree, NVIDIA_VERSION = <version>
+const VERSION_FILE: &'static str = "version.mk";
+
+struct SourceFile {
+    contents: String,
+    // (name, quoted) for each #include line
+    includes: Vec<(String, bool)>,
+}
+
+pub struct SourceTree {
+    pub root: String,
+    // none for a tree on disk
+    files: Option<HashMap<PathBuf, SourceFile>>,
+}
+
+fn just_headers(entry: &DirEntry) -> bool {
+    entry.file_name()
+         .to_str()
+         .map(|s| s.ends_with(".h"))
+         .unwrap_or(false)
+}
+
+// resolve . and .. without touching the filesystem
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+	match component {
+	    Component::CurDir => {}
+	    Component::ParentDir => { out.pop(); }
+	    x => { out.push(x); }
+	}
+    }
+    out
+}
+
+// every #include, conditional or not, including too much only costs
+// a few more unsaved files
+fn include_names(contents: &str) -> Vec<(String, bool)> {
+    let mut names: Vec<(String, bool)> = Default::default();
+    for line in contents.lines() {
+	let rest = match line.trim_start().strip_prefix('#') {
+	    Some(x) => { x.trim_start() }
+	    None => { continue }
+	};
+	let rest = match rest.strip_prefix("include") {
+	    Some(x) => { x.trim_start() }
+	    None => { continue }
+	};
+	let (quoted, close) = match rest.chars().next() {
+	    Some('"') => { (true, '"') }
+	    Some('<') => { (false, '>') }
+	    _ => { continue }
+	};
+	if let Some(end) = rest[1..].find(close) {
+	    names.push((rest[1..end + 1].to_string(), quoted));
+	}
+    }
+    names
+}
+
+fn parse_version_mk(contents: &str) -> Option<String> {
+    for line in contents.lines() {
+	if let Some((name, value)) = line.split_once('=') {
+	    if name.trim() == "NVIDIA_VERSION" && value.trim() != "" {
+		return Some(value.trim().to_string());
+	    }
+	}
+    }
+    None
+}
+
+fn is_archive(path: &str) -> bool {
+    [".tar", ".tar.gz", ".tgz", ".tar.xz", ".txz"].iter().any(|x| path.ends_with(x))
+}
+
+fn open_archive(path: &str) -> std::io::Result<tar::Archive<Box<dyn Read>>> {
+    let file = BufReader::new(File::open(path)?);
+    let reader: Box<dyn Read> = if path.ends_with(".gz") || path.ends_with(".tgz") {
+	Box::new(GzDecoder::new(file))
+    } else if path.ends_with(".xz") || path.ends_with(".txz") {
+	Box::new(XzDecoder::new(file))
+    } else {
+	Box::new(file)
+    };
+    Ok(tar::Archive::new(reader))
+}
+
+// release tarballs have everything under open-gpu-kernel-modules-<version>/,
+// the tree is wherever version.mk is, or the top of the archive if
+// there is none. Returns that directory and version.mk.
+fn archive_root(path: &str) -> std::io::Result<(PathBuf, Option<String>)> {
+    for entry in open_archive(path)?.entries()? {
+	let mut entry = entry?;
+	let name = entry.path()?.to_path_buf();
+	if name.file_name().map_or(false, |n| n == VERSION_FILE) && name.components().count() <= 2 {
+	    let mut contents = String::new();
+	    entry.read_to_string(&mut contents)?;
+	    return Ok((name.parent().unwrap_or(Path::new("")).to_path_buf(), Some(contents)));
+	}
+    }
+    Ok((PathBuf::new(), None))
+}
+
+// the driver version of a tree, from its version.mk
+pub fn tree_version(tree: &str, git_rev: Option<&String>) -> std::io::Result<String> {
+    let contents = match git_rev {
+	Some(rev) => {
+	    let output = Command::new("git").arg("-C").arg(tree)
+		.arg("show").arg(format!("{}:{}", rev, VERSION_FILE))
+		.output()?;
+	    if !output.status.success() {
+		return Err(git_error(&format!("git show {}:{}", rev, VERSION_FILE), &output.stderr));
+	    }
+	    Some(String::from_utf8_lossy(&output.stdout).to_string())
+	}
+	None if is_archive(tree) => { archive_root(tree)?.1 }
+	None => { std::fs::read_to_string(Path::new(tree).join(VERSION_FILE)).ok() }
+    };
+    contents.as_deref().and_then(parse_version_mk).ok_or_else(|| {
+	Error::new(ErrorKind::NotFound, format!("{}: no NVIDIA_VERSION in {}, give the version explicitly", tree, VERSION_FILE))
+    })
+}
+
+fn git_error(what: &str, stderr: &[u8]) -> Error {
+    Error::new(ErrorKind::Other, format!("{}: {}", what, String::from_utf8_lossy(stderr).trim()))
+}
+
+// blob ids of the headers under dirs at rev
+fn git_headers(repo: &str, rev: &str, dirs: &Vec<String>) -> std::io::Result<BTreeMap<String, String>> {
+    let output = Command::new("git").arg("-C").arg(repo)
+	.args(["ls-tree", "-r", "-z", rev, "--"])
+	.args(dirs.iter().map(|d| d.trim_end_matches('/')))
+	.output()?;
+    if !output.status.success() {
+	return Err(git_error(&format!("git ls-tree {}", rev), &output.stderr));
+    }
+
+    let mut blobs: BTreeMap<String, String> = Default::default();
+    for entry in output.stdout.split(|b| *b == 0) {
+	let entry = String::from_utf8_lossy(entry);
+	// <mode> <type> <object>\t<path>
+	let (info, path) = match entry.split_once('\t') {
+	    Some(x) => { x }
+	    None => { continue }
+	};
+	let info: Vec<&str> = info.split(' ').collect();
+	if info.len() == 3 && info[1] == "blob" && path.ends_with(".h") {
+	    blobs.insert(path.to_string(), info[2].to_string());
+	}
+    }
+    Ok(blobs)
+}
+
+// contents of the blobs, in order, with one git process
+fn git_read_blobs(repo: &str, blobs: Vec<String>) -> std::io::Result<Vec<Vec<u8>>> {
+    let mut child = Command::new("git").arg("-C").arg(repo)
+	.args(["cat-file", "--batch"])
+	.stdin(Stdio::piped())
+	.stdout(Stdio::piped())
+	.stderr(Stdio::piped())
+	.spawn()?;
+
+    // write from another thread so a full stdout pipe can't deadlock us
+    let mut stdin = child.stdin.take().unwrap();
+    let request: String = blobs.iter().map(|b| b.clone() + "\n").collect();
+    let writer = std::thread::spawn(move || stdin.write_all(request.as_bytes()));
+
+    let mut output: Vec<u8> = Default::default();
+    child.stdout.take().unwrap().read_to_end(&mut output)?;
+    writer.join().unwrap()?;
+    let status = child.wait_with_output()?;
+    if !status.status.success() {
+	return Err(git_error("git cat-file", &status.stderr));
+    }
+
+    // <object> <type> <size>\n<contents>\n for each blob
+    let mut contents: Vec<Vec<u8>> = Default::default();
+    let mut pos = 0;
+    for blob in &blobs {
+	let bad = || Error::new(ErrorKind::InvalidData, format!("git cat-file: bad output for {}", blob));
+	let eol = output[pos..].iter().position(|b| *b == b'\n').ok_or_else(bad)? + pos;
+	let header = String::from_utf8_lossy(&output[pos..eol]).to_string();
+	let size: usize = header.rsplit(' ').next().and_then(|x| x.parse().ok()).ok_or_else(bad)?;
+	if eol + 1 + size > output.len() {
+	    return Err(bad());
+	}
+	contents.push(output[eol + 1..eol + 1 + size].to_vec());
+	pos = eol + 1 + size + 1;
+    }
+    Ok(contents)
+}
+
+// the headers under any of dirs
+fn wanted_header(path: &Path, dirs: &Vec<PathBuf>) -> bool {
+    path.extension().map_or(false, |x| x == "h") && dirs.iter().any(|d| path.starts_with(d))
+}
+
+impl SourceTree {
+    // a git revision if one is given, a tarball or a directory
+    pub fn open(tree: &str, git_rev: Option<&String>, settings: &ExtractSettings) -> std::io::Result<SourceTree> {
+	match git_rev {
+	    Some(rev) => { SourceTree::git(tree, rev, settings) }
+	    None if is_archive(tree) => { SourceTree::archive(tree, settings) }
+	    None => { Ok(SourceTree::dir(tree)) }
+	}
+    }
+
+    pub fn dir(tree: &str) -> SourceTree {
+	SourceTree { root: tree.to_string(), files: None }
+    }
+
+    fn in_memory(root: PathBuf, contents: Vec<(String, Vec<u8>)>) -> SourceTree {
+	let mut files: HashMap<PathBuf, SourceFile> = Default::default();
+	for (path, bytes) in contents {
+	    let contents = String::from_utf8_lossy(&bytes).replace('\0', "");
+	    let includes = include_names(&contents);
+	    files.insert(root.join(path), SourceFile { contents, includes });
+	}
+	SourceTree { root: root.to_string_lossy().to_string(), files: Some(files) }
+    }
+
+    // the headers under the scan and include paths in a tarball
+    pub fn archive(path: &str, settings: &ExtractSettings) -> std::io::Result<SourceTree> {
+	let (top, _) = archive_root(path)?;
+	let dirs: Vec<PathBuf> = settings.scan_paths.iter().chain(settings.include_paths.iter())
+	    .map(|d| normalize(&top.join(d)))
+	    .collect();
+
+	let mut contents: Vec<(String, Vec<u8>)> = Default::default();
+	for entry in open_archive(path)?.entries()? {
+	    let mut entry = entry?;
+	    let name = normalize(&entry.path()?);
+	    if entry.header().entry_type() != tar::EntryType::Regular || !wanted_header(&name, &dirs) {
+		continue;
+	    }
+	    let mut bytes: Vec<u8> = Default::default();
+	    entry.read_to_end(&mut bytes)?;
+	    let name = name.strip_prefix(&top).unwrap().to_string_lossy().to_string();
+	    contents.push((name, bytes));
+	}
+	if contents.is_empty() {
+	    return Err(Error::new(ErrorKind::NotFound, format!("no headers found in {}", path)));
+	}
+
+	let archive_dir = std::fs::canonicalize(path)?.parent().unwrap().to_path_buf();
+	Ok(SourceTree::in_memory(archive_dir.join(VIRTUAL_ROOT), contents))
+    }
+
+    // the headers under the scan and include paths at rev
+    pub fn git(repo: &str, rev: &str, settings: &ExtractSettings) -> std::io::Result<SourceTree> {
+	let mut dirs = settings.scan_paths.clone();
+	dirs.extend(settings.include_paths.iter().cloned());
+	let blobs = git_headers(repo, rev, &dirs)?;
+	if blobs.is_empty() {
+	    return Err(Error::new(ErrorKind::NotFound, format!("no headers found in {} at {}", repo, rev)));
+	}
+
+	let root = std::fs::canonicalize(repo)?.join(VIRTUAL_ROOT);
+	let contents = git_read_blobs(repo, blobs.values().cloned().collect())?;
+	Ok(SourceTree::in_memory(root, blobs.into_keys().zip(contents).collect()))
+    }
+
+    // headers to parse, in the same order for a directory and a git
+    // revision of the same tree
+    pub fn headers(&self, scan_paths: &Vec<String>) -> Vec<String> {
+	let mut headers: Vec<String> = Default::default();
+	for path in scan_paths {
+	    let newpath = self.root.clone() + "/" + path;
+	    match &self.files {
+		Some(files) => {
+		    let dir = normalize(Path::new(&newpath));
+		    let mut found: Vec<&PathBuf> = files.keys().filter(|p| p.starts_with(&dir)).collect();
+		    found.sort();
+		    headers.extend(found.iter().map(|p| p.to_string_lossy().to_string()));
+		}
+		None => {
+		    for entry in WalkDir::new(&newpath).sort_by_file_name().into_iter() {
+			let ent = match entry {
+			    Err(_) => { continue; }
+			    Ok(x) => { x }
+			};
+			if just_headers(&ent) {
+			    headers.push(ent.path().to_str().unwrap().to_string());
+			}
+		    }
+		}
+	    }
+	}
+	headers
+    }
+
+    // contents of the files that aren't on disk, for hashing
+    pub fn files(&self) -> Vec<(&Path, &str)> {
+	match &self.files {
+	    Some(files) => { files.iter().map(|(p, f)| (p.as_path(), f.contents.as_str())).collect() }
+	    None => { Default::default() }
+	}
+    }
+
+    // the header and everything it may include, nothing for a tree
+    // on disk where clang reads the files itself
+    pub fn unsaved_for(&self, header: &str, settings: &ExtractSettings) -> Vec<Unsaved> {
+	let mut unsaved: Vec<Unsaved> = Default::default();
+	if let Some(files) = &self.files {
+	    for path in self.reachable(header, settings) {
+		if let Some(file) = files.get(&path) {
+		    unsaved.push(Unsaved::new(&path, &file.contents));
+		}
+	    }
+	}
+	unsaved
+    }
+
+    // the in memory files header can include, directly or not
+    fn reachable(&self, header: &str, settings: &ExtractSettings) -> HashSet<PathBuf> {
+	let files = match &self.files {
+	    Some(x) => { x }
+	    None => { return Default::default() }
+	};
+	let inc_dirs: Vec<PathBuf> = settings.include_paths.iter()
+	    .map(|p| normalize(&Path::new(&self.root).join(p)))
+	    .collect();
+	let resolve = |from: Option<&Path>, name: &str, quoted: bool| -> Option<PathBuf> {
+	    let local = match (from, quoted) {
+		(Some(x), true) => { x.parent().map(|d| normalize(&d.join(name))) }
+		_ => { None }
+	    };
+	    local.into_iter()
+		.chain(inc_dirs.iter().map(|d| normalize(&d.join(name))))
+		.find(|p| files.contains_key(p))
+	};
+
+	let mut seen: HashSet<PathBuf> = Default::default();
+	let mut pending: Vec<PathBuf> = vec!(PathBuf::from(header));
+	pending.extend(settings.include_files.iter().filter_map(|f| resolve(None, f, false)));
+	while let Some(path) = pending.pop() {
+	    if !seen.insert(path.clone()) {
+		continue;
+	    }
+	    if let Some(file) = files.get(&path) {
+		for (name, quoted) in &file.includes {
+		    if let Some(x) = resolve(Some(&path), name, *quoted) {
+			pending.push(x);
+		    }
+		}
+	    }
+	}
+	seen
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch_tree;
+
+    fn git(repo: &Path, args: &[&str]) {
+	let status = Command::new("git").arg("-C").arg(repo)
+	    .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+	    .args(args)
+	    .stdout(Stdio::null())
+	    .status().unwrap();
+	assert!(status.success(), "git {:?}", args);
+    }
+
+    fn settings(scan: &str, include: &str) -> ExtractSettings {
+	ExtractSettings { scan_paths: vec![scan.to_string()], include_paths: vec![include.to_string()], ..Default::default() }
+    }
+
+    // a repository with version.mk and a header at tag old, and a
+    // different version and header at new
+    fn two_versions() -> PathBuf {
+	let repo = scratch_tree(&[
+	    ("version.mk", "NVIDIA_VERSION = 535.113.01\n"),
+	    ("src/inc/a.h", "#include \"b.h\"\n#include <common.h>\n#define OLD 1\n"),
+	    ("src/inc/b.h", "#define B 2\n"),
+	    ("src/common/common.h", "#define COMMON 3\n"),
+	    ("src/unused/c.h", "#define C 4\n"),
+	]);
+	git(&repo, &["init", "-q"]);
+	git(&repo, &["add", "-A"]);
+	git(&repo, &["commit", "-q", "-m", "old"]);
+	git(&repo, &["tag", "old"]);
+	std::fs::write(repo.join("version.mk"), "NVIDIA_VERSION = 570.86.16\n").unwrap();
+	std::fs::write(repo.join("src/inc/a.h"), "#define NEW 1\n").unwrap();
+	git(&repo, &["commit", "-q", "-a", "-m", "new"]);
+	git(&repo, &["tag", "new"]);
+	repo
+    }
+
+    #[test]
+    fn git_revision_is_read_without_checking_out() {
+	let repo = two_versions();
+	let tree = repo.to_str().unwrap();
+	let settings = settings("src/inc", "src/common");
+	let source = SourceTree::git(tree, "old", &settings).unwrap();
+
+	let root = std::fs::canonicalize(&repo).unwrap().join(VIRTUAL_ROOT);
+	assert_eq!(source.root, root.to_string_lossy());
+	let mut files: Vec<(String, String)> = source.files().iter()
+	    .map(|(p, c)| (p.strip_prefix(&root).unwrap().to_string_lossy().to_string(), c.to_string()))
+	    .collect();
+	files.sort();
+	assert_eq!(files, [
+	    ("src/common/common.h".to_string(), "#define COMMON 3\n".to_string()),
+	    ("src/inc/a.h".to_string(), "#include \"b.h\"\n#include <common.h>\n#define OLD 1\n".to_string()),
+	    ("src/inc/b.h".to_string(), "#define B 2\n".to_string()),
+	]);
+
+	// the same headers in the same order as the checked out tree
+	let headers: Vec<String> = source.headers(&settings.scan_paths).iter()
+	    .map(|h| Path::new(h).strip_prefix(&root).unwrap().to_string_lossy().to_string())
+	    .collect();
+	let on_disk: Vec<String> = SourceTree::dir(tree).headers(&settings.scan_paths).iter()
+	    .map(|h| Path::new(h).strip_prefix(&repo).unwrap().to_string_lossy().to_string())
+	    .collect();
+	assert_eq!(headers, ["src/inc/a.h", "src/inc/b.h"]);
+	assert_eq!(headers, on_disk);
+
+	// the working tree is left alone
+	assert_eq!(std::fs::read_to_string(repo.join("src/inc/a.h")).unwrap(), "#define NEW 1\n");
+	std::fs::remove_dir_all(repo).unwrap();
+    }
+
+    #[test]
+    fn git_versions_come_from_the_revision() {
+	let repo = two_versions();
+	let tree = repo.to_str().unwrap();
+	assert_eq!(tree_version(tree, Some(&"old".to_string())).unwrap(), "535.113.01");
+	assert_eq!(tree_version(tree, Some(&"new".to_string())).unwrap(), "570.86.16");
+	assert_eq!(tree_version(tree, None).unwrap(), "570.86.16");
+	assert!(tree_version(tree, Some(&"missing".to_string())).is_err());
+	assert!(SourceTree::git(tree, "missing", &settings("src/inc", "src/common")).is_err());
+	assert_eq!(SourceTree::git(tree, "old", &settings("src/none", "src/none")).err().unwrap().kind(), ErrorKind::NotFound);
+	std::fs::remove_dir_all(repo).unwrap();
+    }
+
+    #[test]
+    fn unsaved_files_follow_the_includes() {
+	let repo = two_versions();
+	let settings = settings("src/inc", "src/common");
+	let source = SourceTree::git(repo.to_str().unwrap(), "old", &settings).unwrap();
+	let root = Path::new(&source.root);
+	let reachable = |header: &str| -> Vec<String> {
+	    let mut files: Vec<String> = source.reachable(&root.join(header).to_string_lossy(), &settings).iter()
+		.map(|p| p.strip_prefix(root).unwrap().to_string_lossy().to_string())
+		.collect();
+	    files.sort();
+	    files
+	};
+	assert_eq!(reachable("src/inc/a.h"), ["src/common/common.h", "src/inc/a.h", "src/inc/b.h"]);
+	assert_eq!(reachable("src/inc/b.h"), ["src/inc/b.h"]);
+	assert!(SourceTree::dir(repo.to_str().unwrap()).reachable("src/inc/a.h", &settings).is_empty());
+	std::fs::remove_dir_all(repo).unwrap();
+    }
+
+    // a release tarball of files under prefix, compressed by the
+    // extension of name
+    fn tarball(dir: &Path, name: &str, prefix: &str, files: &[(&str, &str)]) -> String {
+	let path = dir.join(name);
+	let file = File::create(&path).unwrap();
+	let writer: Box<dyn Write> = if name.ends_with(".gz") || name.ends_with(".tgz") {
+	    Box::new(flate2::write::GzEncoder::new(file, flate2::Compression::default()))
+	} else if name.ends_with(".xz") {
+	    Box::new(xz2::write::XzEncoder::new(file, 6))
+	} else {
+	    Box::new(file)
+	};
+	let mut builder = tar::Builder::new(writer);
+	for (name, contents) in files {
+	    let mut header = tar::Header::new_gnu();
+	    header.set_size(contents.len() as u64);
+	    header.set_mode(0o644);
+	    header.set_cksum();
+	    builder.append_data(&mut header, Path::new(prefix).join(name), contents.as_bytes()).unwrap();
+	}
+	builder.into_inner().unwrap().flush().unwrap();
+	path.to_string_lossy().to_string()
+    }
+
+    const RELEASE: &[(&str, &str)] = &[
+	("README.md", "open-gpu-kernel-modules\n"),
+	("version.mk", "# the driver version\nNVIDIA_VERSION = 570.86.16\n"),
+	("src/inc/a.h", "#include <common.h>\n#define A 1\n"),
+	("src/common/common.h", "#define COMMON 3\n"),
+	("src/unused/c.h", "#define C 4\n"),
+    ];
+
+    #[test]
+    fn release_tarballs() {
+	let dir = scratch_tree(&[]);
+	std::fs::create_dir_all(&dir).unwrap();
+	let settings = settings("src/inc", "src/common");
+	let root = std::fs::canonicalize(&dir).unwrap().join(VIRTUAL_ROOT);
+	for name in ["release.tar", "release.tar.gz", "release.tar.xz"] {
+	    let archive = tarball(&dir, name, "open-gpu-kernel-modules-570.86.16", RELEASE);
+	    assert!(is_archive(&archive));
+	    assert_eq!(tree_version(&archive, None).unwrap(), "570.86.16");
+
+	    let source = SourceTree::open(&archive, None, &settings).unwrap();
+	    assert_eq!(source.root, root.to_string_lossy());
+	    let mut files: Vec<String> = source.files().iter()
+		.map(|(p, _)| p.strip_prefix(&root).unwrap().to_string_lossy().to_string())
+		.collect();
+	    files.sort();
+	    assert_eq!(files, ["src/common/common.h", "src/inc/a.h"]);
+	    assert_eq!(source.headers(&settings.scan_paths), [root.join("src/inc/a.h").to_string_lossy()]);
+	}
+	std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn tarballs_without_a_top_directory_or_version() {
+	let dir = scratch_tree(&[]);
+	std::fs::create_dir_all(&dir).unwrap();
+	let settings = settings("src/inc", "src/common");
+	let archive = tarball(&dir, "flat.tgz", "", &RELEASE[2..]);
+	assert_eq!(archive_root(&archive).unwrap(), (PathBuf::new(), None));
+	assert_eq!(tree_version(&archive, None).err().unwrap().kind(), ErrorKind::NotFound);
+	assert_eq!(SourceTree::archive(&archive, &settings).unwrap().files().len(), 2);
+
+	let archive = tarball(&dir, "empty.tar", "top", &RELEASE[..2]);
+	assert_eq!(archive_root(&archive).unwrap().0, PathBuf::from("top"));
+	assert_eq!(SourceTree::archive(&archive, &settings).err().unwrap().kind(), ErrorKind::NotFound);
+	std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn version_mk() {
+	assert_eq!(parse_version_mk("# comment\nNVIDIA_VERSION = 535.113.01\n").as_deref(), Some("535.113.01"));
+	assert_eq!(parse_version_mk("NVIDIA_VERSION=570.86.16").as_deref(), Some("570.86.16"));
+	assert_eq!(parse_version_mk("NVIDIA_VERSION =\nOTHER = 1\n"), None);
+	assert!(!is_archive("open-gpu-kernel-modules"));
+	assert!(is_archive("x.txz"));
+    }
+
+    #[test]
+    fn include_lines() {
+	assert_eq!(include_names("#include \"a.h\"\n  #  include <sys/b.h>\n#define X\n#include NAME\n//#include \"c.h\"\n"),
+		   [("a.h".to_string(), true), ("sys/b.h".to_string(), false)]);
+	assert_eq!(normalize(Path::new("a/./b/../c.h")), PathBuf::from("a/c.h"));
+    }
+}